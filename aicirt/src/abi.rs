@@ -0,0 +1,164 @@
+//! Checking modules against the interface aicirt provides:
+//! aici_abi version, imports, and exports.
+
+use crate::hostimpl::ModuleData;
+use aici_abi::AICI_ABI_VERSION;
+use aicirt::{api::ModuleFeatures, bail_user, user_error};
+use anyhow::{bail, Result};
+use wasmtime::{ExternType, Linker, Module};
+
+/// Name of the custom section emitted by aici_expose_all!().
+const VERSION_SECTION: &str = "aici_abi_version";
+
+/// Compatibility matrix: versions of aici_abi this runtime can run,
+/// with the host functions/exports each one introduced.
+/// Modules without the version section predate versioning and are treated as version 0.
+const SUPPORTED_VERSIONS: &[(u32, &str)] = &[
+    (0, "legacy modules"),
+    (1, "aici_host_return_logit_bias_soft, aici_post_process, aici_host_request_info"),
+];
+
+/// Exports every controller has to provide (aici_expose_all!() takes care of these).
+const REQUIRED_EXPORTS: &[&str] = &[
+    "memory",
+    "aici_init",
+    "aici_create",
+    "aici_init_prompt",
+    "aici_mid_process",
+];
+
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+fn read_leb128(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut res = 0u32;
+    let mut shift = 0;
+    loop {
+        if *pos >= data.len() || shift > 28 {
+            bail!("invalid LEB128 in wasm file");
+        }
+        let b = data[*pos];
+        *pos += 1;
+        res |= ((b & 0x7f) as u32) << shift;
+        if b & 0x80 == 0 {
+            return Ok(res);
+        }
+        shift += 7;
+    }
+}
+
+/// Find the first custom section with the given name in a wasm binary.
+fn custom_section<'a>(wasm: &'a [u8], name: &str) -> Result<Option<&'a [u8]>> {
+    if wasm.len() < 8 || &wasm[0..4] != b"\0asm" {
+        bail_user!("not a wasm file");
+    }
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_leb128(wasm, &mut pos)? as usize;
+        let end = pos + size;
+        if end > wasm.len() {
+            bail_user!("truncated wasm file");
+        }
+        if id == 0 {
+            let mut p = pos;
+            let name_len = read_leb128(wasm, &mut p)? as usize;
+            if p + name_len <= end && &wasm[p..p + name_len] == name.as_bytes() {
+                return Ok(Some(&wasm[p + name_len..end]));
+            }
+        }
+        pos = end;
+    }
+    Ok(None)
+}
+
+/// Return the aici_abi version of the module, or a user error if this runtime can't run it.
+pub fn check_abi_version(wasm: &[u8]) -> Result<u32> {
+    let version = match custom_section(wasm, VERSION_SECTION)? {
+        None => 0,
+        Some(data) if data.len() >= 4 => u32::from_le_bytes(data[0..4].try_into().unwrap()),
+        Some(_) => bail_user!("invalid {VERSION_SECTION} section in module"),
+    };
+    check_version(version)
+}
+
+/// Return the version if this runtime can run modules built against it, or a user error.
+pub fn check_version(version: u32) -> Result<u32> {
+    if SUPPORTED_VERSIONS.iter().any(|(v, _)| *v == version) {
+        return Ok(version);
+    }
+    if version > AICI_ABI_VERSION {
+        bail_user!(
+            "module was built against aici_abi version {version}, \
+             but this aicirt only supports up to version {AICI_ABI_VERSION}; \
+             upgrade aicirt or rebuild the module with an older aici_abi"
+        );
+    } else {
+        bail_user!(
+            "module was built against aici_abi version {version}, \
+             which is no longer supported by this aicirt; rebuild the module"
+        );
+    }
+}
+
+/// Check that all imports of the module are provided by the linker and that the required
+/// exports are there; returns the optional features the module uses.
+pub fn check_module(
+    linker: &Linker<ModuleData>,
+    module: &Module,
+    abi_version: u32,
+) -> Result<ModuleFeatures> {
+    let mut features = ModuleFeatures {
+        abi_version,
+        ..Default::default()
+    };
+    let mut unknown = vec![];
+
+    // Linker can't be queried without a store, but with shadowing disabled
+    // defining an already existing name fails, so we try that on a copy.
+    let mut probe = linker.clone();
+    for imp in module.imports() {
+        let name = format!("{}::{}", imp.module(), imp.name());
+        let defined = match imp.ty() {
+            ExternType::Func(fty) => probe
+                .func_new(imp.module(), imp.name(), fty, |_, _, _| Ok(()))
+                .is_err(),
+            _ => false,
+        };
+        if !defined {
+            unknown.push(name);
+            continue;
+        }
+        match (imp.module(), imp.name()) {
+            (WASI_MODULE, n) => features.wasi.push(n.to_string()),
+            ("env", "aici_host_storage_cmd") => features.storage = true,
+            _ => {}
+        }
+    }
+
+    let missing = REQUIRED_EXPORTS
+        .iter()
+        .filter(|e| module.get_export(e).is_none())
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>();
+    features.post_process = module.get_export("aici_post_process").is_some();
+    features.preinit = module.get_export("aici_preinit").is_some();
+
+    if unknown.len() > 0 || missing.len() > 0 {
+        let mut msg = vec![];
+        if unknown.len() > 0 {
+            msg.push(format!("unknown imports: {}", unknown.join(", ")));
+        }
+        if missing.len() > 0 {
+            msg.push(format!("missing exports: {}", missing.join(", ")));
+        }
+        bail_user!("invalid module ({})", msg.join("; "));
+    }
+
+    // all names are known; this checks the signatures
+    linker
+        .instantiate_pre(module)
+        .map_err(|e| user_error!("invalid module: {e}"))?;
+
+    Ok(features)
+}
@@ -1,11 +1,32 @@
 use crate::{shm::ShmAllocator, HashMap};
-use aici_abi::{ProcessResultOffset, StorageCmd, TokenId};
-use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
+use aici_abi::{
+    FinishReason, ProcessResultOffset, RequestInfo, SampledLogprobs, StorageCmd, TokenId,
+};
+use anyhow::{anyhow, bail, Result};
+use half::{bf16, f16};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 
 pub type ModuleInstId = usize;
 
+/// First byte of mid_process requests and responses in binary (bincode) encoding,
+/// which the client can switch to with the set_encoding op; JSON messages start with '{'.
+/// Fields of these messages can't be skipped when serializing, as bincode isn't self-describing.
+pub const BIN_MSG_MAGIC: u8 = 0;
+
+pub fn encode_bin<T: Serialize>(v: &T) -> Vec<u8> {
+    let mut msg = vec![BIN_MSG_MAGIC];
+    bincode::serialize_into(&mut msg, v).unwrap();
+    msg
+}
+
+pub fn decode_bin<T: DeserializeOwned>(msg: &[u8]) -> Result<T> {
+    if msg.first() != Some(&BIN_MSG_MAGIC) {
+        bail!("not a binary message");
+    }
+    Ok(bincode::deserialize(&msg[1..])?)
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct InferenceCapabilities {
     #[serde(default)]
@@ -20,6 +41,10 @@ pub struct InferenceCapabilities {
 pub struct AiciMidProcessReq {
     pub ops: Vec<AiciMidOp>,
     pub freed: Vec<ModuleInstId>,
+    /// Why sequences in `freed` have finished.
+    /// Controllers of sequences listed here get the post_process() callback before being freed.
+    #[serde(default)]
+    pub finish_reasons: HashMap<ModuleInstId, FinishReason>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,6 +55,22 @@ pub struct AiciMidProcessResp {
     pub mask_num_bytes: usize,
     pub mask_num_elts: usize,
     pub num_masks: usize,
+    /// Time spent forking workers in this step.
+    #[serde(default)]
+    pub fork_micros: u64,
+    /// Results of post_process() for sequences with `finish_reasons` in the request.
+    #[serde(default)]
+    pub finished: HashMap<ModuleInstId, SequenceResult>,
+    /// When aicirt is used over a socket (--listen), the masks are not in shared memory,
+    /// and are instead sent here, base64-encoded, indexed by the mask index in branches.
+    /// Each is a bitset of (vocab_size + 7) / 8 bytes, where bit i % 8 of byte i / 8
+    /// is set if token i is allowed.
+    #[serde(default)]
+    pub masks: Vec<String>,
+    /// Masks that allow only a few tokens (see --sparse-mask-max-tokens) are not written
+    /// to shared memory; instead their allowed tokens are listed here, by mask index.
+    #[serde(default)]
+    pub sparse_masks: HashMap<usize, Vec<TokenId>>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -39,6 +80,9 @@ pub struct AiciMidOp {
     pub id: ModuleInstId,
     /// The token that was sampled (after applying the mask), before any splicing.
     pub sampled: Option<TokenId>,
+    /// Log-probabilities of the sampled token, if the request asked for them.
+    #[serde(default)]
+    pub logprobs: Option<SampledLogprobs>,
     /// Set to None, except upon first call for a branch after forking.
     pub clone_id: Option<ModuleInstId>,
     /// This is index of branch, set iff clone_id is set.
@@ -51,6 +95,10 @@ pub struct AiciMidOp {
     /// Can be more complex when splices are used.
     pub backtrack: u32,
     pub tokens: Vec<Token>,
+    /// Storage commands (coming from the client) to execute in the request's group
+    /// before running the controller.
+    #[serde(default)]
+    pub storage: Vec<StorageCmd>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -61,6 +109,12 @@ pub struct SequenceResult<T = ()> {
     pub storage: Vec<StorageCmd>,
     pub logs: String,
     pub micros: u64,
+    /// Fuel (roughly, WASM instructions) used, when fuel metering is enabled.
+    #[serde(default)]
+    pub fuel: u64,
+    /// Set when the controller didn't finish the step before the deadline.
+    #[serde(default)]
+    pub timeout: bool,
 }
 
 impl<T> SequenceResult<T> {
@@ -71,6 +125,8 @@ impl<T> SequenceResult<T> {
             result: None,
             storage: vec![],
             micros: 0,
+            fuel: 0,
+            timeout: false,
         }
     }
     pub fn clone_with<S>(&self, result: Option<S>) -> SequenceResult<S> {
@@ -80,6 +136,8 @@ impl<T> SequenceResult<T> {
             storage: self.storage.clone(),
             logs: self.logs.clone(),
             micros: self.micros,
+            fuel: self.fuel,
+            timeout: self.timeout,
         }
     }
     pub fn map_result<S, F>(self, f: F) -> SequenceResult<S>
@@ -92,6 +150,8 @@ impl<T> SequenceResult<T> {
             storage: self.storage,
             logs: self.logs,
             micros: self.micros,
+            fuel: self.fuel,
+            timeout: self.timeout,
         }
     }
 }
@@ -107,6 +167,24 @@ pub struct MkModuleResp {
     pub wasm_size: usize,
     pub compiled_size: usize,
     pub time: u64,
+    #[serde(default)]
+    pub features: ModuleFeatures,
+}
+
+/// Optional parts of the host interface used by a module, as found at upload.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModuleFeatures {
+    /// Version of aici_abi the module was built against (0 for modules predating versioning).
+    pub abi_version: u32,
+    /// WASI functions imported by the module (most of them are stubs returning errors).
+    pub wasi: Vec<String>,
+    /// The module uses shared variable storage.
+    pub storage: bool,
+    /// The module exports aici_post_process().
+    pub post_process: bool,
+    /// The module exports aici_preinit(); its state after that call is snapshotted.
+    #[serde(default)]
+    pub preinit: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -138,6 +216,9 @@ pub struct InstantiateReq {
     pub module_id: String, // or tag name
     #[serde(default)]
     pub module_arg: Value,
+    /// Exposed to the controller via aici_host_request_info().
+    #[serde(default)]
+    pub request_info: RequestInfo,
 }
 
 pub type Token = TokenId;
@@ -169,6 +250,7 @@ impl AuthInfo {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum BiasType {
     F32,
     F16,
@@ -214,7 +296,7 @@ impl BiasType {
 
     pub fn size_in_bytes(&self, vocab_size: usize) -> usize {
         match self {
-            BiasType::Bool => 4 * (vocab_size + 31) / 32,
+            BiasType::Bool => 4 * ((vocab_size + 31) / 32),
             _ => vocab_size * self.elt_size().unwrap(),
         }
     }
@@ -273,6 +355,80 @@ impl BiasType {
             }
         }
     }
+
+    /// Add soft biases (token, bias) on top of the mask written by apply_to_shm_allocator().
+    pub fn add_to_shm_allocator(
+        &self,
+        bias: &[(usize, f32)],
+        shm: &ShmAllocator,
+        off: usize,
+    ) -> Result<()> {
+        let vocab_size = self.bytes_to_elts(shm.elt_size());
+        match self {
+            BiasType::F32 => {
+                let dst = shm.slice_at_byte_offset::<f32>(off, vocab_size);
+                for &(tok, b) in bias {
+                    dst[tok] += b;
+                }
+            }
+            BiasType::F16 => {
+                let dst = shm.slice_at_byte_offset::<u16>(off, vocab_size);
+                for &(tok, b) in bias {
+                    let v = f16::from_bits(dst[tok]).to_f32() + b;
+                    dst[tok] = f16::from_f32(v).to_bits();
+                }
+            }
+            BiasType::BF16 => {
+                let dst = shm.slice_at_byte_offset::<u16>(off, vocab_size);
+                for &(tok, b) in bias {
+                    let v = bf16::from_bits(dst[tok]).to_f32() + b;
+                    dst[tok] = bf16::from_f32(v).to_bits();
+                }
+            }
+            BiasType::Bool => bail!("soft logit biases not supported with bool bias type"),
+        }
+        Ok(())
+    }
+
+    /// Tokens not disallowed by the mask at given offset.
+    pub fn allowed_tokens(
+        &self,
+        shm: &ShmAllocator,
+        off: usize,
+        vocab_size: usize,
+    ) -> Vec<TokenId> {
+        let ok = |idx: usize| idx as TokenId;
+        match self {
+            BiasType::F32 => {
+                let src = shm.slice_at_byte_offset::<f32>(off, vocab_size);
+                (0..vocab_size)
+                    .filter(|&i| src[i] != Self::LOGIT_BIAS_DISALLOW)
+                    .map(ok)
+                    .collect()
+            }
+            BiasType::F16 => {
+                let src = shm.slice_at_byte_offset::<u16>(off, vocab_size);
+                (0..vocab_size)
+                    .filter(|&i| src[i] != Self::LOGIT_BIAS_DISALLOW_F16)
+                    .map(ok)
+                    .collect()
+            }
+            BiasType::BF16 => {
+                let src = shm.slice_at_byte_offset::<u16>(off, vocab_size);
+                (0..vocab_size)
+                    .filter(|&i| src[i] != Self::LOGIT_BIAS_DISALLOW_BF16)
+                    .map(ok)
+                    .collect()
+            }
+            BiasType::Bool => {
+                let src = shm.slice_at_byte_offset::<u8>(off, self.size_in_bytes(vocab_size));
+                (0..vocab_size)
+                    .filter(|&i| src[i / 8] & (1 << (i % 8)) != 0)
+                    .map(ok)
+                    .collect()
+            }
+        }
+    }
 }
 
 fn apply_to_slice<T: Copy>(src: &[u8], dst: &mut [T], allow: T, disallow: T) {
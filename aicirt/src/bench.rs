@@ -143,6 +143,15 @@ impl TimerRef {
         inner.timers[self.idx].stop();
     }
 
+    /// Record a duration measured elsewhere (e.g., in another process).
+    pub fn add_elapsed(&self, elapsed: Duration) {
+        let mut inner = self.set.inner.lock().unwrap();
+        let timer = &mut inner.timers[self.idx];
+        assert!(timer.state == TimerState::Stopped);
+        timer.elapsed += elapsed;
+        timer.num += 1;
+    }
+
     #[allow(dead_code)]
     pub fn set(&self) -> &TimerSet {
         &self.set
@@ -319,7 +319,12 @@ where
     }
 
     pub fn to_client(self) -> TypedClient<Cmd, Resp> {
-        let shm = Shm::new(&self.shm_name, self.shm_size, Unlink::Post).unwrap();
-        TypedClient::new(shm)
+        let shm = if self.shm_name.starts_with("/proc/") {
+            // memfd of the server process
+            Shm::open(&self.shm_name, self.shm_size)
+        } else {
+            Shm::new(&self.shm_name, self.shm_size, Unlink::Post)
+        };
+        TypedClient::new(shm.unwrap())
     }
 }
@@ -2,14 +2,15 @@ use crate::worker::{GroupCmd, GroupHandle, GroupResp, RtMidProcessArg};
 use aici_abi::{
     bytes::{clone_vec_as_bytes, limit_str, vec_from_bytes, U32Pair},
     toktrie::{TokRxInfo, TokTrie},
-    StorageCmd,
+    ProcessResultOffset, RequestInfo, StorageCmd, TokenId,
 };
 use aicirt::{
     api::{BiasType, InferenceCapabilities},
     shm::ShmAllocator,
     user_error,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::{
     rc::Rc,
     sync::Arc,
@@ -17,22 +18,62 @@ use std::{
 };
 use tokenizers::Tokenizer;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AiciLimits {
     pub ipc_shm_bytes: usize,
 
     pub timer_resolution_ns: u64,
     pub max_memory_bytes: usize,
     pub max_step_ms: u64,
+    pub max_post_ms: u64,
     pub max_init_ms: u64,
+    pub max_step_fuel: u64,
+    pub max_init_fuel: u64,
+    /// Replaces the wall-clock limits above when fuel metering is enabled.
+    pub fuel_wall_clock_ms: u64,
     pub max_compile_ms: u64,
     pub max_timeout_steps: usize,
     pub logit_memory_bytes: usize,
     pub busy_wait_duration: Duration,
     pub max_forks: usize,
+    pub worker_pool_size: usize,
+    /// Masks allowing at most this many tokens are reported as lists (0 disables).
+    pub max_sparse_mask_tokens: usize,
+    /// Install the seccomp filter in seq and comms workers.
+    pub seccomp: bool,
 
     pub module_upload: bool,
     pub gh_download: bool,
+    /// Name of the policy profile these limits come from (see policy.rs).
+    pub profile: String,
+}
+
+impl AiciLimits {
+    /// Fuel metering is enabled when any fuel budget is set.
+    pub fn fuel_metering(&self) -> bool {
+        self.max_step_fuel > 0 || self.max_init_fuel > 0
+    }
+
+    /// Wall-clock deadline of a step; with fuel metering it's only a safety net.
+    pub fn step_ms(&self) -> u64 {
+        self.wall_clock_ms(self.max_step_ms)
+    }
+
+    pub fn post_ms(&self) -> u64 {
+        self.wall_clock_ms(self.max_post_ms)
+    }
+
+    pub fn init_ms(&self) -> u64 {
+        self.wall_clock_ms(self.max_init_ms)
+    }
+
+    fn wall_clock_ms(&self, ms: u64) -> u64 {
+        if self.fuel_metering() {
+            self.fuel_wall_clock_ms
+        } else {
+            ms
+        }
+    }
 }
 
 type ModuleInstId = crate::api::ModuleInstId;
@@ -47,21 +88,26 @@ pub struct ModuleData {
     pub process_result: Vec<u8>,
     pub logit_shm: Rc<ShmAllocator>,
     pub logit_offsets: Vec<u32>,
+    /// Allowed tokens of masks that were not written to logit shm, by offset.
+    pub sparse_masks: Vec<(u32, Vec<TokenId>)>,
+    /// Offsets of masks written as f32 rows with soft biases, when logit shm holds bool masks.
+    pub soft_masks: Vec<u32>,
     pub limits: AiciLimits,
-    pub linker: Arc<wasmtime::Linker<ModuleData>>,
     pub instance: Option<wasmtime::Instance>,
     pub memory: Option<wasmtime::Memory>,
-    pub module: wasmtime::Module,
     pub store_limits: wasmtime::StoreLimits,
     pub had_error: bool,
     pub storage_log: Vec<StorageCmd>,
     pub start_time: Instant,
+    pub request_info: RequestInfo,
+    /// Number of tokens generated so far (after backtracking).
+    pub num_generated: usize,
     blobs: Vec<Rc<Vec<u8>>>,
 }
 
 const MAXLOG: usize = 64 * 1024;
 
-pub struct BlobId(u32);
+pub struct BlobId(pub u32);
 
 impl BlobId {
     pub const MODULE_ARG: BlobId = BlobId(1);
@@ -69,6 +115,7 @@ impl BlobId {
     pub const TOKENS: BlobId = BlobId(3);
     pub const PROCESS_ARG: BlobId = BlobId(4);
     pub const STORAGE_RESULT: BlobId = BlobId(5);
+    pub const REQUEST_INFO: BlobId = BlobId(6);
 
     pub const MAX_BLOB_ID: u32 = 20;
 
@@ -80,9 +127,7 @@ impl ModuleData {
     pub fn new(
         id: ModuleInstId,
         limits: &AiciLimits,
-        module: &wasmtime::Module,
         module_arg: String,
-        linker: &Arc<wasmtime::Linker<ModuleData>>,
         globals: GlobalInfo,
         group_channel: GroupHandle,
         logit_shm: Rc<ShmAllocator>,
@@ -101,24 +146,30 @@ impl ModuleData {
             printed_log: 0,
             globals,
             group_channel,
-            module: module.clone(),
             limits: limits.clone(),
-            linker: linker.clone(),
             instance: None,
             memory: None,
             store_limits,
             process_result: Vec::new(),
             logit_shm,
             logit_offsets: Vec::new(),
+            sparse_masks: Vec::new(),
+            soft_masks: Vec::new(),
             had_error: false,
             storage_log: Vec::new(),
             start_time: Instant::now(),
+            request_info: RequestInfo::default(),
+            num_generated: 0,
             blobs: vec![Rc::new(Vec::new()); BlobId::MAX_BLOB_ID as usize],
         };
-        r.set_blob(BlobId::MODULE_ARG, module_arg.as_bytes().to_vec());
+        r.set_module_arg(module_arg);
         r
     }
 
+    pub fn set_module_arg(&mut self, arg: String) {
+        self.set_blob(BlobId::MODULE_ARG, arg.into_bytes());
+    }
+
     fn clear_blob(&mut self, blob_id: BlobId) {
         self.set_blob(blob_id, vec![])
     }
@@ -133,9 +184,202 @@ impl ModuleData {
     }
 
     pub fn set_mid_process_data(&mut self, data: RtMidProcessArg) {
+        self.num_generated = (self.num_generated + data.op.tokens.len())
+            .saturating_sub(data.op.backtrack as usize);
         let bytes = serde_json::to_vec(&data.op).unwrap();
         self.set_process_arg(bytes);
         self.logit_offsets.clear();
+        self.sparse_masks.clear();
+        self.soft_masks.clear();
+    }
+
+    fn current_request_info(&self) -> RequestInfo {
+        let mut info = self.request_info.clone();
+        info.remaining_tokens = info
+            .max_tokens
+            .map(|m| m.saturating_sub(self.num_generated));
+        // this matches the fuel computation in rllm (2*generated + processed)
+        info.remaining_fuel = info
+            .fuel
+            .map(|f| f.saturating_sub(info.prompt_tokens + 2 * self.num_generated));
+        info
+    }
+
+    pub fn request_info_blob(&mut self) -> BlobId {
+        let info = self.current_request_info();
+        let bytes = serde_json::to_vec(&info).unwrap();
+        self.set_blob(BlobId::REQUEST_INFO, bytes);
+        BlobId::REQUEST_INFO
+    }
+
+    /// Contents of the blob, or None if the id is invalid.
+    pub fn blob(&self, blob_id: u32) -> Option<&[u8]> {
+        if blob_id == BlobId::TRIE.0 {
+            Some(&self.globals.trie_bytes)
+        } else if blob_id < BlobId::MAX_BLOB_ID {
+            Some(&self.blobs[blob_id as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn tokenize_blob(&mut self, s: &[u8]) -> BlobId {
+        match self.tokenize_bytes(s) {
+            Err(e) => {
+                self.warn(&format!("tokenize error: {e:?}"));
+                self.clear_blob(BlobId::TOKENIZE);
+            }
+            Ok(tokens) => {
+                self.set_blob(BlobId::TOKENIZE, clone_vec_as_bytes(&tokens));
+            }
+        }
+        BlobId::TOKENIZE
+    }
+
+    pub fn get_config(&self, name: &str) -> i32 {
+        let caps = serde_json::to_value(self.globals.inference_caps.clone()).unwrap();
+        if caps[name].as_bool().unwrap_or(false) {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Pair soft biases with tokens; without `tokens`, biases are for tokens 0..biases.len().
+    pub fn soft_biases(
+        &self,
+        tokens: Option<Vec<u32>>,
+        biases: Vec<f32>,
+    ) -> Result<Vec<(usize, f32)>> {
+        let numtok = self.globals.tokrx_info.vocab_size as usize;
+        if let Some(b) = biases.iter().find(|b| !b.is_finite()) {
+            return Err(user_error!("return_logit_bias_soft: invalid bias {b}"));
+        }
+        match tokens {
+            None => {
+                if biases.len() > numtok {
+                    return Err(user_error!("return_logit_bias_soft: too many biases"));
+                }
+                Ok(biases.into_iter().enumerate().collect())
+            }
+            Some(toks) => {
+                if let Some(t) = toks.iter().find(|t| **t as usize >= numtok) {
+                    return Err(user_error!("return_logit_bias_soft: invalid token {t}"));
+                }
+                Ok(toks
+                    .into_iter()
+                    .map(|t| t as usize)
+                    .zip(biases.into_iter())
+                    .collect())
+            }
+        }
+    }
+
+    /// Write the mask (and optionally soft biases) to a fresh slot in logit shm.
+    /// Masks with few allowed tokens only reserve the slot, and are recorded in sparse_masks.
+    /// Soft biases over bool masks are written as f32 rows (if slots fit them), and recorded
+    /// in soft_masks.
+    pub fn return_logit_bias(
+        &mut self,
+        mask: &[u8],
+        soft: Option<Vec<(usize, f32)>>,
+    ) -> Result<u32> {
+        let shm = self.logit_shm.clone();
+        let id: u32 = self.id.try_into().unwrap();
+
+        let mut bias_type = BiasType::from_u32(shm.elt_type() & 0xf).unwrap();
+        let numtok = self.globals.tokrx_info.vocab_size as usize;
+        let soft_as_f32 = soft.is_some()
+            && bias_type == BiasType::Bool
+            && shm.elt_size() >= BiasType::F32.size_in_bytes(numtok);
+        if soft_as_f32 {
+            bias_type = BiasType::F32;
+        }
+        if soft.is_some() && bias_type.elt_size().is_none() {
+            return Err(user_error!(
+                "soft logit biases not supported with bias type {}",
+                bias_type.to_string()
+            ));
+        }
+
+        let off = shm
+            .alloc(id)
+            .map_err(|e| user_error!("return_logit_bias: {e} (too many sampling branches?)"))?;
+
+        let off32: u32 = off.try_into().unwrap();
+        let sparse = if soft.is_none() {
+            self.sparse_tokens(mask)
+        } else {
+            None
+        };
+        if let Some(tokens) = sparse {
+            self.sparse_masks.push((off32, tokens));
+        } else {
+            if soft_as_f32 {
+                self.soft_masks.push(off32);
+            }
+            bias_type.apply_to_shm_allocator(mask, &shm, off);
+            if let Some(soft) = soft {
+                bias_type.add_to_shm_allocator(&soft, &shm, off)?;
+            }
+        }
+
+        self.logit_offsets.push(off32);
+        Ok(off32)
+    }
+
+    /// Tokens allowed by the mask, if there are at most max_sparse_mask_tokens of them.
+    fn sparse_tokens(&self, mask: &[u8]) -> Option<Vec<TokenId>> {
+        let max = self.limits.max_sparse_mask_tokens;
+        if max == 0 {
+            return None;
+        }
+        let numtok = self.globals.tokrx_info.vocab_size as usize;
+        let mut tokens = Vec::new();
+        for (idx, &byte) in mask.iter().enumerate() {
+            if byte == 0 {
+                continue;
+            }
+            for bit in 0..8 {
+                let tok = idx * 8 + bit;
+                if tok < numtok && byte & (1 << bit) != 0 {
+                    if tokens.len() >= max {
+                        return None;
+                    }
+                    tokens.push(tok as TokenId);
+                }
+            }
+        }
+        Some(tokens)
+    }
+
+    /// Size in bytes of masks passed to return_logit_bias().
+    pub fn mask_num_bytes(&self) -> usize {
+        let numtok = self.globals.tokrx_info.vocab_size as usize;
+        4 * ((numtok + 31) / 32)
+    }
+
+    pub fn proc_result<T: for<'a> Deserialize<'a>>(&self) -> Result<T> {
+        let bytes = &self.process_result;
+        if bytes.len() == 0 {
+            Err(anyhow!("aici_host_return_process_result not called"))
+        } else {
+            serde_json::from_slice::<T>(bytes).map_err(|e| e.into())
+        }
+    }
+
+    /// Result of aici_mid_process(), checking that masks come from return_logit_bias().
+    pub fn mid_process_result(&self) -> Result<ProcessResultOffset> {
+        let res: ProcessResultOffset = self.proc_result()?;
+        let offs = &self.logit_offsets;
+        for b in res.branches.iter() {
+            if let Some(o) = b.sample_mask {
+                if !offs.contains(&(o as u32)) {
+                    panic!("logit offset not found: {}", o);
+                }
+            }
+        }
+        Ok(res)
     }
 
     pub fn tokenize_bytes(&mut self, s: &[u8]) -> Result<Vec<u32>> {
@@ -220,7 +464,7 @@ impl ModuleData {
                         let res_bytes = serde_json::to_vec(&resp).unwrap();
                         self.set_blob(BlobId::STORAGE_RESULT, res_bytes);
                     }
-                    // Ok(r) => self.fatal(&format!("storage_cmd invalid resp: {r:?}")),
+                    Ok(r) => self.fatal(&format!("storage_cmd invalid resp: {r:?}")),
                     Err(msg) => self.fatal(&format!("storage_cmd send error: {msg:?}")),
                 }
             }
@@ -276,6 +520,18 @@ fn write_caller_mem(
     src.len() as u32
 }
 
+fn return_logit_bias(
+    caller: &mut wasmtime::Caller<'_, ModuleData>,
+    src: u32,
+    soft: Option<Vec<(usize, f32)>>,
+) -> Result<u32> {
+    let numbytes = caller.data().mask_num_bytes();
+    let mem = caller.data().memory.unwrap();
+    let (mem, data) = mem.data_and_store_mut(caller);
+    let sptr = src as usize;
+    data.return_logit_bias(&mem[sptr..sptr + numbytes], soft)
+}
+
 macro_rules! fake_wasi {
     ($linker:ident, $func_name:ident, $($arg_type:ty)+) => {
         $linker.func_wrap(
@@ -419,16 +675,20 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "env",
         "aici_host_read_blob",
         |mut caller: wasmtime::Caller<'_, ModuleData>, blob_id: u32, ptr: u32, len: u32| {
-            if blob_id == BlobId::TRIE.0 {
-                let trie_bytes = caller.data().globals.trie_bytes.clone();
-                write_caller_mem(&mut caller, ptr, len, &trie_bytes)
-            } else if blob_id < BlobId::MAX_BLOB_ID {
-                let blob = caller.data().blobs[blob_id as usize].clone();
-                write_caller_mem(&mut caller, ptr, len, &blob)
-            } else {
+            let mem = caller.data().memory.unwrap();
+            let (mem, data) = mem.data_and_store_mut(&mut caller);
+            let size = data.blob(blob_id).map(|blob| {
+                if len > 0 {
+                    let ptr = ptr as usize;
+                    let min_len = std::cmp::min(len as usize, blob.len());
+                    mem[ptr..ptr + min_len].copy_from_slice(&blob[..min_len]);
+                }
+                blob.len() as u32
+            });
+            size.unwrap_or_else(|| {
                 fatal_error(&mut caller, "invalid blob_id");
                 0
-            }
+            })
         },
     )?;
 
@@ -437,25 +697,19 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
     linker.func_wrap("env", "aici_host_token_trie", || BlobId::TRIE.0)?;
     linker.func_wrap("env", "aici_host_tokens", || BlobId::TOKENS.0)?;
 
+    linker.func_wrap(
+        "env",
+        "aici_host_request_info",
+        |mut caller: wasmtime::Caller<'_, ModuleData>| caller.data_mut().request_info_blob().0,
+    )?;
+
     // uint32_t aici_host_tokenize(const uint8_t *src, uint32_t src_size, uint32_t *dst, uint32_t dst_size);
     linker.func_wrap(
         "env",
         "aici_host_tokenize",
         |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
             let m = read_caller_mem(&caller, src, src_size);
-            let tokens = caller.data_mut().tokenize_bytes(&m);
-            match tokens {
-                Err(e) => {
-                    caller.data_mut().warn(&format!("tokenize error: {e:?}"));
-                    caller.data_mut().clear_blob(BlobId::TOKENIZE);
-                }
-                Ok(tokens) => {
-                    caller
-                        .data_mut()
-                        .set_blob(BlobId::TOKENIZE, clone_vec_as_bytes(&tokens));
-                }
-            }
-            BlobId::TOKENIZE.0
+            caller.data_mut().tokenize_blob(&m).0
         },
     )?;
 
@@ -463,24 +717,27 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "env",
         "aici_host_return_logit_bias",
         |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32| {
-            let data = caller.data();
-
-            let numtok = data.globals.tokrx_info.vocab_size as usize;
-            let shm = data.logit_shm.clone();
-            let id: u32 = data.id.try_into().unwrap();
-            let numbytes = 4 * ((numtok + 31) / 32);
-            let mem = caller.data().memory.unwrap();
-            let sptr = src as usize;
-            let slice = &mem.data(&caller)[sptr..sptr + numbytes];
-
-            let bias_type = BiasType::from_u32(shm.elt_type() & 0xf).unwrap();
-            let off = shm.alloc(id).unwrap();
-
-            bias_type.apply_to_shm_allocator(slice, &shm, off);
+            return_logit_bias(&mut caller, src, None)
+        },
+    )?;
 
-            let off32: u32 = off.try_into().unwrap();
-            caller.data_mut().logit_offsets.push(off32);
-            off32
+    linker.func_wrap(
+        "env",
+        "aici_host_return_logit_bias_soft",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         src: u32,
+         tokens: u32,
+         bias: u32,
+         num: u32|
+         -> Result<u32> {
+            let biases: Vec<f32> = vec_from_bytes(&read_caller_mem(&caller, bias, 4 * num));
+            let toks = if tokens == 0 {
+                None
+            } else {
+                Some(vec_from_bytes(&read_caller_mem(&caller, tokens, 4 * num)))
+            };
+            let soft = caller.data().soft_biases(toks, biases)?;
+            return_logit_bias(&mut caller, src, Some(soft))
         },
     )?;
 
@@ -495,12 +752,7 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "aici_host_get_config",
         |caller: wasmtime::Caller<'_, ModuleData>, name: u32, name_size: u32| {
             let m = read_caller_mem(&caller, name, name_size);
-            let name = String::from_utf8_lossy(&m);
-            let caps = serde_json::to_value(caller.data().globals.inference_caps.clone()).unwrap();
-            if caps[name.as_ref()].as_bool().unwrap_or(false) {
-                return 1;
-            }
-            return 0;
+            caller.data().get_config(&String::from_utf8_lossy(&m))
         },
     )?;
 
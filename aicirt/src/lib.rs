@@ -2,8 +2,10 @@ pub mod api;
 mod bench;
 pub mod futexshm;
 pub mod msgchannel;
+pub mod seccomp;
 pub mod semaphore;
 pub mod shm;
+pub mod sockchannel;
 
 pub use aici_native::*;
 
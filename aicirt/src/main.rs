@@ -1,5 +1,10 @@
+mod abi;
 mod hostimpl;
 mod moduleinstance;
+mod native;
+mod policy;
+mod simulate;
+mod trace;
 mod worker;
 
 use crate::{
@@ -7,16 +12,24 @@ use crate::{
     hostimpl::*,
     moduleinstance::*,
     msgchannel::MessageChannel,
+    policy::Policy,
     shm::Shm,
+    trace::{MaskLayout, StepInputs, TraceRecorder},
     worker::{RtMidProcessArg, WorkerForker},
     TimerSet,
 };
 use aici_abi::{
-    bytes::limit_str, toktrie::TokTrie, Branch, MidProcessArg, ProcessResultOffset, SeqId,
-    TokenizerEnv,
+    bytes::limit_str, toktrie::TokTrie, Branch, MidProcessArg, PostProcessArg,
+    ProcessResultOffset, SeqId, StorageCmd, TokenId, TokenizerEnv,
 };
-use aicirt::{bintokens::find_tokenizer, futexshm::ServerChannel, shm::ShmAllocator, *};
-use anyhow::{anyhow, ensure, Result};
+use aicirt::{
+    bintokens::find_tokenizer,
+    futexshm::ServerChannel,
+    shm::ShmAllocator,
+    sockchannel::{SockListener, SockStream},
+    *,
+};
+use anyhow::{anyhow, bail, ensure, Result};
 use base64::{self, Engine as _};
 use bintokens::ByteTokenizerEnv;
 use clap::Parser;
@@ -71,6 +84,14 @@ struct Cli {
     #[arg(long)]
     restricted: bool,
 
+    /// JSON file mapping users to limit profiles, overriding the limits given here
+    #[arg(long)]
+    policy: Option<PathBuf>,
+
+    /// Don't install the seccomp filter in worker processes (for debugging)
+    #[arg(long)]
+    no_seccomp: bool,
+
     /// Save the --tokenizer=... to specified file
     #[arg(long)]
     save_tokenizer: Option<String>,
@@ -87,10 +108,65 @@ struct Cli {
     #[arg(long)]
     run_arg: Option<PathBuf>,
 
+    /// Generate text with the module just added (and --run-arg) without an LLM,
+    /// picking tokens allowed by its masks
+    #[arg(long)]
+    simulate: bool,
+
+    /// Prompt for --simulate
+    #[arg(long, default_value = "")]
+    sim_prompt: String,
+
+    /// How --simulate picks tokens: random, first (allowed token), or script (see --sim-tokens)
+    #[arg(long, default_value = "random")]
+    sim_sampling: String,
+
+    /// Seed for --sim-sampling=random
+    #[arg(long, default_value = "1")]
+    sim_seed: usize,
+
+    /// JSON file with list of tokens for --sim-sampling=script
+    #[arg(long)]
+    sim_tokens: Option<String>,
+
+    /// Maximum number of tokens generated by each fork in --simulate
+    #[arg(long, default_value = "100")]
+    sim_max_tokens: usize,
+
+    /// Trusted native controller (shared library built from a crate using aici_expose_all!()),
+    /// available as module_id native:NAME; can be specified multiple times.
+    /// These run without the WASM sandbox, so only use libraries you trust.
+    #[arg(long, value_name = "NAME=PATH")]
+    native_module: Vec<String>,
+
+    /// Serve the protocol over a socket instead of shared memory: unix:PATH or tcp:HOST:PORT;
+    /// the side channel is at PATH-side or the next port.
+    /// Masks are then sent as bitsets in the mid_process response, and --bias-dtype is ignored.
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<String>,
+
+    /// Shared secret clients of --listen have to send before anything else
+    #[arg(long, value_name = "TOKEN")]
+    listen_token: Option<String>,
+
+    /// File with the shared secret for --listen; unlike --listen-token, it's not visible
+    /// in the process list
+    #[arg(long, value_name = "PATH")]
+    listen_token_file: Option<PathBuf>,
+
     /// Run with POSIX shared memory interface
     #[arg(short, long)]
     server: bool,
 
+    /// Record every request (instantiation, mid_process steps and their results)
+    /// into a separate file in this directory
+    #[arg(long)]
+    record: Option<String>,
+
+    /// Re-execute a request recorded with --record, without the LLM, and report differences
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
     /// Run benchmarks
     #[arg(long)]
     bench: bool,
@@ -107,10 +183,16 @@ struct Cli {
     #[arg(long)]
     cap_ff_tokens: bool,
 
-    /// Specify the type of bias to pass using shared memory (f32, f16, bf16, bool)
+    /// Specify the type of bias to pass using shared memory (f32, f16, bf16, bool);
+    /// with bool, steps where controllers return soft biases are passed as f32
     #[arg(long, default_value = "f32")]
     bias_dtype: String,
 
+    /// Report masks allowing at most this many tokens as token lists in the mid_process
+    /// response, instead of writing them to shared memory; 0 to disable
+    #[arg(long, default_value = "0")]
+    sparse_mask_max_tokens: usize,
+
     /// Enable futex comms
     #[arg(long, default_value_t = false)]
     futex: bool,
@@ -131,6 +213,10 @@ struct Cli {
     #[arg(long, default_value = "16")]
     wasm_max_forks: usize,
 
+    /// Number of idle pre-forked workers kept ready for new requests; 0 to disable
+    #[arg(long, default_value = "4")]
+    worker_pool_size: usize,
+
     /// Maximum size of WASM module memory in megabytes
     #[arg(long, default_value = "64")]
     wasm_max_memory: usize,
@@ -143,10 +229,31 @@ struct Cli {
     #[arg(long, default_value = "10")]
     wasm_max_timeout_steps: usize,
 
+    /// Maximum time WASM module can execute post_process() of a finished sequence in milliseconds
+    #[arg(long, default_value = "20")]
+    wasm_max_post_time: u64,
+
     /// Maximum time WASM module can execute initialization code in milliseconds
     #[arg(long, default_value = "1000")]
     wasm_max_init_time: u64,
 
+    /// Fuel (roughly, WASM instructions) budget for a step or post_process();
+    /// setting this or --wasm-max-init-fuel enables deterministic fuel metering,
+    /// where 0 means unlimited; the time limits above are then replaced
+    /// by --wasm-fuel-wall-clock-ms
+    #[arg(long, default_value = "0")]
+    wasm_max_step_fuel: u64,
+
+    /// Fuel budget for initialization code (see --wasm-max-step-fuel)
+    #[arg(long, default_value = "0")]
+    wasm_max_init_fuel: u64,
+
+    /// With fuel metering, wall-clock limit in milliseconds for a step, post_process()
+    /// or initialization; it's only a safety net for an overloaded host,
+    /// so exceeding it is a host error, and not a timed-out step
+    #[arg(long, default_value = "10000")]
+    wasm_fuel_wall_clock_ms: u64,
+
     /// Resolution of timer exposed to WASM modules in microseconds; 0 to disable timer
     #[arg(long, default_value = "0")]
     wasm_timer_resolution_us: u64,
@@ -160,12 +267,28 @@ impl Cli {
     pub fn prefixed_name(&self, name: &str, name2: &str) -> String {
         format!("{}{}{}", self.name, name, name2)
     }
+
+    pub fn listen_token(&self) -> Result<String> {
+        let token = match (&self.listen_token, &self.listen_token_file) {
+            (Some(t), None) => t.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|e| anyhow!("can't read {}: {e}", path.display()))?
+                .trim()
+                .to_string(),
+            (Some(_), Some(_)) => {
+                bail!("only one of --listen-token and --listen-token-file allowed")
+            }
+            (None, None) => bail!("--listen requires --listen-token or --listen-token-file"),
+        };
+        ensure!(!token.is_empty(), "empty --listen token");
+        Ok(token)
+    }
 }
 
 enum ModuleStatus {
     Missing,
     Locked,
-    Ready,
+    Ready(ModuleFeatures),
 }
 
 // this is cloned for every module-level request, so don't go overboard with fields
@@ -178,16 +301,25 @@ struct ModuleRegistry {
     req_instances: Arc<Mutex<HashMap<String, SeqWorkerHandle>>>,
     // not sure Mutex is needed
     forker: Arc<Mutex<WorkerForker>>,
+    trace: Option<Arc<TraceRecorder>>,
+    // maps NAME in native:NAME module ids to shared library paths (--native-module)
+    native_modules: Arc<HashMap<String, PathBuf>>,
+    policy: Arc<Policy>,
 }
 
 struct Stepper {
     req_instances: Arc<Mutex<HashMap<String, SeqWorkerHandle>>>,
     instances: HashMap<ModuleInstId, SeqWorkerHandle>,
     num_timeouts: HashMap<ModuleInstId, usize>,
+    // storage commands that arrived while the instance was pending after a timeout
+    pending_storage: HashMap<ModuleInstId, Vec<StorageCmd>>,
     limits: AiciLimits,
     globals: GlobalInfo,
     shm: Rc<ShmAllocator>,
     token_bytes: Vec<Vec<u8>>,
+    trace: Option<Arc<TraceRecorder>>,
+    // send masks in the response, as the client can't see our shm (--listen)
+    inline_masks: bool,
 }
 
 fn hex_hash_string(s: &str) -> String {
@@ -216,6 +348,9 @@ impl ModuleRegistry {
             wasm_ctx: Arc::new(wasm_ctx),
             modules: Arc::new(Mutex::new(HashMap::default())),
             req_instances: Arc::new(Mutex::new(HashMap::default())),
+            trace: None,
+            native_modules: Arc::new(HashMap::default()),
+            policy: Arc::new(Policy::default()),
         })
     }
 
@@ -227,7 +362,7 @@ impl ModuleRegistry {
                     drop(lck);
                     std::thread::sleep(std::time::Duration::from_millis(50))
                 }
-                ModuleStatus::Ready => return false,
+                ModuleStatus::Ready(_) => return false,
                 ModuleStatus::Missing => {
                     // we lock it
                     lck.insert(module_id.to_string(), ModuleStatus::Locked);
@@ -259,28 +394,42 @@ impl ModuleRegistry {
         self.cache_path.join(format!("tags/{}.json", tagname))
     }
 
-    fn compile_module(&self, module_id: &str, force: bool) -> Result<()> {
+    fn compile_module(&self, module_id: &str, force: bool) -> Result<ModuleFeatures> {
+        // this runs once per module (until restart), both on upload and first instantiation
+        let abi_version = abi::check_abi_version(&fs::read(self.wasm_path(module_id))?)?;
+        log::debug!("module {module_id}: aici_abi version {abi_version}");
+
         let module = if force {
             Err(anyhow!("force"))
         } else {
             self.wasm_ctx.deserialize_module(self.elf_path(module_id))
         };
 
-        match module {
+        let module = match module {
             Err(e) => {
                 let wasm_bytes = fs::read(self.wasm_path(module_id))?;
                 log::info!("compiling {}; {}", module_id, e);
                 let compiled = self.forker.lock().unwrap().compile(wasm_bytes)?;
                 fs::write(self.elf_path(module_id), compiled)?;
                 // make sure we can deserialize it
-                let _ = self.wasm_ctx.deserialize_module(self.elf_path(module_id))?;
+                self.wasm_ctx.deserialize_module(self.elf_path(module_id))?
             }
-            Ok(_) => {}
+            Ok(m) => m,
         };
 
+        let features = abi::check_module(&self.wasm_ctx.linker, &module, abi_version)?;
+
         let mut lck = self.modules.lock().unwrap();
-        lck.insert(module_id.to_string(), ModuleStatus::Ready);
-        return Ok(());
+        lck.insert(module_id.to_string(), ModuleStatus::Ready(features.clone()));
+        return Ok(features);
+    }
+
+    /// Features found by `compile_module()`; the module has to be ready.
+    fn module_features(&self, module_id: &str) -> ModuleFeatures {
+        match self.modules.lock().unwrap().get(module_id) {
+            Some(ModuleStatus::Ready(features)) => features.clone(),
+            _ => panic!("module {module_id} not ready"),
+        }
     }
 
     fn ensure_module_in_fs(&self, module_id: &str) -> Result<PathBuf> {
@@ -298,8 +447,22 @@ impl ModuleRegistry {
         Ok(self.elf_path(module_id))
     }
 
+    fn limits_for(&self, auth: &AuthInfo) -> AiciLimits {
+        self.policy.limits_for(&self.wasm_ctx.limits, auth)
+    }
+
+    fn check_upload(&self, auth: &AuthInfo) -> Result<()> {
+        let limits = self.limits_for(auth);
+        ensure_user!(
+            limits.module_upload,
+            "module upload disabled ({})",
+            policy::describe(&limits)
+        );
+        Ok(())
+    }
+
     fn create_module(&self, wasm_bytes: Vec<u8>, auth: AuthInfo) -> Result<MkModuleResp> {
-        ensure_user!(self.wasm_ctx.limits.module_upload, "module upload disabled");
+        self.check_upload(&auth)?;
 
         let timer = Instant::now();
 
@@ -309,16 +472,18 @@ impl ModuleRegistry {
         let module_id = hex::encode(hasher.finalize());
         let module_id = &module_id;
 
-        if self.module_needs_check(module_id) {
+        let features = if self.module_needs_check(module_id) {
             match self.write_and_compile(module_id, &wasm_bytes, &auth) {
                 Err(e) => {
                     let mut lck = self.modules.lock().unwrap();
                     lck.remove(module_id);
                     return Err(e);
                 }
-                Ok(_) => {}
+                Ok(features) => features,
             }
-        }
+        } else {
+            self.module_features(module_id)
+        };
 
         let compiled_size = fs::metadata(self.elf_path(module_id))?.len() as usize;
         let time = timer.elapsed().as_millis() as u64;
@@ -336,6 +501,7 @@ impl ModuleRegistry {
             wasm_size: wasm_bytes.len(),
             compiled_size,
             time,
+            features,
         })
     }
 
@@ -344,7 +510,7 @@ impl ModuleRegistry {
         module_id: &String,
         wasm_bytes: &Vec<u8>,
         auth: &AuthInfo,
-    ) -> Result<()> {
+    ) -> Result<ModuleFeatures> {
         fs::create_dir_all(&self.cache_path)?;
         let meta = self.wasm_path(module_id).metadata();
         Ok(
@@ -372,7 +538,7 @@ impl ModuleRegistry {
     }
 
     fn set_tags(&self, req: SetTagsReq, auth: AuthInfo) -> Result<Value> {
-        ensure_user!(self.wasm_ctx.limits.module_upload, "module upload disabled");
+        self.check_upload(&auth)?;
         ensure_user!(valid_module_id(&req.module_id), "invalid module_id");
         let _ = self.ensure_module_in_fs(&req.module_id)?;
 
@@ -572,23 +738,48 @@ impl ModuleRegistry {
         Ok(resp.module_id)
     }
 
-    fn instantiate(&mut self, mut req: InstantiateReq) -> Result<Value> {
-        req.module_id = self.resolve_gh_module(&req.module_id, None)?;
-        if valid_tagname(&req.module_id) {
-            let taginfo = self.read_tag(&req.module_id)?;
-            req.module_id = taginfo.module_id;
+    fn resolve_native_module(&self, module_id: &str) -> Result<Option<PathBuf>> {
+        match module_id.strip_prefix("native:") {
+            None => Ok(None),
+            Some(name) => match self.native_modules.get(name) {
+                Some(path) => Ok(Some(path.clone())),
+                None => bail_user!("native module {name:?} not available"),
+            },
         }
-        ensure!(is_hex_string(&req.module_id), "invalid module_id");
-        let module_path = self.ensure_module_in_fs(&req.module_id)?;
-        log::debug!("instance {} -> {}", req.module_id, req.req_id);
+    }
+
+    fn instantiate(&mut self, mut req: InstantiateReq, auth: AuthInfo) -> Result<Value> {
+        let (module_path, native) = match self.resolve_native_module(&req.module_id)? {
+            Some(path) => (path, true),
+            None => {
+                req.module_id = self.resolve_gh_module(&req.module_id, None)?;
+                if valid_tagname(&req.module_id) {
+                    let taginfo = self.read_tag(&req.module_id)?;
+                    req.module_id = taginfo.module_id;
+                }
+                ensure!(is_hex_string(&req.module_id), "invalid module_id");
+                (self.ensure_module_in_fs(&req.module_id)?, false)
+            }
+        };
+        let limits = self.limits_for(&auth);
+        log::debug!(
+            "instance {} -> {} ({})",
+            req.module_id,
+            req.req_id,
+            policy::describe(&limits)
+        );
         let (handle, res) = self
             .forker
             .lock()
             .unwrap()
-            .instantiate(req.clone(), module_path)?;
+            .instantiate(req.clone(), module_path, native, limits)?;
+        let res = serde_json::to_value(res)?;
+        if let Some(trace) = &self.trace {
+            trace.record_instantiate(&req, &res);
+        }
         let mut req_instances = self.req_instances.lock().unwrap();
         req_instances.insert(req.req_id, handle);
-        Ok(serde_json::to_value(res)?)
+        Ok(res)
     }
 
     fn run_main(&self, req_id: &String) -> Result<()> {
@@ -629,6 +820,18 @@ impl ModuleRegistry {
                             .unwrap();
                     });
                 }
+                CmdRespChannel::Socket {
+                    resp_ch, conn_id, ..
+                } => {
+                    // the reply only goes to the connection the request came from
+                    let resp_ch = resp_ch.clone();
+                    let conn_id = *conn_id;
+                    rayon::spawn(move || {
+                        let r = s2.exec_wrapped(&msg);
+                        let r = serde_json::to_vec(&r).unwrap();
+                        CmdRespChannel::sock_send(&resp_ch, conn_id, &r);
+                    });
+                }
             }
         }
     }
@@ -645,10 +848,13 @@ impl Stepper {
             req_instances: reg.req_instances.clone(),
             instances: HashMap::default(),
             num_timeouts: HashMap::default(),
+            pending_storage: HashMap::default(),
             limits,
             globals: reg.wasm_ctx.globals.clone(),
             shm,
             token_bytes,
+            trace: reg.trace.clone(),
+            inline_masks: false,
         })
     }
 
@@ -671,28 +877,37 @@ impl Stepper {
         }
     }
 
-    // returns the parent id (if any) or current module id otherwise
-    fn maybe_fork(&mut self, op: &AiciMidOp) -> Result<ModuleInstId> {
+    // returns the parent id (if any) or current module id otherwise;
+    // forks are only recorded in `forks` and executed later by run_forks()
+    fn maybe_fork(
+        &mut self,
+        op: &AiciMidOp,
+        forks: &mut Vec<(ModuleInstId, ModuleInstId)>,
+    ) -> Result<ModuleInstId> {
         self.mk_instance(&op)?;
         let id = op.id;
         if let Some(parent_id) = op.clone_id {
             ensure!(
-                !self.instances.contains_key(&id),
+                !self.instances.contains_key(&id) && !forks.iter().any(|(_, c)| *c == id),
                 "duplicate id {id} (cloning {parent_id})"
             );
             let parent = self.get_worker(parent_id)?;
-            let num_forks = self
-                .instances
-                .values()
-                .filter(|r| r.req_id == parent.req_id)
-                .count();
-            if num_forks + 1 > self.limits.max_forks {
-                anyhow::bail!("too many forks (max={})", self.limits.max_forks)
+            let same_req = |h: &SeqWorkerHandle| h.req_id == parent.req_id;
+            let num_forks = self.instances.values().filter(|h| same_req(h)).count()
+                + forks
+                    .iter()
+                    .filter(|(p, _)| self.instances.get(p).map_or(false, same_req))
+                    .count();
+            let limits = &parent.limits;
+            if num_forks + 1 > limits.max_forks {
+                anyhow::bail!(
+                    "too many forks (max={}; {})",
+                    limits.max_forks,
+                    policy::describe(limits)
+                )
             }
             log::debug!("fork {} -> ({})", parent_id, id);
-            // TODO the forks should be done in parallel, best in tree-like fashion
-            let h = parent.fork(id)?;
-            self.instances.insert(id, h);
+            forks.push((parent_id, id));
             Ok(parent_id)
         } else {
             // make sure worker exists
@@ -701,6 +916,64 @@ impl Stepper {
         }
     }
 
+    // Forks are executed in rounds. In each round, every worker of a fork group
+    // (the parent and the children forked in previous rounds) forks at most one child,
+    // and all workers fork concurrently. This way n children take about log2(n+1) rounds.
+    // Returns the children that failed to fork.
+    fn run_forks(
+        &mut self,
+        forks: Vec<(ModuleInstId, ModuleInstId)>,
+    ) -> Vec<(ModuleInstId, anyhow::Error)> {
+        let mut failed = Vec::new();
+
+        // (workers that can fork, children yet to be forked)
+        let mut groups: Vec<(Vec<ModuleInstId>, Vec<ModuleInstId>)> = Vec::new();
+        for (parent_id, id) in forks {
+            match groups.iter_mut().find(|(src, _)| src[0] == parent_id) {
+                Some((_, todo)) => todo.push(id),
+                None => groups.push((vec![parent_id], vec![id])),
+            }
+        }
+        for (_, todo) in groups.iter_mut() {
+            todo.reverse();
+        }
+
+        loop {
+            let mut started = Vec::new();
+            for (gidx, (sources, todo)) in groups.iter_mut().enumerate() {
+                for src in sources.iter() {
+                    match todo.pop() {
+                        Some(id) => started.push((gidx, *src, id)),
+                        None => break,
+                    }
+                }
+            }
+            if started.is_empty() {
+                break;
+            }
+
+            let mut pending = Vec::new();
+            for (gidx, src, id) in started {
+                match self.instances[&src].start_fork(id) {
+                    Ok(()) => pending.push((gidx, src, id)),
+                    Err(e) => failed.push((id, e)),
+                }
+            }
+
+            for (gidx, src, id) in pending {
+                match self.instances[&src].finish_fork() {
+                    Ok(h) => {
+                        self.instances.insert(id, h);
+                        groups[gidx].0.push(id);
+                    }
+                    Err(e) => failed.push((id, e)),
+                }
+            }
+        }
+
+        failed
+    }
+
     fn mk_instance(&mut self, op: &AiciMidOp) -> Result<()> {
         if let Some(req_id) = &op.req_id {
             let req_id = req_id.clone();
@@ -720,16 +993,22 @@ impl Stepper {
         let block_elts = self.globals.tokrx_info.vocab_size as usize;
         let mut outputs = HashMap::default();
 
+        // figure out which requests the sequences belong to, before any of them are freed
+        let trace_inputs = self.trace.as_ref().map(|_| {
+            StepInputs::new(&req, |id| self.instances.get(&id).map(|h| h.req_id.clone()))
+        });
+
         // first, execute forks
         let mut parents = HashMap::default();
         let mut child_lists = HashMap::default();
+        let mut forks = Vec::new();
 
         for op in req.ops.iter() {
             assert!(op.clone_id.is_none() == op.clone_idx.is_none());
             assert!(op.req_id.is_none() || op.clone_id.is_none());
 
             let id = op.id;
-            match self.maybe_fork(op) {
+            match self.maybe_fork(op, &mut forks) {
                 Ok(parent_id) => {
                     let lst = child_lists.entry(parent_id).or_insert_with(Vec::new);
                     let idx = op.clone_idx.unwrap_or(0);
@@ -745,6 +1024,20 @@ impl Stepper {
             }
         }
 
+        let fork_start = Instant::now();
+        let num_forks = forks.len();
+        for (id, e) in self.run_forks(forks) {
+            if let Some(lst) = child_lists.get_mut(&parents[&id]) {
+                lst.retain(|x| *x != id);
+            }
+            parents.remove(&id);
+            self.worker_error(id, &mut outputs, e);
+        }
+        let fork_micros = fork_start.elapsed().as_micros() as u64;
+        if num_forks > 0 {
+            log::debug!("{num_forks} fork(s) in {fork_micros}us");
+        }
+
         for lst in child_lists.values() {
             assert!(lst.iter().all(|id| self.instances.contains_key(&id)));
         }
@@ -763,7 +1056,7 @@ impl Stepper {
 
         for op in req.ops.into_iter() {
             let instid = op.id;
-            if let Ok(h) = self.get_worker(instid) {
+            if self.instances.contains_key(&instid) {
                 let par = *parents.get(&instid).unwrap();
                 let fork_group = child_lists
                     .get(&par)
@@ -771,22 +1064,33 @@ impl Stepper {
                     .iter()
                     .map(|id| SeqId(*id as u32))
                     .collect::<Vec<_>>();
-                let op = RtMidProcessArg {
+                let mut op = RtMidProcessArg {
                     op: MidProcessArg {
                         backtrack: op.backtrack,
                         tokens: op.tokens.clone(),
                         sampled: op.sampled,
+                        logprobs: op.logprobs,
                         fork_group,
                     },
+                    storage: op.storage,
                 };
                 if self.num_timeouts.get(&instid).is_some() {
                     assert!(op.op.backtrack == 0);
                     assert!(op.op.tokens.is_empty());
+                    if !op.storage.is_empty() {
+                        log::debug!("{instid} pending; queuing storage commands");
+                        self.pending_storage.entry(instid).or_default().extend(op.storage);
+                    }
                     // TODO logit_offset!
                     log::debug!("{instid} still pending (timeout in previous round)");
                     used_ids.push(instid);
                 } else {
-                    match h.start_process(op) {
+                    // queued commands go first, in the order the client sent them
+                    if let Some(mut storage) = self.pending_storage.remove(&instid) {
+                        storage.append(&mut op.storage);
+                        op.storage = storage;
+                    }
+                    match self.instances[&instid].start_process(op) {
                         Ok(_) => used_ids.push(instid),
                         Err(e) => self.worker_error(instid, &mut outputs, e),
                     }
@@ -796,18 +1100,41 @@ impl Stepper {
             }
         }
 
-        let deadline = Instant::now() + std::time::Duration::from_millis(self.limits.max_step_ms);
+        // controllers of finished sequences run concurrently with the mid_process() above
+        let mut post_ids = Vec::new();
+        for id in req.freed.iter() {
+            if let Some(reason) = req.finish_reasons.get(id) {
+                if self.num_timeouts.contains_key(id) {
+                    log::debug!("{id} still pending; skipping post_process");
+                    continue;
+                }
+                if let Some(h) = self.instances.get(id) {
+                    match h.start_post_process(PostProcessArg { reason: *reason }) {
+                        Ok(_) => post_ids.push(*id),
+                        Err(e) => log::warn!("post_process {id}: {e}"),
+                    }
+                }
+            }
+        }
+        // deadlines depend on the limits of the user who started the request
+        let step_start = Instant::now();
         let mut max_offset = 0;
         let mut max_idx = 0;
+        let mut has_masks = false;
+        let mut sparse_masks = HashMap::default();
+        let mut soft_masks = Vec::new();
+        let mut used_masks = Vec::new();
         let first_mask_byte_offset = self.shm.data_off();
         let mask_num_bytes = self.shm.elt_size();
 
         for id in used_ids {
             let prev_timeout = self.num_timeouts.remove(&id).unwrap_or(0);
             let h = self.get_worker(id).unwrap();
+            let limits = h.limits.clone();
+            let deadline = step_start + Duration::from_millis(limits.step_ms());
             let timeout = deadline.saturating_duration_since(Instant::now());
             match h.check_process(timeout) {
-                Ok(mut data) => {
+                Ok((mut data, sparse, soft)) => {
                     if !self.globals.inference_caps.fork {
                         if let Some(r) = &data.result {
                             if r.branches.len() > 1 {
@@ -847,16 +1174,26 @@ impl Stepper {
                         }
                     }
 
+                    let mask_idx = |off: usize| {
+                        let idx = (off - first_mask_byte_offset) / mask_num_bytes;
+                        assert!(idx * mask_num_bytes + first_mask_byte_offset == off);
+                        idx
+                    };
+                    for (off, tokens) in sparse {
+                        sparse_masks.insert(mask_idx(off as usize), tokens);
+                    }
+                    soft_masks.extend(soft.into_iter().map(|off| mask_idx(off as usize)));
                     if let Some(r) = &mut data.result {
                         r.branches = r
                             .branches
                             .iter()
                             .map(|b| {
                                 b.map_mask(|off| {
-                                    let idx = (*off - first_mask_byte_offset) / mask_num_bytes;
-                                    assert!(idx * mask_num_bytes + first_mask_byte_offset == *off);
+                                    let idx = mask_idx(*off);
                                     max_offset = std::cmp::max(max_offset, *off);
                                     max_idx = std::cmp::max(max_idx, idx);
+                                    has_masks = true;
+                                    used_masks.push(idx);
                                     idx
                                 })
                             })
@@ -865,7 +1202,18 @@ impl Stepper {
                     outputs.insert(id, data);
                 }
                 Err(e) => {
-                    if e.to_string() == "timeout" && prev_timeout < self.limits.max_timeout_steps {
+                    let is_timeout = e.to_string() == "timeout";
+                    if is_timeout && limits.fuel_metering() {
+                        // only running out of fuel is reported as the user's fault
+                        self.worker_error(
+                            id,
+                            &mut outputs,
+                            anyhow!(
+                                "host overloaded: step exceeded wall-clock limit of {}ms",
+                                limits.step_ms()
+                            ),
+                        );
+                    } else if is_timeout && prev_timeout < limits.max_timeout_steps {
                         outputs.insert(
                             id,
                             SequenceResult {
@@ -875,12 +1223,15 @@ impl Stepper {
                                 error: String::new(),
                                 storage: vec![],
                                 logs: format!(
-                                    "⏲ timeout [deadline: {}ms; step {}/{}]\n",
-                                    self.limits.max_step_ms,
+                                    "⏲ timeout [deadline: {}ms; step {}/{}; profile {}]\n",
+                                    limits.max_step_ms,
                                     prev_timeout + 1,
-                                    self.limits.max_timeout_steps
+                                    limits.max_timeout_steps,
+                                    limits.profile
                                 ),
                                 micros: start_time.elapsed().as_micros() as u64,
+                                fuel: 0,
+                                timeout: true,
                             },
                         );
                         self.num_timeouts.insert(id, prev_timeout + 1);
@@ -891,18 +1242,73 @@ impl Stepper {
             }
         }
 
+        let mut finished = HashMap::default();
+        for id in post_ids {
+            let h = self.get_worker(id).unwrap();
+            let post_deadline = step_start + Duration::from_millis(h.limits.post_ms());
+            let timeout = post_deadline.saturating_duration_since(Instant::now());
+            let res = match h.check_post_process(timeout) {
+                Ok(r) => r,
+                Err(e) => SequenceResult::from_error(format!("post_process: {e}")),
+            };
+            finished.insert(id, res);
+        }
+
+        // masks allocated but not used in any branch
+        sparse_masks.retain(|idx, _| has_masks && *idx <= max_idx);
+
+        // bool masks can't carry soft biases; if any controller returned them (as f32 rows),
+        // expand the other masks of this step to f32 too
+        let mut bias_type = BiasType::from_u32(self.shm.elt_type() & 0xf).unwrap();
+        if bias_type == BiasType::Bool && !soft_masks.is_empty() {
+            bias_type = BiasType::F32;
+            let num_bytes = (block_elts + 7) / 8;
+            used_masks.sort();
+            used_masks.dedup();
+            for idx in used_masks {
+                if soft_masks.contains(&idx) || sparse_masks.contains_key(&idx) {
+                    continue;
+                }
+                let off = first_mask_byte_offset + idx * mask_num_bytes;
+                let bits = self.shm.slice_at_byte_offset::<u8>(off, num_bytes).to_vec();
+                bias_type.apply_to_shm_allocator(&bits, &self.shm, off);
+            }
+        }
+
         for id in req.freed {
             log::debug!("free module {}", id);
             self.instances.remove(&id);
+            self.pending_storage.remove(&id);
         }
 
+        if let (Some(trace), Some(inputs)) = (&self.trace, trace_inputs) {
+            let masks = MaskLayout {
+                shm: &self.shm,
+                first_mask_byte_offset,
+                mask_num_bytes,
+                vocab_size: block_elts,
+                bias_type,
+                sparse_masks: &sparse_masks,
+            };
+            trace.record_step(inputs, &outputs, &finished, &masks);
+        }
+
+        let masks = if self.inline_masks && has_masks {
+            (0..=max_idx)
+                .map(|idx| match sparse_masks.get(&idx) {
+                    Some(tokens) => self.sparse_bitset(tokens),
+                    None => self.mask_bitset(first_mask_byte_offset + idx * mask_num_bytes),
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
         self.shm.free(max_offset, |client_id| {
             let id = client_id as ModuleInstId;
             !self.num_timeouts.contains_key(&id)
         });
 
-        let bias_type = BiasType::from_u32(self.shm.elt_type() & 0xf).unwrap();
-
         Ok(AiciMidProcessResp {
             seqs: outputs,
             mask_num_bytes,
@@ -910,9 +1316,29 @@ impl Stepper {
             num_masks: max_idx + 1,
             dtype: bias_type.to_string(),
             mask_num_elts: bias_type.bytes_to_elts(mask_num_bytes),
+            fork_micros,
+            finished,
+            masks,
+            sparse_masks,
         })
     }
 
+    /// Base64-encoded mask at given offset; the shm holds bitsets with --listen.
+    fn mask_bitset(&self, off: usize) -> String {
+        let num_bytes = (self.globals.tokrx_info.vocab_size as usize + 7) / 8;
+        let bytes: &[u8] = self.shm.slice_at_byte_offset(off, num_bytes);
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+
+    /// Same format as mask_bitset(), for a mask given as list of allowed tokens.
+    fn sparse_bitset(&self, tokens: &[TokenId]) -> String {
+        let mut bytes = vec![0u8; (self.globals.tokrx_info.vocab_size as usize + 7) / 8];
+        for &t in tokens {
+            bytes[t as usize / 8] |= 1 << (t % 8);
+        }
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+
     fn worker_error<T>(
         &mut self,
         instid: usize,
@@ -923,6 +1349,7 @@ impl Stepper {
         log::warn!("error: {err}");
         map.insert(instid, SequenceResult::from_error(err));
         self.instances.remove(&instid);
+        self.pending_storage.remove(&instid);
     }
 }
 
@@ -937,9 +1364,19 @@ impl Exec for Stepper {
             Some("mid_process") => Ok(serde_json::to_value(
                 &self.aici_mid_process(serde_json::from_value(json)?)?,
             )?),
+            Some("set_encoding") => match json["encoding"].as_str() {
+                // messages are decoded based on their first byte, so there is no state to keep
+                Some(e @ ("json" | "bincode")) => Ok(json!({ "encoding": e })),
+                e => bail_user!("unsupported encoding {e:?}"),
+            },
             _ => return Err(anyhow!("bad op")),
         }
     }
+
+    fn exec_bin(&mut self, msg: &[u8]) -> Result<Vec<u8>> {
+        let req: AiciMidProcessReq = decode_bin(msg)?;
+        Ok(encode_bin(&self.aici_mid_process(req)?))
+    }
 }
 
 impl Exec for ModuleRegistry {
@@ -949,7 +1386,7 @@ impl Exec for ModuleRegistry {
             Some("set_tags") => self.set_tags(serde_json::from_value(json)?, auth),
             Some("get_tags") => self.get_tags(serde_json::from_value(json)?),
             Some("mk_module") => self.mk_module(serde_json::from_value(json)?, auth),
-            Some("instantiate") => self.instantiate(serde_json::from_value(json)?),
+            Some("instantiate") => self.instantiate(serde_json::from_value(json)?, auth),
             _ => return Err(anyhow!("bad op")),
         }
     }
@@ -958,7 +1395,34 @@ impl Exec for ModuleRegistry {
 trait Exec {
     fn exec(&mut self, json: Value, auth: AuthInfo) -> Result<Value>;
 
-    fn exec_wrapped(&mut self, msg: &[u8]) -> Value {
+    /// Execute mid_process request in binary encoding; only the main channel supports it.
+    fn exec_bin(&mut self, _msg: &[u8]) -> Result<Vec<u8>> {
+        Err(anyhow!("binary encoding not supported"))
+    }
+
+    /// Responses to binary requests are binary, unless there is an error.
+    /// `remote` is set for messages from a socket connection.
+    fn exec_msg(&mut self, msg: &[u8], remote: bool) -> Vec<u8> {
+        if msg.first() == Some(&BIN_MSG_MAGIC) {
+            match self.exec_bin(msg) {
+                Ok(resp) => resp,
+                Err(err) => {
+                    let errmsg = UserError::maybe_stacktrace(&err);
+                    log::warn!("dispatch error (binary): {}", errmsg);
+                    serde_json::to_vec(&json!({
+                        "type": "error",
+                        "error": errmsg,
+                        "is_user_error": UserError::is_self(&err)
+                    }))
+                    .unwrap()
+                }
+            }
+        } else {
+            serde_json::to_vec(&self.exec_wrapped(msg, remote)).unwrap()
+        }
+    }
+
+    fn exec_wrapped(&mut self, msg: &[u8], remote: bool) -> Value {
         match serde_json::from_slice::<Value>(msg) {
             Ok(json) => {
                 let rid = json["$rid"].as_str().map(|v| v.to_string());
@@ -966,8 +1430,11 @@ trait Exec {
                 log::trace!("dispatch: rid={:?} op={:?}", rid, json["op"]);
                 let val = match json["op"].as_str() {
                     Some("ping") => Ok(json!({ "pong": 1 })),
+                    Some("stop") if remote => Err(anyhow!("stop not allowed over sockets")),
                     Some("stop") => worker::stop_process(),
                     _ => {
+                        // socket connections only get here after the handshake
+                        // (see --listen-token), so $auth comes from the LLM server
                         let auth = if json["$auth"].as_object().is_none() {
                             Ok(AuthInfo::local_user())
                         } else {
@@ -1034,12 +1501,31 @@ enum CmdRespChannel {
         resp_ch: Arc<Mutex<ServerChannel>>,
         busy_wait_duration: Duration,
     },
+    // one client connection at a time; a new one is accepted when it's closed
+    Socket {
+        listener: SockListener,
+        conn: Option<SockStream>,
+        // writer of the current connection, and its number
+        resp_ch: Arc<Mutex<Option<(usize, SockStream)>>>,
+        conn_id: usize,
+        max_size: usize,
+        token: String,
+    },
 }
 
 impl CmdRespChannel {
     pub fn new(suff: &str, cli: &Cli) -> Result<Self> {
         let busy_wait_duration = Duration::from_millis(cli.busy_wait_time);
-        if cli.futex {
+        if let Some(addr) = &cli.listen {
+            Ok(Self::Socket {
+                listener: SockListener::bind(&sockchannel::side_addr(addr, suff)?)?,
+                conn: None,
+                resp_ch: Arc::new(Mutex::new(None)),
+                conn_id: 0,
+                max_size: cli.json_size * MEGABYTE,
+                token: cli.listen_token()?,
+            })
+        } else if cli.futex {
             let cmd_shm = Shm::new(
                 &cli.prefixed_name("cmd", suff),
                 cli.json_size * MEGABYTE,
@@ -1080,19 +1566,32 @@ impl CmdRespChannel {
                 cmd_ch.busy_reset();
                 resp_ch.lock().unwrap().busy_reset();
             }
-            Self::Futex { .. } => {}
+            Self::Futex { .. } | Self::Socket { .. } => {}
         }
     }
 
-    pub fn respond(&self, json: Value) {
-        let slice = serde_json::to_vec(&json).unwrap();
+    fn sock_send(resp_ch: &Mutex<Option<(usize, SockStream)>>, conn_id: usize, msg: &[u8]) {
+        match resp_ch.lock().unwrap().as_mut() {
+            Some((id, conn)) if *id == conn_id => {
+                if let Err(e) = conn.send_msg(msg) {
+                    log::warn!("socket send: {e}");
+                }
+            }
+            _ => log::warn!("socket send: connection {conn_id} closed; dropping response"),
+        }
+    }
+
+    pub fn respond(&self, slice: &[u8]) {
         match self {
             Self::Sem { resp_ch, .. } => {
-                resp_ch.lock().unwrap().send(&slice).unwrap();
+                resp_ch.lock().unwrap().send(slice).unwrap();
             }
             Self::Futex { resp_ch, .. } => {
-                resp_ch.lock().unwrap().send_resp(&slice).unwrap();
+                resp_ch.lock().unwrap().send_resp(slice).unwrap();
             }
+            Self::Socket {
+                resp_ch, conn_id, ..
+            } => Self::sock_send(resp_ch, *conn_id, slice),
         }
     }
 
@@ -1108,16 +1607,51 @@ impl CmdRespChannel {
                 busy_wait_duration,
                 ..
             } => cmd_ch.recv_req(busy_wait_duration.clone()),
+            Self::Socket {
+                listener,
+                conn,
+                resp_ch,
+                conn_id,
+                max_size,
+                token,
+            } => loop {
+                if conn.is_none() {
+                    match listener.accept().and_then(|c| Ok((c.try_clone()?, c))) {
+                        Ok((writer, mut reader)) => {
+                            // nothing is read from the connection before this succeeds
+                            if let Err(e) = reader.server_auth(token) {
+                                log::warn!("socket auth: {e}");
+                                continue;
+                            }
+                            *conn_id += 1;
+                            *resp_ch.lock().unwrap() = Some((*conn_id, writer));
+                            *conn = Some(reader);
+                        }
+                        Err(e) => {
+                            log::warn!("socket accept: {e}");
+                            continue;
+                        }
+                    }
+                }
+                match conn.as_mut().unwrap().recv_msg(*max_size) {
+                    Ok(msg) => return msg,
+                    Err(e) => {
+                        log::info!("socket connection closed: {e}");
+                        *conn = None;
+                        *resp_ch.lock().unwrap() = None;
+                    }
+                }
+            },
         }
     }
 
     pub fn dispatch_loop(&mut self, mut exec: impl Exec) -> ! {
+        let remote = matches!(self, Self::Socket { .. });
         loop {
             let msg = self.recv();
             //println!("exec main: {}", String::from_utf8_lossy(&msg));
-            let val = exec.exec_wrapped(&msg);
-            //println!("resp main: {}", serde_json::to_string(&val).unwrap());
-            self.respond(val)
+            let resp = exec.exec_msg(&msg, remote);
+            self.respond(&resp)
         }
     }
 }
@@ -1185,9 +1719,36 @@ fn save_tokenizer(cli: &Cli) {
     }
 }
 
-fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>) {
+fn native_modules(cli: &Cli) -> Arc<HashMap<String, PathBuf>> {
+    let mut res = HashMap::default();
+    for spec in cli.native_module.iter() {
+        match spec.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+                res.insert(name.to_string(), PathBuf::from(path));
+            }
+            _ => {
+                eprintln!("invalid --native-module {spec:?}; expecting NAME=PATH");
+                std::process::exit(1);
+            }
+        }
+    }
+    Arc::new(res)
+}
+
+fn install_from_cmdline(
+    cli: &Cli,
+    wasm_ctx: WasmContext,
+    limits: AiciLimits,
+    shm: Rc<ShmAllocator>,
+    token_bytes: Vec<Vec<u8>>,
+) {
     let name = cli.module.as_deref().unwrap();
-    let mut reg = ModuleRegistry::new(wasm_ctx, shm).unwrap();
+    let mut reg = ModuleRegistry::new(wasm_ctx, shm.clone()).unwrap();
+    reg.native_modules = native_modules(cli);
+    // --simulate runs can be recorded too
+    if let Some(dir) = &cli.record {
+        reg.trace = Some(Arc::new(TraceRecorder::new(dir).unwrap()));
+    }
     let module_id = if name.ends_with(".wasm") {
         let wasm_bytes = fs::read(name).unwrap();
         if let Some(gh) = &cli.gh_module {
@@ -1213,18 +1774,43 @@ fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>)
         println!("{}", serde_json::to_string_pretty(&resp).unwrap());
     }
 
+    let arg = match cli.run_arg {
+        Some(ref path) => json!(fs::read_to_string(path).unwrap()),
+        None => json!({"steps":[]}),
+    };
+
+    if cli.simulate {
+        let opts = simulate::SimOptions {
+            module_id: module_id.clone(),
+            module_arg: arg.clone(),
+            prompt: cli.sim_prompt.clone(),
+            max_tokens: cli.sim_max_tokens,
+            sampling: simulate::Sampling::from_cli(
+                &cli.sim_sampling,
+                cli.sim_seed,
+                &cli.sim_tokens,
+            )
+            .unwrap(),
+        };
+        let mut stepper = Stepper::new(&reg, limits, shm, token_bytes).unwrap();
+        if let Err(e) = simulate::simulate(&mut reg, &mut stepper, opts) {
+            println!("simulation failed: {e}");
+            worker::stop_process_with(1);
+        }
+    }
+
     if cli.run {
         let req_id = "main".to_string();
-        let arg = match cli.run_arg {
-            Some(ref path) => json!(fs::read_to_string(path).unwrap()),
-            None => json!({"steps":[]}),
-        };
-        reg.instantiate(InstantiateReq {
-            req_id: req_id.clone(),
-            prompt: json!(""),
-            module_id: module_id.clone(),
-            module_arg: arg,
-        })
+        reg.instantiate(
+            InstantiateReq {
+                req_id: req_id.clone(),
+                prompt: json!(""),
+                module_id: module_id.clone(),
+                module_arg: arg,
+                request_info: Default::default(),
+            },
+            AuthInfo::admin_user(),
+        )
         .unwrap();
         reg.run_main(&req_id).unwrap();
     }
@@ -1232,6 +1818,44 @@ fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>)
     worker::stop_process();
 }
 
+fn replay_trace(
+    cli: &Cli,
+    path: &PathBuf,
+    wasm_ctx: WasmContext,
+    limits: AiciLimits,
+    shm_alloc: Rc<ShmAllocator>,
+    token_bytes: Vec<Vec<u8>>,
+) -> ! {
+    let mut reg = ModuleRegistry::new(wasm_ctx, shm_alloc.clone()).unwrap();
+    reg.native_modules = native_modules(cli);
+    let mut stepper = Stepper::new(&reg, limits, shm_alloc, token_bytes).unwrap();
+    let code = match trace::replay(path, &mut reg, &mut stepper) {
+        Ok(0) => {
+            println!("replay OK");
+            0
+        }
+        Ok(n) => {
+            println!("replay: {n} difference(s)");
+            1
+        }
+        Err(e) => {
+            println!("replay failed: {e}");
+            1
+        }
+    };
+    worker::stop_process_with(code);
+}
+
+fn seccomp_supported() -> bool {
+    match seccomp::check() {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("{e}; worker processes will not be sandboxed");
+            false
+        }
+    }
+}
+
 fn main() -> () {
     setup_log();
 
@@ -1243,6 +1867,8 @@ fn main() -> () {
     }
 
     let bias_type = match BiasType::from_str(&cli.bias_dtype) {
+        // masks are sent as bitsets over the socket
+        Ok(_) if cli.listen.is_some() => BiasType::Bool,
         Ok(x) => x,
         Err(e) => {
             eprintln!("invalid bias_type: {}", e);
@@ -1256,14 +1882,22 @@ fn main() -> () {
         max_memory_bytes: cli.wasm_max_memory * MEGABYTE,
         max_init_ms: cli.wasm_max_init_time,
         max_step_ms: cli.wasm_max_step_time,
+        max_post_ms: cli.wasm_max_post_time,
+        max_step_fuel: cli.wasm_max_step_fuel,
+        max_init_fuel: cli.wasm_max_init_fuel,
+        fuel_wall_clock_ms: cli.wasm_fuel_wall_clock_ms,
         max_timeout_steps: cli.wasm_max_timeout_steps,
         max_compile_ms: 10_000,
         logit_memory_bytes: cli.bin_size * MEGABYTE,
         busy_wait_duration: Duration::from_millis(cli.busy_wait_time),
         max_forks: cli.wasm_max_forks,
+        worker_pool_size: cli.worker_pool_size,
+        max_sparse_mask_tokens: cli.sparse_mask_max_tokens,
+        seccomp: !cli.no_seccomp && seccomp_supported(),
 
         module_upload: !cli.restricted,
         gh_download: !cli.restricted,
+        profile: policy::CLI_PROFILE.to_string(),
     };
 
     if cli.bench {
@@ -1289,45 +1923,80 @@ fn main() -> () {
         return ();
     }
 
-    let bin_shm = Shm::new(
-        &MessageChannel::shm_name(&cli.prefixed_name("bin", "")),
-        limits.logit_memory_bytes,
-        if cli.module.is_none() {
-            shm::Unlink::None
-        } else {
-            shm::Unlink::Pre
-        },
-    )
+    let bin_shm = if cli.replay.is_some() || cli.listen.is_some() {
+        // nobody else reads the masks
+        Shm::anon(limits.logit_memory_bytes)
+    } else {
+        Shm::new(
+            &MessageChannel::shm_name(&cli.prefixed_name("bin", "")),
+            limits.logit_memory_bytes,
+            if cli.module.is_none() {
+                shm::Unlink::None
+            } else {
+                shm::Unlink::Pre
+            },
+        )
+    }
     .unwrap();
 
     let vocab_size = wasm_ctx.globals.tokrx_info.vocab_size as usize;
+    // bool slots fit f32 rows, so steps where controllers return soft biases can switch to f32
+    let slot_type = match bias_type {
+        BiasType::Bool if cli.listen.is_none() => BiasType::F32,
+        _ => bias_type,
+    };
     let shm_alloc = Rc::new(ShmAllocator::new(
         bin_shm,
         // allow for a little leeway
-        bias_type.size_in_bytes((vocab_size + 1000) & !63),
+        slot_type.size_in_bytes((vocab_size + 1000) & !63),
         bias_type.to_u32(),
     ));
 
     if cli.module.is_some() {
-        install_from_cmdline(&cli, wasm_ctx, shm_alloc.clone());
+        install_from_cmdline(&cli, wasm_ctx, limits, shm_alloc, token_bytes);
         return ();
     }
 
-    if !cli.server {
-        println!("missing --server");
+    if let Some(path) = &cli.replay {
+        replay_trace(&cli, path, wasm_ctx, limits, shm_alloc, token_bytes);
+    }
+
+    if !cli.server && cli.listen.is_none() {
+        println!("missing --server or --listen");
         std::process::exit(1);
     }
 
+    if cli.listen.is_some() {
+        if let Err(e) = cli.listen_token() {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+
     ALLOCATOR.set_limit(MAX_MALLOC).expect("set memory limit");
 
     set_max_priority();
 
-    let reg = ModuleRegistry::new(wasm_ctx, shm_alloc.clone()).unwrap();
+    let mut reg = ModuleRegistry::new(wasm_ctx, shm_alloc.clone()).unwrap();
+    reg.native_modules = native_modules(&cli);
+    if let Some(path) = &cli.policy {
+        match Policy::load(path) {
+            Ok(p) => reg.policy = Arc::new(p),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(dir) = &cli.record {
+        reg.trace = Some(Arc::new(TraceRecorder::new(dir).unwrap()));
+    }
 
     // needs to be done after WorkerForker is spawned
     setup_bg_worker_pool();
 
-    let exec = Stepper::new(&reg, limits, shm_alloc, token_bytes).unwrap();
+    let mut exec = Stepper::new(&reg, limits, shm_alloc, token_bytes).unwrap();
+    exec.inline_masks = cli.listen.is_some();
     let cli2 = cli.clone();
     rayon::spawn(move || {
         let reg_disp = CmdRespChannel::new("-side", &cli2).unwrap();
@@ -4,7 +4,10 @@ use crate::{
     worker::{GroupHandle, RtMidProcessArg},
     TimerSet, UserError,
 };
-use aici_abi::{toktrie::TokTrie, InitPromptArg, InitPromptResult, ProcessResultOffset, TokenId};
+use aici_abi::{
+    toktrie::TokTrie, InitPromptArg, InitPromptResult, PostProcessArg, ProcessResultOffset,
+    RequestInfo, TokenId,
+};
 use aicirt::{
     api::{InferenceCapabilities, SequenceResult},
     bail_user,
@@ -13,8 +16,15 @@ use aicirt::{
     user_error,
 };
 use anyhow::{anyhow, ensure, Result};
-use serde::Deserialize;
-use std::{path::PathBuf, rc::Rc, sync::Arc, time::Instant};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::Instant,
+};
 use wasmtime;
 
 #[derive(Clone)]
@@ -24,6 +34,8 @@ pub struct WasmContext {
     pub globals: GlobalInfo,
     pub limits: AiciLimits,
     pub timers: TimerSet,
+    /// Snapshots of pre-initialized modules are only valid for the same value of this.
+    pub snapshot_key: String,
 }
 
 impl WasmContext {
@@ -41,7 +53,6 @@ impl WasmContext {
         cfg.debug_info(false)
             .wasm_backtrace(true)
             .native_unwind_info(true)
-            .consume_fuel(false)
             .max_wasm_stack(512 * 1024)
             .wasm_tail_call(false)
             .wasm_threads(false)
@@ -57,6 +68,9 @@ impl WasmContext {
         // we use fork()
         cfg.macos_use_mach_ports(false);
 
+        // deterministic metering; modules compiled with different setting get recompiled
+        cfg.consume_fuel(limits.fuel_metering());
+
         // disable stuff we don't need
         cfg.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Disable)
             .wasm_reference_types(false);
@@ -80,6 +94,12 @@ impl WasmContext {
         // let tokens = tok.encode("I am something", false).unwrap();
         // println!("tokens: {:?}", tokens);
 
+        // everything the module can learn from the host before request is known
+        let mut hasher = <Sha256 as Digest>::new();
+        hasher.update(&bytes);
+        hasher.update(serde_json::to_vec(&inference_caps)?);
+        let snapshot_key = hex::encode(hasher.finalize());
+
         let globals = GlobalInfo {
             tokrx_info: tokenizer.tokrx_info(),
             tok_trie: Arc::new(trie2),
@@ -94,17 +114,41 @@ impl WasmContext {
             globals,
             limits,
             timers: TimerSet::new(),
+            snapshot_key,
         })
     }
 }
 
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+enum SnapshotVal {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+/// Header of snapshot file; it's followed by the contents of linear memory.
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    key: String,
+    memory_size: usize,
+    // only exported globals are accessible; the stack pointer is back
+    // to its initial value after aici_preinit() returns
+    globals: Vec<(String, SnapshotVal)>,
+}
+
 pub struct ModuleInstance {
     store: wasmtime::Store<ModuleData>,
     memory: wasmtime::Memory,
     instance: wasmtime::Instance,
     handle: WasmAici,
-    #[allow(dead_code)]
     limits: AiciLimits,
+    fuel_budget: u64,
+    snapshot_key: String,
+    // aici_init() was already called, or restored from a snapshot taken after it
+    initialized: bool,
 }
 type WasmPtr = u32;
 type WasmAici = u32;
@@ -128,7 +172,13 @@ impl ModuleInstance {
             Ok(r) => Ok(r),
             Err(e) => {
                 ctx.had_error = true;
-                if let Some(e) = e.downcast_ref::<UserError>() {
+                if let Some(wasmtime::Trap::OutOfFuel) = e.downcast_ref::<wasmtime::Trap>() {
+                    Err(user_error!(
+                        "{}\nfuel budget of {} exceeded",
+                        ctx.string_log(),
+                        self.fuel_budget
+                    ))
+                } else if let Some(e) = e.downcast_ref::<UserError>() {
                     Err(user_error!("{}\n{}", ctx.string_log(), e))
                 } else if let Some(bt) = e.downcast_ref::<wasmtime::WasmBacktrace>() {
                     Err(user_error!(
@@ -185,7 +235,6 @@ impl ModuleInstance {
         id: ModuleInstId,
         ctx: WasmContext,
         module: wasmtime::Module,
-        module_arg: String,
         group_channel: GroupHandle,
         shm: Rc<ShmAllocator>,
     ) -> Result<Self> {
@@ -193,18 +242,21 @@ impl ModuleInstance {
 
         let mut store = wasmtime::Store::new(
             engine,
+            // the module argument is only set after preinit(), see set_module_arg()
             ModuleData::new(
                 id,
                 &ctx.limits,
-                &module,
-                module_arg,
-                &ctx.linker,
+                String::new(),
                 ctx.globals,
                 group_channel,
                 shm,
             ),
         );
         store.limiter(|state| &mut state.store_limits);
+        if ctx.limits.fuel_metering() {
+            // budgets are set for each step; this covers instantiation and run_main()
+            store.set_fuel(u64::MAX)?;
+        }
 
         let instance = ctx.linker.instantiate(&mut store, &module)?;
         let memory = instance
@@ -219,15 +271,167 @@ impl ModuleInstance {
             memory,
             instance,
             limits: ctx.limits,
+            fuel_budget: u64::MAX,
+            snapshot_key: ctx.snapshot_key,
+            initialized: false,
         })
     }
 
+    /// If the module exports aici_preinit(), restore its state after that call from
+    /// the snapshot at `path`, or run it and save the snapshot for the next instances.
+    /// aici_preinit() can do expensive setup, but can't depend on the module argument;
+    /// it runs before set_module_arg() and set_request_info(), so the snapshot
+    /// can't capture the data of the request that happened to create it.
+    pub fn preinit(&mut self, path: &Path) -> Result<()> {
+        if self
+            .instance
+            .get_export(&mut self.store, "aici_preinit")
+            .is_none()
+        {
+            return Ok(());
+        }
+
+        match self.restore_snapshot(path) {
+            Ok(true) => {
+                self.initialized = true;
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!("can't restore snapshot {}: {e}", path.display()),
+        }
+
+        let t0 = Instant::now();
+        self.set_fuel_budget(self.limits.max_init_fuel)?;
+        self.run_init()?;
+        self.call_func::<(), ()>("aici_preinit", ())?;
+        // the logs would only show up for the first instance
+        let _ = self.store.data_mut().string_log();
+        if let Err(e) = self.save_snapshot(path) {
+            log::warn!("can't save snapshot {}: {e}", path.display());
+        }
+        log::info!("preinit and snapshot: {:?}", t0.elapsed());
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, path: &Path) -> Result<()> {
+        let exported = self
+            .instance
+            .exports(&mut self.store)
+            .filter_map(|e| {
+                let name = e.name().to_string();
+                e.into_global().map(|g| (name, g))
+            })
+            .collect::<Vec<_>>();
+        let mut globals = vec![];
+        for (name, g) in exported {
+            if g.ty(&self.store).mutability() != wasmtime::Mutability::Var {
+                continue;
+            }
+            let v = match g.get(&mut self.store) {
+                wasmtime::Val::I32(v) => SnapshotVal::I32(v),
+                wasmtime::Val::I64(v) => SnapshotVal::I64(v),
+                wasmtime::Val::F32(v) => SnapshotVal::F32(v),
+                wasmtime::Val::F64(v) => SnapshotVal::F64(v),
+                v => bail_user!("can't snapshot global {name}: {v:?}"),
+            };
+            globals.push((name, v));
+        }
+
+        let memory = self.memory.data(&self.store);
+        let header = serde_json::to_vec(&SnapshotHeader {
+            key: self.snapshot_key.clone(),
+            memory_size: memory.len(),
+            globals,
+        })?;
+        let mut data = Vec::with_capacity(4 + header.len() + memory.len());
+        data.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        data.extend_from_slice(&header);
+        data.extend_from_slice(memory);
+
+        // other workers may be doing the same
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn restore_snapshot(&mut self, path: &Path) -> Result<bool> {
+        let data = match fs::read(path) {
+            Ok(d) => d,
+            Err(_) => return Ok(false),
+        };
+        ensure!(data.len() >= 4, "snapshot too short");
+        let header_len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        ensure!(data.len() >= 4 + header_len, "snapshot too short");
+        let header: SnapshotHeader = serde_json::from_slice(&data[4..4 + header_len])?;
+        if header.key != self.snapshot_key {
+            // tokenizer or host capabilities changed
+            return Ok(false);
+        }
+        let memory = &data[4 + header_len..];
+        ensure!(memory.len() == header.memory_size, "snapshot memory size mismatch");
+
+        let curr_size = self.memory.data_size(&self.store);
+        ensure!(curr_size <= memory.len(), "snapshot memory too small");
+        let pages = (memory.len() - curr_size) / WASM_PAGE_SIZE;
+        self.memory.grow(&mut self.store, pages as u64)?;
+        ensure!(
+            self.memory.data_size(&self.store) == memory.len(),
+            "snapshot memory size not a multiple of page size"
+        );
+        self.memory.data_mut(&mut self.store).copy_from_slice(memory);
+
+        for (name, v) in header.globals {
+            let g = self
+                .instance
+                .get_global(&mut self.store, &name)
+                .ok_or_else(|| anyhow!("global {name} missing"))?;
+            let v = match v {
+                SnapshotVal::I32(v) => wasmtime::Val::I32(v),
+                SnapshotVal::I64(v) => wasmtime::Val::I64(v),
+                SnapshotVal::F32(v) => wasmtime::Val::F32(v),
+                SnapshotVal::F64(v) => wasmtime::Val::F64(v),
+            };
+            g.set(&mut self.store, v)?;
+        }
+        Ok(true)
+    }
+
+    /// Set fuel for the next step; 0 means unlimited.
+    fn set_fuel_budget(&mut self, limit: u64) -> Result<()> {
+        if self.limits.fuel_metering() {
+            self.fuel_budget = if limit == 0 { u64::MAX } else { limit };
+            self.store.set_fuel(self.fuel_budget)?;
+        }
+        Ok(())
+    }
+
+    fn fuel_used(&self) -> u64 {
+        if self.limits.fuel_metering() {
+            self.fuel_budget - self.store.get_fuel().unwrap_or(0)
+        } else {
+            0
+        }
+    }
+
     pub fn set_id(&mut self, id: ModuleInstId) {
         self.store.data_mut().id = id;
     }
 
+    pub fn set_request_info(&mut self, info: RequestInfo) {
+        self.store.data_mut().request_info = info;
+    }
+
+    pub fn set_module_arg(&mut self, arg: String) {
+        self.store.data_mut().set_module_arg(arg);
+    }
+
+    // aici_init() sets up the host interface and can only run once
     fn run_init(&mut self) -> Result<()> {
-        self.call_func::<(), ()>("aici_init", ())?;
+        if !self.initialized {
+            self.call_func::<(), ()>("aici_init", ())?;
+            self.initialized = true;
+        }
         Ok(())
     }
 
@@ -252,65 +456,24 @@ impl ModuleInstance {
         &self.store.data().group_channel
     }
 
-    fn proc_result<T: for<'a> Deserialize<'a>>(&self) -> Result<T> {
-        let bytes = &self.store.data().process_result;
-        if bytes.len() == 0 {
-            Err(anyhow!("aici_host_return_process_result not called"))
-        } else {
-            serde_json::from_slice::<T>(bytes).map_err(|e| e.into())
-        }
+    pub fn take_sparse_masks(&mut self) -> Vec<(u32, Vec<TokenId>)> {
+        std::mem::take(&mut self.store.data_mut().sparse_masks)
+    }
+
+    pub fn take_soft_masks(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.store.data_mut().soft_masks)
     }
 
     fn do_mid_process(&mut self, op: RtMidProcessArg) -> Result<ProcessResultOffset> {
+        self.set_fuel_budget(self.limits.max_step_fuel)?;
         self.store.data_mut().set_mid_process_data(op);
         self.call_func::<WasmAici, ()>("aici_mid_process", self.handle)?;
-        let res: ProcessResultOffset = self.proc_result()?;
-        let offs = &self.store.data().logit_offsets;
-        let res = ProcessResultOffset {
-            branches: res
-                .branches
-                .iter()
-                .map(|b| {
-                    b.map_mask(|o| {
-                        let o32 = *o as u32;
-                        if !offs.contains(&o32) {
-                            panic!("logit offset not found: {}", o);
-                        }
-                        *o
-                    })
-                })
-                .collect(),
-        };
-        Ok(res)
+        self.store.data().mid_process_result()
     }
 
     fn seq_result<T>(&mut self, lbl: &str, t0: Instant, res: Result<T>) -> SequenceResult<T> {
-        // 10us accuracy for Spectre mitigation
-        let micros = (t0.elapsed().as_micros() as u64 / 10) * 10;
-        let logs = self.store.data_mut().string_log();
-        let storage = std::mem::take(&mut self.store.data_mut().storage_log);
-        match res {
-            Ok(r) => SequenceResult {
-                error: String::new(),
-                logs,
-                storage,
-                micros,
-                result: Some(r),
-            },
-
-            Err(e) => {
-                let error = format!("Error ({lbl}): {}", UserError::maybe_stacktrace(&e));
-                let logs = logs + "\n" + &error;
-                log::warn!("exec: {error}");
-                SequenceResult {
-                    error,
-                    logs,
-                    storage,
-                    micros,
-                    result: None,
-                }
-            }
-        }
+        let fuel = self.fuel_used();
+        seq_result(self.store.data_mut(), lbl, t0, fuel, res)
     }
 
     pub fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset> {
@@ -320,11 +483,35 @@ impl ModuleInstance {
         self.seq_result("mid", t0, res)
     }
 
+    fn do_post_process(&mut self, arg: PostProcessArg) -> Result<()> {
+        // modules built against older aici_abi don't have it
+        if self
+            .instance
+            .get_export(&mut self.store, "aici_post_process")
+            .is_none()
+        {
+            return Ok(());
+        }
+        self.set_fuel_budget(self.limits.max_step_fuel)?;
+        self.store
+            .data_mut()
+            .set_process_arg(serde_json::to_vec(&arg)?);
+        self.call_func::<WasmAici, ()>("aici_post_process", self.handle)?;
+        Ok(())
+    }
+
+    pub fn post_process(&mut self, arg: PostProcessArg) -> SequenceResult {
+        let t0 = Instant::now();
+        let res = self.do_post_process(arg);
+        self.seq_result("post", t0, res)
+    }
+
     pub fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
         self.store.data_mut().tokenize_bytes(s.as_bytes())
     }
 
     fn setup_inner(&mut self, prompt: Vec<TokenId>) -> Result<InitPromptResult> {
+        self.set_fuel_budget(self.limits.max_init_fuel)?;
         self.run_init()?;
 
         self.handle = self.call_func::<(), WasmAici>("aici_create", ())?;
@@ -333,7 +520,9 @@ impl ModuleInstance {
             .data_mut()
             .set_process_arg(serde_json::to_vec(&InitPromptArg { prompt })?);
         self.call_func::<WasmAici, ()>("aici_init_prompt", self.handle)?;
-        let res: InitPromptResult = self.proc_result()?;
+        let res: InitPromptResult = self.store.data().proc_result()?;
+        // the controller may have rewritten the prompt
+        self.store.data_mut().request_info.prompt_tokens = res.prompt.len();
         Ok(res)
     }
 
@@ -345,3 +534,95 @@ impl ModuleInstance {
         }
     }
 }
+
+pub fn seq_result<T>(
+    data: &mut ModuleData,
+    lbl: &str,
+    t0: Instant,
+    fuel: u64,
+    res: Result<T>,
+) -> SequenceResult<T> {
+    // 10us accuracy for Spectre mitigation
+    let micros = (t0.elapsed().as_micros() as u64 / 10) * 10;
+    let logs = data.string_log();
+    let storage = std::mem::take(&mut data.storage_log);
+    match res {
+        Ok(r) => SequenceResult {
+            error: String::new(),
+            logs,
+            storage,
+            micros,
+            fuel,
+            timeout: false,
+            result: Some(r),
+        },
+
+        Err(e) => {
+            let error = format!("Error ({lbl}): {}", UserError::maybe_stacktrace(&e));
+            let logs = logs + "\n" + &error;
+            log::warn!("exec: {error}");
+            SequenceResult {
+                error,
+                logs,
+                storage,
+                micros,
+                fuel,
+                timeout: false,
+                result: None,
+            }
+        }
+    }
+}
+
+/// Controller running in a seq worker; either a wasm module or a native plugin.
+pub trait Controller {
+    fn set_id(&mut self, id: ModuleInstId);
+    fn set_request_info(&mut self, info: RequestInfo);
+    fn group_channel(&self) -> &GroupHandle;
+    fn tokenize(&mut self, s: &str) -> Result<Vec<u32>>;
+    fn setup(&mut self, prompt: Vec<TokenId>) -> SequenceResult<InitPromptResult>;
+    fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset>;
+    fn post_process(&mut self, arg: PostProcessArg) -> SequenceResult;
+    fn run_main(&mut self) -> Result<()>;
+    /// Masks from the last mid_process() that were reported as token lists, by shm offset.
+    fn take_sparse_masks(&mut self) -> Vec<(u32, Vec<TokenId>)>;
+    /// Masks from the last mid_process() written as f32 rows with soft biases, by shm offset.
+    fn take_soft_masks(&mut self) -> Vec<u32>;
+    /// Called in the child process after the worker forks.
+    fn after_fork(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Controller for ModuleInstance {
+    fn set_id(&mut self, id: ModuleInstId) {
+        ModuleInstance::set_id(self, id)
+    }
+    fn set_request_info(&mut self, info: RequestInfo) {
+        ModuleInstance::set_request_info(self, info)
+    }
+    fn group_channel(&self) -> &GroupHandle {
+        ModuleInstance::group_channel(self)
+    }
+    fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
+        ModuleInstance::tokenize(self, s)
+    }
+    fn setup(&mut self, prompt: Vec<TokenId>) -> SequenceResult<InitPromptResult> {
+        ModuleInstance::setup(self, prompt)
+    }
+    fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset> {
+        ModuleInstance::mid_process(self, op)
+    }
+    fn post_process(&mut self, arg: PostProcessArg) -> SequenceResult {
+        ModuleInstance::post_process(self, arg)
+    }
+    fn run_main(&mut self) -> Result<()> {
+        ModuleInstance::run_main(self)
+    }
+    fn take_sparse_masks(&mut self) -> Vec<(u32, Vec<TokenId>)> {
+        ModuleInstance::take_sparse_masks(self)
+    }
+    fn take_soft_masks(&mut self) -> Vec<u32> {
+        ModuleInstance::take_soft_masks(self)
+    }
+}
@@ -0,0 +1,388 @@
+//! Trusted native controllers, loaded as shared libraries (`--native-module NAME=PATH`).
+//!
+//! The library is loaded in the seq worker, in place of the wasm module, and talks to
+//! the same ModuleData through the callbacks in NativeHostFns. There is no sandbox,
+//! memory limit, or fuel metering, so only libraries from the operator should be loaded.
+
+use crate::{
+    abi,
+    api::ModuleInstId,
+    hostimpl::{BlobId, ModuleData},
+    moduleinstance::{seq_result, Controller, WasmContext},
+    worker::{GroupHandle, RtMidProcessArg},
+};
+use aici_abi::{
+    InitPromptArg, InitPromptResult, NativeHostFns, PostProcessArg, ProcessResultOffset,
+    RequestInfo, TokenId,
+};
+use aicirt::{api::SequenceResult, bail_user, shm::ShmAllocator, user_error};
+use anyhow::{anyhow, Result};
+use std::{
+    ffi::{c_void, CStr, CString},
+    fs,
+    io::{Read, Seek, SeekFrom},
+    os::fd::AsRawFd,
+    path::Path,
+    rc::Rc,
+    time::Instant,
+};
+
+// there is only one controller per seq worker process
+static mut NATIVE_DATA: Option<ModuleData> = None;
+
+fn data() -> &'static mut ModuleData {
+    unsafe { NATIVE_DATA.as_mut().unwrap() }
+}
+
+unsafe fn slice<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+    if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, len)
+    }
+}
+
+fn or_fatal(res: Result<u32>) -> u32 {
+    match res {
+        Ok(v) => v,
+        Err(e) => {
+            data().fatal(&e.to_string());
+            0
+        }
+    }
+}
+
+extern "C" fn read_blob(blob_id: u32, dst: *mut u8, size: u32) -> u32 {
+    let data = data();
+    match data.blob(blob_id) {
+        Some(blob) => {
+            let min_len = std::cmp::min(size as usize, blob.len());
+            unsafe { std::ptr::copy_nonoverlapping(blob.as_ptr(), dst, min_len) };
+            blob.len() as u32
+        }
+        None => {
+            data.fatal("invalid blob_id");
+            0
+        }
+    }
+}
+
+extern "C" fn token_trie() -> u32 {
+    BlobId::TRIE.0
+}
+
+extern "C" fn module_arg() -> u32 {
+    BlobId::MODULE_ARG.0
+}
+
+extern "C" fn process_arg() -> u32 {
+    BlobId::PROCESS_ARG.0
+}
+
+extern "C" fn tokenize(src: *const u8, src_size: u32) -> u32 {
+    let s = unsafe { slice(src, src_size as usize) };
+    data().tokenize_blob(s).0
+}
+
+extern "C" fn return_logit_bias(src: *const u32) -> u32 {
+    let data = data();
+    let mask = unsafe { slice(src as *const u8, data.mask_num_bytes()) };
+    or_fatal(data.return_logit_bias(mask, None))
+}
+
+extern "C" fn return_logit_bias_soft(
+    src: *const u32,
+    tokens: *const u32,
+    bias: *const f32,
+    num: u32,
+) -> u32 {
+    let data = data();
+    let biases = unsafe { slice(bias, num as usize) }.to_vec();
+    let toks = if tokens.is_null() {
+        None
+    } else {
+        Some(unsafe { slice(tokens, num as usize) }.to_vec())
+    };
+    let mask = unsafe { slice(src as *const u8, data.mask_num_bytes()) };
+    or_fatal(
+        data.soft_biases(toks, biases)
+            .and_then(|soft| data.return_logit_bias(mask, Some(soft))),
+    )
+}
+
+extern "C" fn self_seq_id() -> u32 {
+    data().id as u32
+}
+
+extern "C" fn return_process_result(res: *const u8, res_size: u32) {
+    data().process_result = unsafe { slice(res, res_size as usize) }.to_vec();
+}
+
+extern "C" fn storage_cmd(cmd: *const u8, cmd_size: u32) -> u32 {
+    let cmd = unsafe { slice(cmd, cmd_size as usize) }.to_vec();
+    data().aici_host_storage_cmd(cmd).0
+}
+
+extern "C" fn eos_token() -> TokenId {
+    data().globals.tokrx_info.tok_eos
+}
+
+extern "C" fn get_config(src: *const u8, src_size: u32) -> i32 {
+    let name = unsafe { slice(src, src_size as usize) };
+    data().get_config(&String::from_utf8_lossy(name))
+}
+
+extern "C" fn request_info() -> u32 {
+    data().request_info_blob().0
+}
+
+extern "C" fn stop() {
+    // the controller aborts after this returns; the worker dying is reported as an error
+    log::warn!("{}: aici_host_stop() in native controller", data().id);
+}
+
+static HOST_FNS: NativeHostFns = NativeHostFns {
+    read_blob,
+    token_trie,
+    module_arg,
+    process_arg,
+    tokenize,
+    return_logit_bias,
+    return_logit_bias_soft,
+    self_seq_id,
+    return_process_result,
+    storage_cmd,
+    eos_token,
+    get_config,
+    request_info,
+    stop,
+};
+
+type AiciHandle = *mut c_void;
+
+fn dl_error() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        "unknown error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(err) }.to_string_lossy().to_string()
+    }
+}
+
+unsafe fn symbol(lib: *mut c_void, name: &str) -> Option<*mut c_void> {
+    let cname = CString::new(name).unwrap();
+    let ptr = libc::dlsym(lib, cname.as_ptr());
+    if ptr.is_null() {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+unsafe fn required_symbol(lib: *mut c_void, name: &str) -> Result<*mut c_void> {
+    symbol(lib, name).ok_or_else(|| user_error!("native controller doesn't export {name}"))
+}
+
+/// Unlinked file (a memfd on Linux) that stdout of the worker is redirected to,
+/// so that what the controller prints ends up in the logs, just like with wasm modules.
+struct StdoutCapture {
+    file: fs::File,
+}
+
+impl StdoutCapture {
+    // a memfd, so it can be re-created in forks under the seccomp filter
+    #[cfg(target_os = "linux")]
+    fn open() -> Result<fs::File> {
+        use std::os::fd::FromRawFd;
+        let name = b"aici-native-stdout\0".as_ptr() as *const libc::c_char;
+        let fd = unsafe { libc::memfd_create(name, libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(anyhow!("memfd_create: {}", std::io::Error::last_os_error()));
+        }
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open() -> Result<fs::File> {
+        let path = std::env::temp_dir().join(format!("aici-native-{}.log", std::process::id()));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(file)
+    }
+
+    fn new() -> Result<Self> {
+        let file = Self::open()?;
+        if unsafe { libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+            return Err(anyhow!("dup2: {}", std::io::Error::last_os_error()));
+        }
+        Ok(StdoutCapture { file })
+    }
+
+    fn drain(&mut self) -> Vec<u8> {
+        // the file offset is shared with stdout, so it's at the end of the output;
+        // read_to_end() would call statx(), which the seccomp filter doesn't allow
+        let len = self.file.stream_position().unwrap_or(0);
+        let mut buf = vec![0u8; len as usize];
+        let _ = self.file.seek(SeekFrom::Start(0));
+        if self.file.read_exact(&mut buf).is_err() {
+            buf.clear();
+        }
+        let _ = self.file.set_len(0);
+        let _ = self.file.seek(SeekFrom::Start(0));
+        buf
+    }
+}
+
+pub struct NativeInstance {
+    handle: AiciHandle,
+    create: extern "C" fn() -> AiciHandle,
+    init_prompt: extern "C" fn(AiciHandle),
+    mid_process: extern "C" fn(AiciHandle),
+    post_process: Option<extern "C" fn(AiciHandle)>,
+    stdout: StdoutCapture,
+}
+
+impl NativeInstance {
+    pub fn new(
+        id: ModuleInstId,
+        ctx: &WasmContext,
+        path: &Path,
+        module_arg: String,
+        group_channel: GroupHandle,
+        shm: Rc<ShmAllocator>,
+    ) -> Result<Self> {
+        unsafe {
+            NATIVE_DATA = Some(ModuleData::new(
+                id,
+                &ctx.limits,
+                module_arg,
+                ctx.globals.clone(),
+                group_channel,
+                shm,
+            ));
+        }
+
+        let cpath = CString::new(path.to_string_lossy().as_bytes())?;
+        let lib = unsafe { libc::dlopen(cpath.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if lib.is_null() {
+            bail_user!("can't load {}: {}", path.display(), dl_error());
+        }
+
+        let r = unsafe {
+            let init: extern "C" fn(&'static NativeHostFns) -> u32 =
+                std::mem::transmute(required_symbol(lib, "aici_native_init")?);
+            let version = init(&HOST_FNS);
+            abi::check_version(version)?;
+            NativeInstance {
+                handle: std::ptr::null_mut(),
+                create: std::mem::transmute(required_symbol(lib, "aici_create")?),
+                init_prompt: std::mem::transmute(required_symbol(lib, "aici_init_prompt")?),
+                mid_process: std::mem::transmute(required_symbol(lib, "aici_mid_process")?),
+                post_process: symbol(lib, "aici_post_process").map(|p| std::mem::transmute(p)),
+                stdout: StdoutCapture::new()?,
+            }
+        };
+        // the library is never unloaded; the worker process exits instead
+        Ok(r)
+    }
+
+    fn call(&mut self, name: &str, f: impl FnOnce(AiciHandle)) -> Result<()> {
+        if data().had_error {
+            bail_user!("Previous native controller error");
+        }
+        f(self.handle);
+        let data = data();
+        data.write_log(&self.stdout.drain());
+        data.flush_logs(name);
+        if data.had_error {
+            Err(user_error!("{}", data.string_log()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn setup_inner(&mut self, prompt: Vec<TokenId>) -> Result<InitPromptResult> {
+        self.handle = (self.create)();
+        data().set_process_arg(serde_json::to_vec(&InitPromptArg { prompt })?);
+        let f = self.init_prompt;
+        self.call("aici_init_prompt", f)?;
+        let res: InitPromptResult = data().proc_result()?;
+        // the controller may have rewritten the prompt
+        data().request_info.prompt_tokens = res.prompt.len();
+        Ok(res)
+    }
+
+    fn do_mid_process(&mut self, op: RtMidProcessArg) -> Result<ProcessResultOffset> {
+        data().set_mid_process_data(op);
+        let f = self.mid_process;
+        self.call("aici_mid_process", f)?;
+        data().mid_process_result()
+    }
+
+    fn do_post_process(&mut self, arg: PostProcessArg) -> Result<()> {
+        if let Some(f) = self.post_process {
+            data().set_process_arg(serde_json::to_vec(&arg)?);
+            self.call("aici_post_process", f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Controller for NativeInstance {
+    fn set_id(&mut self, id: ModuleInstId) {
+        data().id = id;
+    }
+
+    fn set_request_info(&mut self, info: RequestInfo) {
+        data().request_info = info;
+    }
+
+    fn group_channel(&self) -> &GroupHandle {
+        &data().group_channel
+    }
+
+    fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
+        data().tokenize_bytes(s.as_bytes())
+    }
+
+    fn setup(&mut self, prompt: Vec<TokenId>) -> SequenceResult<InitPromptResult> {
+        let t0 = Instant::now();
+        let res = self.setup_inner(prompt);
+        seq_result(data(), "setup", t0, 0, res)
+    }
+
+    fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset> {
+        let t0 = Instant::now();
+        let res = self.do_mid_process(op);
+        seq_result(data(), "mid", t0, 0, res)
+    }
+
+    fn post_process(&mut self, arg: PostProcessArg) -> SequenceResult {
+        let t0 = Instant::now();
+        let res = self.do_post_process(arg);
+        seq_result(data(), "post", t0, 0, res)
+    }
+
+    fn run_main(&mut self) -> Result<()> {
+        bail_user!("native controllers don't support run_main")
+    }
+
+    fn take_sparse_masks(&mut self) -> Vec<(u32, Vec<TokenId>)> {
+        std::mem::take(&mut data().sparse_masks)
+    }
+
+    fn take_soft_masks(&mut self) -> Vec<u32> {
+        std::mem::take(&mut data().soft_masks)
+    }
+
+    fn after_fork(&mut self) -> Result<()> {
+        // don't interleave output with the parent
+        self.stdout = StdoutCapture::new()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Per-user resource policies (`--policy FILE`).
+//!
+//! The policy file defines named profiles, each overriding some of the limits given on the
+//! command line, and maps users to profiles:
+//!
+//! ```json
+//! {
+//!   "profiles": {
+//!     "trusted": { "max_step_ms": 100, "max_forks": 64 },
+//!     "anonymous": { "max_step_ms": 10, "max_forks": 4, "module_upload": false }
+//!   },
+//!   "users": { "alice": "trusted" },
+//!   "admin": "trusted",
+//!   "default": "anonymous"
+//! }
+//! ```
+//!
+//! A user is looked up in `users` first, then `admin` applies to admins, and `default`
+//! to everyone else (including admins, if `admin` is not set).
+//! Users without a profile get the command line limits.
+
+use crate::hostimpl::AiciLimits;
+use aicirt::{api::AuthInfo, HashMap};
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Name of the profile of users not covered by the policy.
+pub const CLI_PROFILE: &str = "cli";
+
+/// Limits set by a profile; the rest comes from the command line.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LimitProfile {
+    pub max_step_ms: Option<u64>,
+    pub max_post_ms: Option<u64>,
+    pub max_init_ms: Option<u64>,
+    pub max_timeout_steps: Option<usize>,
+    pub max_forks: Option<usize>,
+    pub max_memory_mb: Option<usize>,
+    pub module_upload: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub profiles: HashMap<String, LimitProfile>,
+    /// Maps user names to profile names.
+    #[serde(default)]
+    pub users: HashMap<String, String>,
+    /// Profile for admins not listed in `users`.
+    pub admin: Option<String>,
+    /// Profile for everyone else.
+    pub default: Option<String>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| anyhow!("can't read policy {}: {e}", path.display()))?;
+        let policy: Policy = serde_json::from_slice(&bytes)
+            .map_err(|e| anyhow!("invalid policy {}: {e}", path.display()))?;
+        let names = policy
+            .users
+            .values()
+            .chain(policy.admin.iter())
+            .chain(policy.default.iter());
+        for name in names {
+            if !policy.profiles.contains_key(name) {
+                bail!("policy {}: undefined profile {name:?}", path.display());
+            }
+        }
+        Ok(policy)
+    }
+
+    fn profile_name(&self, auth: &AuthInfo) -> Option<&String> {
+        self.users.get(&auth.user).or(if auth.is_admin {
+            self.admin.as_ref().or(self.default.as_ref())
+        } else {
+            self.default.as_ref()
+        })
+    }
+
+    /// Limits of the user: `base` with the overrides of the user's profile applied.
+    pub fn limits_for(&self, base: &AiciLimits, auth: &AuthInfo) -> AiciLimits {
+        let mut limits = base.clone();
+        if let Some(name) = self.profile_name(auth) {
+            let p = &self.profiles[name];
+            limits.profile = name.clone();
+            if let Some(v) = p.max_step_ms {
+                limits.max_step_ms = v;
+            }
+            if let Some(v) = p.max_post_ms {
+                limits.max_post_ms = v;
+            }
+            if let Some(v) = p.max_init_ms {
+                limits.max_init_ms = v;
+            }
+            if let Some(v) = p.max_timeout_steps {
+                limits.max_timeout_steps = v;
+            }
+            if let Some(v) = p.max_forks {
+                limits.max_forks = v;
+            }
+            if let Some(v) = p.max_memory_mb {
+                limits.max_memory_bytes = v * 1024 * 1024;
+            }
+            if let Some(v) = p.module_upload {
+                limits.module_upload = v;
+            }
+        }
+        limits
+    }
+}
+
+/// The limits that can differ between users, for error messages.
+pub fn describe(l: &AiciLimits) -> String {
+    format!(
+        "profile {}: step {}ms, post {}ms, init {}ms, {} timeout steps, {} forks, {}MB memory{}",
+        l.profile,
+        l.max_step_ms,
+        l.max_post_ms,
+        l.max_init_ms,
+        l.max_timeout_steps,
+        l.max_forks,
+        l.max_memory_bytes / (1024 * 1024),
+        if l.module_upload { "" } else { ", no uploads" },
+    )
+}
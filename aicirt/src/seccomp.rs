@@ -0,0 +1,234 @@
+//! Seccomp-bpf filter for seq and comms worker processes (Linux on x86_64 and aarch64).
+//!
+//! The filter is installed once the worker is set up, and only allows the syscalls
+//! needed to run the controller and talk to the main process over shared memory.
+//! Forks of the worker inherit it. Any other syscall raises SIGSYS, which logs the
+//! syscall number and terminates the worker. Disable with `--no-seccomp`.
+
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// The comms process only serves storage commands over shared memory.
+    Comms,
+    /// The seq worker runs the controller, and may fork itself (which needs a new memfd).
+    Seq,
+}
+
+/// Exit code of a worker killed for a disallowed syscall (as if killed by SIGSYS).
+pub const EXIT_CODE: i32 = 128 + 31;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod imp {
+    use super::{Profile, EXIT_CODE};
+    use anyhow::{anyhow, Result};
+    use libc::c_long;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // from linux/filter.h and linux/seccomp.h
+    const BPF_LD_W_ABS: u16 = 0x20; // BPF_LD | BPF_W | BPF_ABS
+    const BPF_JMP_JEQ_K: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+    const BPF_RET_K: u16 = 0x06; // BPF_RET | BPF_K
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    // offsets in struct seccomp_data
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+    // low half of args[0] (little endian)
+    const DATA_ARG0: u32 = 16;
+
+    const COMMON_SYSCALLS: &[c_long] = &[
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_futex,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_sched_yield,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_getrandom,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+
+    // fork creates a memfd for the channel to the child, and the native controllers
+    // re-create their stdout capture memfd in the child; nothing touches the file system
+    const SEQ_SYSCALLS: &[c_long] = &[
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        // glibc sets these up in the child of fork()
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_memfd_create,
+        libc::SYS_ftruncate,
+        libc::SYS_close,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_dup3,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_fork,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_dup2,
+    ];
+
+    // syscalls only allowed with the given first argument
+    const SEQ_SYSCALLS_ARG0: &[(c_long, u32)] = &[(libc::SYS_prctl, libc::PR_SET_NAME as u32)];
+
+    fn stmt(code: u16, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jeq(k: u32, jt: usize, jf: usize) -> libc::sock_filter {
+        libc::sock_filter {
+            code: BPF_JMP_JEQ_K,
+            jt: jt.try_into().unwrap(),
+            jf: jf.try_into().unwrap(),
+            k,
+        }
+    }
+
+    fn program(syscalls: &[c_long], with_arg0: &[(c_long, u32)]) -> Vec<libc::sock_filter> {
+        let mut prog = vec![
+            stmt(BPF_LD_W_ABS, DATA_ARCH),
+            jeq(AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD_W_ABS, DATA_NR),
+        ];
+        let trap = prog.len() + syscalls.len() + 3 * with_arg0.len();
+        let allow = trap + 1;
+        // jumps are relative to the next instruction
+        for nr in syscalls {
+            let pc = prog.len() + 1;
+            prog.push(jeq(*nr as u32, allow - pc, 0));
+        }
+        // once the argument is loaded, the syscall number is gone, so a mismatch traps
+        for (nr, arg0) in with_arg0 {
+            prog.push(jeq(*nr as u32, 0, 2));
+            prog.push(stmt(BPF_LD_W_ABS, DATA_ARG0));
+            let pc = prog.len() + 1;
+            prog.push(jeq(*arg0, allow - pc, trap - pc));
+        }
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_TRAP));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        prog
+    }
+
+    // only async-signal-safe calls here
+    extern "C" fn on_sigsys(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+        // si_syscall follows si_call_addr in the union after the three int header fields
+        let nr = unsafe { *((info as *const u8).add(24) as *const i32) };
+        let mut buf = *b"aicirt worker: seccomp: disallowed syscall          \n";
+        let mut pos = buf.len() - 2;
+        let mut v = nr as u32;
+        loop {
+            buf[pos] = b'0' + (v % 10) as u8;
+            v /= 10;
+            if v == 0 {
+                break;
+            }
+            pos -= 1;
+        }
+        unsafe {
+            libc::write(libc::STDERR_FILENO, buf.as_ptr() as *const libc::c_void, buf.len());
+            libc::_exit(EXIT_CODE);
+        }
+    }
+
+    pub fn check() -> Result<()> {
+        if unsafe { libc::prctl(libc::PR_GET_SECCOMP, 0, 0, 0, 0) } < 0 {
+            return Err(anyhow!(
+                "seccomp not available: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn install(profile: Profile) -> Result<()> {
+        let mut syscalls = COMMON_SYSCALLS.to_vec();
+        let mut with_arg0 = vec![];
+        if profile == Profile::Seq {
+            syscalls.extend_from_slice(SEQ_SYSCALLS);
+            with_arg0.extend_from_slice(SEQ_SYSCALLS_ARG0);
+        }
+        let mut prog = program(&syscalls, &with_arg0);
+        let fprog = libc::sock_fprog {
+            len: prog.len() as u16,
+            filter: prog.as_mut_ptr(),
+        };
+
+        unsafe {
+            let mut act: libc::sigaction = std::mem::zeroed();
+            act.sa_sigaction = on_sigsys as usize;
+            act.sa_flags = libc::SA_SIGINFO;
+            if libc::sigaction(libc::SIGSYS, &act, std::ptr::null_mut()) < 0 {
+                return Err(anyhow!("sigaction: {}", std::io::Error::last_os_error()));
+            }
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(anyhow!("no_new_privs: {}", std::io::Error::last_os_error()));
+            }
+            if libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &fprog as *const libc::sock_fprog,
+            ) < 0
+            {
+                return Err(anyhow!("seccomp: {}", std::io::Error::last_os_error()));
+            }
+        }
+
+        log::debug!(
+            "seccomp filter installed ({profile:?}, {} syscalls)",
+            syscalls.len() + with_arg0.len()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod imp {
+    use super::Profile;
+    use anyhow::{bail, Result};
+
+    pub fn check() -> Result<()> {
+        bail!("seccomp is only supported on Linux (x86_64 and aarch64)")
+    }
+
+    pub fn install(_profile: Profile) -> Result<()> {
+        check()
+    }
+}
+
+/// Fails if the filter can't be installed on this system.
+pub fn check() -> Result<()> {
+    imp::check()
+}
+
+/// Install the filter in the current process; it can't be removed.
+pub fn install(profile: Profile) -> Result<()> {
+    imp::install(profile)
+}
@@ -8,6 +8,8 @@ use std::{
 pub struct Shm {
     addr: *mut u8,
     pub size: usize,
+    /// kept open for memfd-backed segments, so other processes can map them
+    fd: i32,
 }
 
 unsafe impl Send for Shm {}
@@ -27,7 +29,64 @@ impl Shm {
         Self::from_fd(-1, size)
     }
 
+    /// Shared memory without a name in /dev/shm; other processes can map it with
+    /// `Shm::open("/proc/<pid>/fd/<fd>")` while this process keeps it.
+    /// Doesn't touch the file system, so it works under the seccomp filter.
+    #[cfg(target_os = "linux")]
+    pub fn memfd(size: usize) -> Result<Self> {
+        ensure!(size > 1024);
+        log::trace!("memfd_create: size={}k", size / 1024);
+        let name = b"aici-shm\0".as_ptr() as *const libc::c_char;
+        let fd = unsafe { libc::memfd_create(name, libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if unsafe { libc::ftruncate(fd, size.try_into().unwrap()) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err.into());
+        }
+        match Self::map(fd, size) {
+            Ok(mut shm) => {
+                shm.fd = fd;
+                Ok(shm)
+            }
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Map a file shared, in particular another process's memfd.
+    pub fn open(path: &str, size: usize) -> Result<Self> {
+        log::trace!("open shm: {} size={}k", path, size / 1024);
+        let path = CString::new(path).unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Self::from_fd(fd, size)
+    }
+
+    /// The memfd backing the segment, if any.
+    pub fn fd(&self) -> Option<i32> {
+        if self.fd >= 0 {
+            Some(self.fd)
+        } else {
+            None
+        }
+    }
+
     fn from_fd(fd: i32, size: usize) -> Result<Self> {
+        let r = Self::map(fd, size);
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+        r
+    }
+
+    fn map(fd: i32, size: usize) -> Result<Self> {
         let mut flag = libc::MAP_SHARED;
         if fd == -1 {
             flag |= libc::MAP_ANONYMOUS;
@@ -42,16 +101,14 @@ impl Shm {
                 0,
             )
         };
-        let err = io::Error::last_os_error();
-        unsafe { libc::close(fd) };
-
         if addr == libc::MAP_FAILED {
-            return Err(err.into());
+            return Err(io::Error::last_os_error().into());
         }
 
         Ok(Self {
             addr: addr as *mut u8,
             size,
+            fd: -1,
         })
     }
 
@@ -148,6 +205,9 @@ impl Drop for Shm {
     fn drop(&mut self) {
         unsafe {
             libc::munmap(self.addr as *mut libc::c_void, self.size);
+            if self.fd >= 0 {
+                libc::close(self.fd);
+            }
         }
     }
 }
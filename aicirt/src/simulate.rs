@@ -0,0 +1,330 @@
+//! Running a controller end-to-end without an LLM (`--simulate`).
+//!
+//! Tokens are picked from the ones allowed by the controller's masks, and splices,
+//! backtracking and forks are applied the same way the LLM engine in rllm does.
+
+use crate::{ModuleRegistry, Stepper};
+use aici_abi::{rng::Rng, Branch, FinishReason, InitPromptResult, RequestInfo, Splice, TokenId};
+use aicirt::{
+    api::{
+        AiciMidOp, AiciMidProcessReq, AiciMidProcessResp, AuthInfo, BiasType, InstantiateReq,
+        ModuleInstId, SequenceResult,
+    },
+    HashMap,
+};
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+pub enum Sampling {
+    /// Pick uniformly at random among allowed tokens.
+    Random(Rng),
+    /// Always pick the first allowed token.
+    First,
+    /// Take tokens from the list; the n-th sampled token of every fork is the n-th in the list.
+    Script(Vec<TokenId>),
+}
+
+impl Sampling {
+    pub fn from_cli(name: &str, seed: usize, tokens: &Option<String>) -> Result<Self> {
+        match name {
+            "random" => Ok(Sampling::Random(Rng::new(seed))),
+            "first" => Ok(Sampling::First),
+            "script" => match tokens {
+                Some(path) => {
+                    let tokens = serde_json::from_slice(&std::fs::read(path)?)?;
+                    Ok(Sampling::Script(tokens))
+                }
+                None => bail!("--sim-sampling=script requires --sim-tokens"),
+            },
+            _ => bail!("invalid sampling {name:?}; expecting random, first, or script"),
+        }
+    }
+}
+
+pub struct SimOptions {
+    pub module_id: String,
+    pub module_arg: Value,
+    pub prompt: String,
+    pub max_tokens: usize,
+    pub sampling: Sampling,
+}
+
+struct SimSeq {
+    id: ModuleInstId,
+    /// Index of the fork, as printed.
+    index: usize,
+    /// Prompt and generated tokens.
+    tokens: Vec<TokenId>,
+    num_sampled: usize,
+    op: AiciMidOp,
+}
+
+fn defl_mid_op(id: ModuleInstId) -> AiciMidOp {
+    AiciMidOp {
+        id,
+        sampled: None,
+        logprobs: None,
+        clone_id: None,
+        clone_idx: None,
+        req_id: None,
+        backtrack: 0,
+        tokens: vec![],
+        storage: vec![],
+    }
+}
+
+struct Simulator<'a> {
+    stepper: &'a mut Stepper,
+    opts: SimOptions,
+    prompt_len: usize,
+    next_id: ModuleInstId,
+    next_index: usize,
+    // index of every sequence, including finished ones
+    indices: HashMap<ModuleInstId, usize>,
+    finished: Vec<(usize, Vec<TokenId>, FinishReason)>,
+    freed: Vec<ModuleInstId>,
+    finish_reasons: HashMap<ModuleInstId, FinishReason>,
+}
+
+fn print_logs<T>(index: usize, res: &SequenceResult<T>) {
+    for line in res.logs.lines() {
+        println!("[{index}] {line}");
+    }
+}
+
+impl Simulator<'_> {
+    fn new_seq(&mut self, tokens: Vec<TokenId>) -> SimSeq {
+        let id = self.next_id;
+        let index = self.next_index;
+        self.next_id += 1;
+        self.next_index += 1;
+        self.indices.insert(id, index);
+        SimSeq {
+            id,
+            index,
+            tokens,
+            num_sampled: 0,
+            op: defl_mid_op(id),
+        }
+    }
+
+    fn finish(&mut self, seq: SimSeq, reason: FinishReason) {
+        self.freed.push(seq.id);
+        self.finish_reasons.insert(seq.id, reason);
+        self.finished.push((seq.index, seq.tokens, reason));
+    }
+
+    fn pick(&mut self, seq: &SimSeq, allowed: &[TokenId]) -> Result<TokenId, String> {
+        if allowed.is_empty() {
+            return Err("no tokens allowed".to_string());
+        }
+        match &mut self.opts.sampling {
+            Sampling::Random(rng) => Ok(allowed[rng.gen_up_to(allowed.len() - 1)]),
+            Sampling::First => Ok(allowed[0]),
+            Sampling::Script(tokens) => match tokens.get(seq.num_sampled) {
+                Some(t) if allowed.contains(t) => Ok(*t),
+                Some(t) => Err(format!("scripted token {t} not allowed")),
+                None => Err("end of script".to_string()),
+            },
+        }
+    }
+
+    fn text(&self, tokens: &[TokenId]) -> String {
+        let mut bytes = Vec::new();
+        for t in tokens {
+            if let Some(b) = self.stepper.token_bytes.get(*t as usize) {
+                bytes.extend_from_slice(b);
+            }
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+
+    /// Sample the next token(s) for the sequence; mirrors RllmEngine::sample().
+    fn sample(
+        &mut self,
+        mut seq: SimSeq,
+        b: &Branch<usize>,
+        resp: &AiciMidProcessResp,
+        bias_type: &BiasType,
+    ) -> Option<SimSeq> {
+        let mut sampled = None;
+        let splice = match b.sample_mask {
+            None => {
+                assert!(b.splices.len() == 1);
+                let s = &b.splices[0];
+                assert!(s.when_sampled.is_empty());
+                s.clone()
+            }
+            Some(mask_idx) => {
+                let allowed = match resp.sparse_masks.get(&mask_idx) {
+                    Some(tokens) => tokens.clone(),
+                    None => {
+                        let off = resp.first_mask_byte_offset + mask_idx * resp.mask_num_bytes;
+                        let vocab_size = self.stepper.globals.tokrx_info.vocab_size as usize;
+                        bias_type.allowed_tokens(&self.stepper.shm, off, vocab_size)
+                    }
+                };
+                let next_token = match self.pick(&seq, &allowed) {
+                    Ok(t) => t,
+                    Err(msg) => {
+                        println!("[{}] {msg}", seq.index);
+                        self.finish(seq, FinishReason::Failed);
+                        return None;
+                    }
+                };
+                sampled = Some(next_token);
+                seq.num_sampled += 1;
+                match b.splices.iter().find(|s| s.when_sampled.contains(&next_token)) {
+                    Some(s) => s.clone(),
+                    None => Splice {
+                        backtrack: 0,
+                        ff_tokens: vec![next_token],
+                        when_sampled: vec![],
+                    },
+                }
+            }
+        };
+
+        let backtrack = std::cmp::min(splice.backtrack as usize, seq.tokens.len());
+        seq.tokens.truncate(seq.tokens.len() - backtrack);
+        seq.tokens.extend_from_slice(&splice.ff_tokens);
+
+        let has_eos = splice
+            .ff_tokens
+            .contains(&self.stepper.globals.tokrx_info.tok_eos);
+        seq.op.tokens = splice.ff_tokens;
+        seq.op.backtrack = splice.backtrack;
+        seq.op.sampled = sampled;
+
+        if has_eos {
+            self.finish(seq, FinishReason::Eos);
+            None
+        } else if seq.tokens.len().saturating_sub(self.prompt_len) >= self.opts.max_tokens {
+            self.finish(seq, FinishReason::MaxTokens);
+            None
+        } else {
+            Some(seq)
+        }
+    }
+
+    fn step(&mut self, seqs: Vec<SimSeq>) -> Result<Vec<SimSeq>> {
+        let resp = self.stepper.aici_mid_process(AiciMidProcessReq {
+            ops: seqs.iter().map(|s| s.op.clone()).collect(),
+            freed: std::mem::take(&mut self.freed),
+            finish_reasons: std::mem::take(&mut self.finish_reasons),
+        })?;
+
+        for (id, res) in resp.finished.iter() {
+            print_logs(self.indices[id], res);
+        }
+
+        let bias_type = BiasType::from_str(&resp.dtype)?;
+        let mut next = Vec::new();
+        for mut seq in seqs {
+            let res = match resp.seqs.get(&seq.id) {
+                Some(r) => r,
+                None => {
+                    println!("[{}] no result", seq.index);
+                    self.finish(seq, FinishReason::Failed);
+                    continue;
+                }
+            };
+            print_logs(seq.index, res);
+            if !res.error.is_empty() {
+                self.finish(seq, FinishReason::Failed);
+                continue;
+            }
+            let branches = res
+                .result
+                .as_ref()
+                .map(|r| r.branches.clone())
+                .unwrap_or_default();
+            if branches.is_empty() {
+                self.finish(seq, FinishReason::Stopped);
+                continue;
+            }
+
+            let mut group = Vec::new();
+            for (idx, b) in branches.iter().enumerate() {
+                if idx == 0 {
+                    continue;
+                }
+                let mut copy = self.new_seq(seq.tokens.clone());
+                copy.num_sampled = seq.num_sampled;
+                copy.op.clone_id = Some(seq.id);
+                copy.op.clone_idx = Some(idx);
+                println!("[{}] forked from [{}]", copy.index, seq.index);
+                group.push((copy, b));
+            }
+            seq.op = defl_mid_op(seq.id);
+            group.insert(0, (seq, &branches[0]));
+
+            for (seq, b) in group {
+                if let Some(seq) = self.sample(seq, b, &resp, &bias_type) {
+                    next.push(seq);
+                }
+            }
+        }
+
+        Ok(next)
+    }
+}
+
+/// Run the module on the prompt until all forks finish, and print the generated text.
+pub fn simulate(reg: &mut ModuleRegistry, stepper: &mut Stepper, opts: SimOptions) -> Result<()> {
+    let req_id = "simulate".to_string();
+    let res = reg.instantiate(
+        InstantiateReq {
+            req_id: req_id.clone(),
+            prompt: json!(opts.prompt),
+            module_id: opts.module_id.clone(),
+            module_arg: opts.module_arg.clone(),
+            request_info: RequestInfo {
+                max_tokens: Some(opts.max_tokens),
+                ..Default::default()
+            },
+        },
+        AuthInfo::admin_user(),
+    )?;
+    let res: SequenceResult<InitPromptResult> = serde_json::from_value(res)?;
+    print_logs(0, &res);
+    if !res.error.is_empty() {
+        bail!("init_prompt failed");
+    }
+    let prompt = res.result.map(|r| r.prompt).unwrap_or_default();
+
+    let mut sim = Simulator {
+        stepper,
+        opts,
+        prompt_len: prompt.len(),
+        // 0 is not a valid client id for the shm allocator
+        next_id: 1,
+        next_index: 0,
+        indices: HashMap::default(),
+        finished: Vec::new(),
+        freed: Vec::new(),
+        finish_reasons: HashMap::default(),
+    };
+    println!("prompt: {} tokens, {:?}", prompt.len(), sim.text(&prompt));
+
+    let mut seq = sim.new_seq(prompt);
+    seq.op.req_id = Some(req_id);
+    let mut seqs = vec![seq];
+    // the last step only runs post_process() of finished sequences
+    while !seqs.is_empty() || !sim.freed.is_empty() {
+        seqs = sim.step(seqs)?;
+    }
+
+    let mut finished = std::mem::take(&mut sim.finished);
+    finished.sort_by_key(|(index, _, _)| *index);
+    for (index, tokens, reason) in finished {
+        let generated = &tokens[std::cmp::min(sim.prompt_len, tokens.len())..];
+        println!(
+            "[{index}] finished ({reason:?}, {} tokens): {:?}",
+            generated.len(),
+            sim.text(generated)
+        );
+    }
+
+    Ok(())
+}
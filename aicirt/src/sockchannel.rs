@@ -0,0 +1,199 @@
+//! Socket transport for the aicirt protocol, as an alternative to shared memory channels.
+//!
+//! Addresses are either `unix:PATH` or `tcp:HOST:PORT`.
+//! Every message is a 4-byte little-endian length followed by that many bytes of JSON.
+//! The first message of a connection is the shared secret of the server instead,
+//! which answers with [`AUTH_OK`], or closes the connection.
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Response to a successful handshake.
+pub const AUTH_OK: &[u8] = b"ok";
+// limits for reading from clients that are not authenticated yet
+const AUTH_MAX_SIZE: usize = 4096;
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum SockListener {
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener),
+}
+
+pub enum SockStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+/// Address of the side channel, given address of the main channel:
+/// `suff` is appended to the path of unix sockets, and TCP uses the next port.
+pub fn side_addr(addr: &str, suff: &str) -> Result<String> {
+    if suff.is_empty() {
+        return Ok(addr.to_string());
+    }
+    if let Some(path) = addr.strip_prefix("unix:") {
+        Ok(format!("unix:{path}{suff}"))
+    } else if let Some(hostport) = addr.strip_prefix("tcp:") {
+        let (host, port) = split_port(hostport)?;
+        Ok(format!("tcp:{host}:{}", port + 1))
+    } else {
+        bail!("invalid socket address {addr:?}; expecting unix:PATH or tcp:HOST:PORT")
+    }
+}
+
+// constant-time comparison
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn split_port(hostport: &str) -> Result<(&str, u16)> {
+    let (host, port) = hostport
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("missing port in {hostport:?}"))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|e| anyhow!("invalid port in {hostport:?}: {e}"))?;
+    Ok((host, port))
+}
+
+impl SockListener {
+    pub fn bind(addr: &str) -> Result<Self> {
+        log::info!("listening on {addr}");
+        if let Some(path) = addr.strip_prefix("unix:") {
+            // stale socket from a previous run
+            let _ = std::fs::remove_file(path);
+            Ok(SockListener::Unix(UnixListener::bind(path)?, PathBuf::from(path)))
+        } else if let Some(hostport) = addr.strip_prefix("tcp:") {
+            split_port(hostport)?;
+            Ok(SockListener::Tcp(TcpListener::bind(hostport)?))
+        } else {
+            bail!("invalid socket address {addr:?}; expecting unix:PATH or tcp:HOST:PORT")
+        }
+    }
+
+    pub fn accept(&self) -> Result<SockStream> {
+        match self {
+            SockListener::Unix(l, _) => Ok(SockStream::Unix(l.accept()?.0)),
+            SockListener::Tcp(l) => {
+                let (s, peer) = l.accept()?;
+                log::info!("connection from {peer}");
+                s.set_nodelay(true)?;
+                Ok(SockStream::Tcp(s))
+            }
+        }
+    }
+}
+
+impl Drop for SockListener {
+    fn drop(&mut self) {
+        if let SockListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl SockStream {
+    pub fn connect(addr: &str) -> Result<Self> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            Ok(SockStream::Unix(UnixStream::connect(path)?))
+        } else if let Some(hostport) = addr.strip_prefix("tcp:") {
+            let s = TcpStream::connect(hostport)?;
+            s.set_nodelay(true)?;
+            Ok(SockStream::Tcp(s))
+        } else {
+            bail!("invalid socket address {addr:?}; expecting unix:PATH or tcp:HOST:PORT")
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            SockStream::Unix(s) => s.set_read_timeout(timeout)?,
+            SockStream::Tcp(s) => s.set_read_timeout(timeout)?,
+        }
+        Ok(())
+    }
+
+    /// Client side of the handshake: send the shared secret, and wait until it's accepted.
+    pub fn client_auth(&mut self, token: &str) -> Result<()> {
+        self.send_msg(token.as_bytes())?;
+        match self.recv_msg(AUTH_MAX_SIZE) {
+            Ok(resp) if resp == AUTH_OK => Ok(()),
+            Ok(resp) => bail!("authentication failed: {}", String::from_utf8_lossy(&resp)),
+            Err(e) => bail!("authentication failed: {e}"),
+        }
+    }
+
+    /// Server side of the handshake: the first message has to be `token`;
+    /// on error, the connection should be dropped.
+    pub fn server_auth(&mut self, token: &str) -> Result<()> {
+        // an idle client shouldn't block the channel
+        self.set_read_timeout(Some(AUTH_TIMEOUT))?;
+        let msg = self.recv_msg(AUTH_MAX_SIZE)?;
+        if !same_secret(&msg, token.as_bytes()) {
+            let _ = self.send_msg(b"invalid token");
+            bail!("invalid token");
+        }
+        self.set_read_timeout(None)?;
+        self.send_msg(AUTH_OK)
+    }
+
+    pub fn try_clone(&self) -> Result<Self> {
+        match self {
+            SockStream::Unix(s) => Ok(SockStream::Unix(s.try_clone()?)),
+            SockStream::Tcp(s) => Ok(SockStream::Tcp(s.try_clone()?)),
+        }
+    }
+
+    pub fn send_msg(&mut self, msg: &[u8]) -> Result<()> {
+        let len: u32 = msg.len().try_into()?;
+        let mut buf = Vec::with_capacity(4 + msg.len());
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(msg);
+        self.write_all(&buf)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Read the next message; fails on EOF or if the message is over `max_size` bytes.
+    pub fn recv_msg(&mut self, max_size: usize) -> Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        self.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > max_size {
+            bail!("message too large: {len} bytes (max {max_size})");
+        }
+        let mut msg = vec![0u8; len];
+        self.read_exact(&mut msg)?;
+        Ok(msg)
+    }
+}
+
+impl Read for SockStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            SockStream::Unix(s) => s.read(buf),
+            SockStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for SockStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            SockStream::Unix(s) => s.write(buf),
+            SockStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SockStream::Unix(s) => s.flush(),
+            SockStream::Tcp(s) => s.flush(),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Recording of requests (`--record DIR`) and replaying them without the LLM (`--replay FILE`).
+//!
+//! Each request is recorded into its own JSON-lines file, with the `InstantiateReq`,
+//! and for every step the `AiciMidOp`s of the request's sequences and their results.
+//! Masks are recorded as digests of their contents, since their offsets in the shared
+//! memory depend on other requests; sparse masks are recorded as their token lists.
+
+use crate::{ModuleRegistry, Stepper};
+use aici_abi::{FinishReason, ProcessResultOffset, TokenId};
+use aicirt::{
+    api::{
+        AiciMidOp, AiciMidProcessReq, AuthInfo, BiasType, InstantiateReq, ModuleInstId,
+        SequenceResult,
+    },
+    shm::ShmAllocator,
+    HashMap,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// One line of a trace file.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceEntry {
+    Instantiate {
+        req: InstantiateReq,
+        /// SequenceResult<InitPromptResult>
+        result: Value,
+    },
+    MidProcess(StepEntry),
+}
+
+/// Inputs and results of a mid_process step of one request.
+#[derive(Serialize, Deserialize, Default)]
+pub struct StepEntry {
+    ops: Vec<AiciMidOp>,
+    freed: Vec<ModuleInstId>,
+    #[serde(default)]
+    finish_reasons: HashMap<ModuleInstId, FinishReason>,
+    /// SequenceResult<Vec<Branch<String>>>, where masks are replaced by digests
+    seqs: HashMap<ModuleInstId, Value>,
+    /// Results of post_process()
+    #[serde(default)]
+    finished: HashMap<ModuleInstId, Value>,
+}
+
+/// Inputs of a mid_process step, kept until the results are known.
+pub struct StepInputs {
+    req_ids: HashMap<ModuleInstId, String>,
+    ops: Vec<AiciMidOp>,
+    freed: Vec<ModuleInstId>,
+    finish_reasons: HashMap<ModuleInstId, FinishReason>,
+}
+
+impl StepInputs {
+    /// `req_id_of` should map ids of sequences in `req` to request ids.
+    pub fn new(
+        req: &AiciMidProcessReq,
+        req_id_of: impl Fn(ModuleInstId) -> Option<String>,
+    ) -> Self {
+        let mut req_ids = HashMap::default();
+        for op in req.ops.iter() {
+            let req_id = match &op.req_id {
+                Some(r) => Some(r.clone()),
+                None => req_id_of(op.clone_id.unwrap_or(op.id)),
+            };
+            if let Some(r) = req_id {
+                req_ids.insert(op.id, r);
+            }
+        }
+        for id in req.freed.iter() {
+            if let Some(r) = req_id_of(*id) {
+                req_ids.insert(*id, r);
+            }
+        }
+        StepInputs {
+            req_ids,
+            ops: req.ops.clone(),
+            freed: req.freed.clone(),
+            finish_reasons: req.finish_reasons.clone(),
+        }
+    }
+}
+
+/// Location of masks in the shared memory, as reported in AiciMidProcessResp.
+pub struct MaskLayout<'a> {
+    pub shm: &'a ShmAllocator,
+    pub first_mask_byte_offset: usize,
+    pub mask_num_bytes: usize,
+    pub vocab_size: usize,
+    /// Format of the masks in this step.
+    pub bias_type: BiasType,
+    pub sparse_masks: &'a HashMap<usize, Vec<TokenId>>,
+}
+
+impl MaskLayout<'_> {
+    fn digest(&self, idx: usize) -> String {
+        if let Some(tokens) = self.sparse_masks.get(&idx) {
+            return format!("allow:{tokens:?}");
+        }
+        // only hash the vocabulary part, the padding is not written to
+        let num_bytes = self.bias_type.size_in_bytes(self.vocab_size);
+        let off = self.first_mask_byte_offset + idx * self.mask_num_bytes;
+        let bytes: &[u8] = self.shm.slice_at_byte_offset(off, num_bytes);
+        let mut hasher = <Sha256 as Digest>::new();
+        hasher.update(bytes);
+        hex::encode(&hasher.finalize()[0..12])
+    }
+
+    /// Replace mask indices with digests and strip timing information.
+    pub fn trace_result(&self, res: &SequenceResult<ProcessResultOffset>) -> Value {
+        let branches = res.result.as_ref().map(|r| {
+            r.branches
+                .iter()
+                .map(|b| b.map_mask(|idx| self.digest(*idx)))
+                .collect::<Vec<_>>()
+        });
+        without_timing(serde_json::to_value(res.clone_with(branches)).unwrap())
+    }
+}
+
+fn without_timing(mut v: Value) -> Value {
+    if let Some(obj) = v.as_object_mut() {
+        obj.remove("micros");
+        obj.remove("fuel");
+    }
+    v
+}
+
+pub struct TraceRecorder {
+    dir: PathBuf,
+    // serializes writes from the module registry and the stepper
+    lock: Mutex<()>,
+}
+
+impl TraceRecorder {
+    pub fn new(dir: &str) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(TraceRecorder {
+            dir: PathBuf::from(dir),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, req_id: &str) -> PathBuf {
+        let name = req_id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+        self.dir.join(format!("{name}.jsonl"))
+    }
+
+    fn record(&self, req_id: &str, entry: &TraceEntry) {
+        let mut line = serde_json::to_vec(entry).unwrap();
+        line.push(b'\n');
+        let _lck = self.lock.lock().unwrap();
+        let r = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(req_id))
+            .and_then(|mut f| f.write_all(&line));
+        if let Err(e) = r {
+            log::warn!("can't record trace for {req_id}: {e}");
+        }
+    }
+
+    pub fn record_instantiate(&self, req: &InstantiateReq, result: &Value) {
+        let entry = TraceEntry::Instantiate {
+            req: req.clone(),
+            result: without_timing(result.clone()),
+        };
+        self.record(&req.req_id, &entry);
+    }
+
+    /// Record a step, split by request; this has to run before the masks are freed.
+    pub fn record_step(
+        &self,
+        inputs: StepInputs,
+        results: &HashMap<ModuleInstId, SequenceResult<ProcessResultOffset>>,
+        finished: &HashMap<ModuleInstId, SequenceResult>,
+        masks: &MaskLayout,
+    ) {
+        let mut entries: HashMap<String, StepEntry> = HashMap::default();
+        let req_ids = inputs.req_ids;
+        fn entry<'a>(
+            entries: &'a mut HashMap<String, StepEntry>,
+            req_ids: &HashMap<ModuleInstId, String>,
+            id: ModuleInstId,
+        ) -> Option<&'a mut StepEntry> {
+            let req_id = req_ids.get(&id)?;
+            Some(entries.entry(req_id.clone()).or_default())
+        }
+
+        for op in inputs.ops {
+            let id = op.id;
+            if let Some(e) = entry(&mut entries, &req_ids, id) {
+                e.ops.push(op);
+                if let Some(res) = results.get(&id) {
+                    e.seqs.insert(id, masks.trace_result(res));
+                }
+            }
+        }
+        for id in inputs.freed {
+            if let Some(e) = entry(&mut entries, &req_ids, id) {
+                e.freed.push(id);
+                if let Some(r) = inputs.finish_reasons.get(&id) {
+                    e.finish_reasons.insert(id, *r);
+                }
+            }
+        }
+        for (id, res) in finished.iter() {
+            if let Some(e) = entry(&mut entries, &req_ids, *id) {
+                e.finished
+                    .insert(*id, without_timing(serde_json::to_value(res).unwrap()));
+            }
+        }
+
+        for (req_id, e) in entries {
+            self.record(&req_id, &TraceEntry::MidProcess(e));
+        }
+    }
+}
+
+fn report_diff(lineno: usize, what: &str, recorded: &Value, replayed: &Value) -> bool {
+    if recorded == replayed {
+        return false;
+    }
+    println!("line {lineno}: {what} differs");
+    println!("  recorded: {recorded}");
+    println!("  replayed: {replayed}");
+    true
+}
+
+fn diff_results(
+    lineno: usize,
+    what: &str,
+    recorded: &HashMap<ModuleInstId, Value>,
+    replayed: &HashMap<ModuleInstId, Value>,
+) -> usize {
+    let mut ids = recorded.keys().chain(replayed.keys()).collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+    let missing = Value::Null;
+    ids.into_iter()
+        .filter(|id| {
+            report_diff(
+                lineno,
+                &format!("{what} of seq {id}"),
+                recorded.get(id).unwrap_or(&missing),
+                replayed.get(id).unwrap_or(&missing),
+            )
+        })
+        .count()
+}
+
+/// Re-execute a recorded request and compare results (including masks) with the recording.
+/// Returns the number of differences found.
+pub fn replay(path: &Path, reg: &mut ModuleRegistry, stepper: &mut Stepper) -> Result<usize> {
+    let data = fs::read_to_string(path)?;
+    let mut num_diffs = 0;
+    for (idx, line) in data.lines().enumerate() {
+        let lineno = idx + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: TraceEntry = serde_json::from_str(line)
+            .map_err(|e| anyhow!("{}:{lineno}: {e}", path.display()))?;
+        match entry {
+            TraceEntry::Instantiate { req, result } => {
+                let res = without_timing(reg.instantiate(req, AuthInfo::admin_user())?);
+                if report_diff(lineno, "instantiate", &result, &res) {
+                    num_diffs += 1;
+                }
+            }
+            TraceEntry::MidProcess(e) => {
+                let resp = stepper.aici_mid_process(AiciMidProcessReq {
+                    ops: e.ops,
+                    freed: e.freed,
+                    finish_reasons: e.finish_reasons,
+                })?;
+                // masks are still in the shm, as there is nothing else running
+                let masks = MaskLayout {
+                    shm: &stepper.shm,
+                    first_mask_byte_offset: resp.first_mask_byte_offset,
+                    mask_num_bytes: resp.mask_num_bytes,
+                    vocab_size: stepper.globals.tokrx_info.vocab_size as usize,
+                    bias_type: BiasType::from_str(&resp.dtype)?,
+                    sparse_masks: &resp.sparse_masks,
+                };
+                let replayed = resp
+                    .seqs
+                    .iter()
+                    .map(|(id, r)| (*id, masks.trace_result(r)))
+                    .collect();
+                num_diffs += diff_results(lineno, "mid_process", &e.seqs, &replayed);
+                let replayed = resp
+                    .finished
+                    .iter()
+                    .map(|(id, r)| (*id, without_timing(serde_json::to_value(r).unwrap())))
+                    .collect();
+                num_diffs += diff_results(lineno, "post_process", &e.finished, &replayed);
+            }
+        }
+    }
+    Ok(num_diffs)
+}
@@ -1,19 +1,22 @@
 use crate::{
     api::ModuleInstId,
     hostimpl::AiciLimits,
-    moduleinstance::{ModuleInstance, WasmContext},
+    moduleinstance::{Controller, ModuleInstance, WasmContext},
+    native::NativeInstance,
     setup_bg_worker_pool,
     shm::Shm,
     InstantiateReq, UserError,
 };
 use aici_abi::{
-    InitPromptResult, MidProcessArg, ProcessResultOffset, StorageCmd, StorageResp, TokenId,
+    InitPromptResult, MidProcessArg, PostProcessArg, ProcessResultOffset, RequestInfo, StorageCmd,
+    StorageResp, TokenId,
 };
 use aicirt::{
     api::SequenceResult,
     futexshm::{TypedClient, TypedClientHandle, TypedServer},
+    seccomp::{self, Profile},
     set_max_priority,
-    shm::{ShmAllocator, Unlink},
+    shm::ShmAllocator,
     user_error,
     variables::Variables,
 };
@@ -21,10 +24,14 @@ use anyhow::{anyhow, Result};
 use libc::pid_t;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
     fmt::Debug,
     path::PathBuf,
     rc::Rc,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
@@ -34,11 +41,14 @@ const QUICK_OP_RETRY_MS: u64 = 100;
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GroupCmd {
     StorageCmd { cmd: StorageCmd },
+    /// Sent when a (possibly pooled) worker is handed out to a request.
+    SetLimits { limits: AiciLimits },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GroupResp {
     StorageResp { resp: StorageResp },
+    Ok {},
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -90,13 +100,21 @@ where
     Cmd: for<'d> Deserialize<'d> + Serialize,
     Resp: for<'d> Deserialize<'d> + Serialize,
 {
+    // the main process maps it from /proc/<child>/fd/; this needs no file system access,
+    // so seq workers can fork under the seccomp filter
+    #[cfg(target_os = "linux")]
+    let shm = Shm::memfd(limits.ipc_shm_bytes)?;
+
+    #[cfg(not(target_os = "linux"))]
     let shm_name = format!(
         "/aici-{}",
         uuid::Uuid::new_v4().to_string().replace('-', "")
     );
     // 31 is max length for shm name on macos
+    #[cfg(not(target_os = "linux"))]
     let shm_name = shm_name[0..31].to_string();
-    let shm = Shm::new(&shm_name, limits.ipc_shm_bytes, Unlink::Pre)?;
+    #[cfg(not(target_os = "linux"))]
+    let shm = Shm::new(&shm_name, limits.ipc_shm_bytes, aicirt::shm::Unlink::Pre)?;
 
     let pid = unsafe { libc::fork() };
 
@@ -108,9 +126,12 @@ where
         let server = TypedServer::<Cmd, Resp>::new(shm);
         Ok(ForkResult::Child { server })
     } else {
+        // the child keeps its copy of the memfd open as long as it serves the channel
+        #[cfg(target_os = "linux")]
+        let shm_name = format!("/proc/{}/fd/{}", pid, shm.fd().unwrap());
         // we drop shm, so it's unmapped
         drop(shm);
-        // cmd.to_client() will unlink the shm - thus we need it constructed before
+        // for named shm, cmd.to_client() will unlink it - thus we need it constructed before
         // so there is no race between creation in the child and unlinking
         let cmd = TypedClientHandle::<Cmd, Resp>::new(shm_name, limits.ipc_shm_bytes);
         Ok(ForkResult::Parent {
@@ -133,6 +154,7 @@ struct ForkerCmd {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RtMidProcessArg {
     pub op: MidProcessArg,
+    pub storage: Vec<StorageCmd>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -142,11 +164,19 @@ struct ForkerResp(WireSeqHandle);
 enum SeqCmd {
     GetCommsPid {},
     Instantiate {
+        /// pooled workers only learn the request they serve here
+        req_id: String,
         module_path: PathBuf,
         module_id: String,
         module_arg: String,
+        /// module_path is a shared library, not a compiled wasm module
+        native: bool,
+        // JSON-encoded RequestInfo (bincode can't handle serde_json::Value)
+        request_info: String,
         prompt_str: Option<String>,
         prompt_toks: Option<Vec<TokenId>>,
+        /// limits of the user who started the request
+        limits: AiciLimits,
     },
     Fork {
         inst_id: ModuleInstId,
@@ -157,6 +187,9 @@ enum SeqCmd {
     MidProcess {
         data: RtMidProcessArg,
     },
+    PostProcess {
+        data: PostProcessArg,
+    },
     RunMain {},
     Compile {
         wasm: Vec<u8>,
@@ -171,6 +204,7 @@ impl SeqCmd {
             SeqCmd::Fork { .. } => "fork",
             SeqCmd::SetId { .. } => "set_id",
             SeqCmd::MidProcess { .. } => "process",
+            SeqCmd::PostProcess { .. } => "post_process",
             SeqCmd::RunMain {} => "run_main",
             SeqCmd::Compile { .. } => "compile",
         }
@@ -184,7 +218,12 @@ enum SeqResp {
     Ok {},
     InitPrompt { json: String },
     PostPreProcess { post_json: String, pre_json: String },
-    MidProcess { json: String },
+    MidProcess {
+        res: SequenceResult<ProcessResultOffset>,
+        sparse: Vec<(u32, Vec<TokenId>)>,
+        soft: Vec<u32>,
+    },
+    PostProcess { res: SequenceResult },
     Compile { binary: Vec<u8> },
     Error { msg: String, is_user_error: bool },
 }
@@ -342,10 +381,11 @@ impl SeqCtx {
                 match fork_child(&self.wasm_ctx.limits)? {
                     ForkResult::Parent { handle } => Ok(SeqResp::Fork { handle }),
                     ForkResult::Child { server } => {
-                        set_max_priority();
+                        // the priority is inherited from the parent
                         self.server = server;
                         self.inst_id = inst_id;
                         self.mutinst().set_id(inst_id);
+                        self.mutinst().after_fork()?;
                         // note that this is sent over the child channel
                         // we do it this way, so that we come back to dispatch_loop()
                         // and continue in the child with the same stack height as in the parent
@@ -354,23 +394,58 @@ impl SeqCtx {
                 }
             }
             SeqCmd::Instantiate {
+                req_id,
                 module_path,
                 module_id,
                 module_arg,
+                native,
+                request_info,
                 prompt_str,
                 prompt_toks,
+                limits,
             } => {
-                let module = self.wasm_ctx.deserialize_module(module_path).unwrap();
                 let _ = module_id;
+                // the worker only ever serves this request
+                self.id = req_id;
+                match self.group_cmd(GroupCmd::SetLimits {
+                    limits: limits.clone(),
+                }) {
+                    GroupResp::Ok {} => {}
+                    r => return Err(anyhow!("unexpected response (set limits) {r:?}")),
+                }
+                self.wasm_ctx.limits = limits;
+                let request_info: RequestInfo = serde_json::from_str(&request_info)?;
                 let ch = std::mem::take(&mut self.query);
-                let mut inst = ModuleInstance::new(
-                    424242,
-                    self.wasm_ctx.clone(),
-                    module,
-                    module_arg,
-                    ch.unwrap(),
-                    self.shm.clone(),
-                )?;
+                let mut inst: Box<dyn Controller> = if native {
+                    let mut inst = NativeInstance::new(
+                        424242,
+                        &self.wasm_ctx,
+                        &module_path,
+                        module_arg,
+                        ch.unwrap(),
+                        self.shm.clone(),
+                    )?;
+                    inst.set_request_info(request_info);
+                    Box::new(inst)
+                } else {
+                    let module = self
+                        .wasm_ctx
+                        .deserialize_module(module_path.clone())
+                        .unwrap();
+                    let mut inst = ModuleInstance::new(
+                        424242,
+                        self.wasm_ctx.clone(),
+                        module,
+                        ch.unwrap(),
+                        self.shm.clone(),
+                    )?;
+                    // the snapshot lives next to the compiled module in the cache;
+                    // it's shared by all requests, so nothing request-specific is set yet
+                    inst.preinit(&module_path.with_extension("snap"))?;
+                    inst.set_module_arg(module_arg);
+                    inst.set_request_info(request_info);
+                    Box::new(inst)
+                };
                 let prompt_toks = if let Some(t) = prompt_toks {
                     t
                 } else {
@@ -385,6 +460,10 @@ impl SeqCtx {
                 };
                 self.modinst = Some(inst);
                 let r = self.mutinst().setup(prompt_toks);
+                // everything from the file system is loaded by now
+                if self.wasm_ctx.limits.seccomp {
+                    seccomp::install(Profile::Seq)?;
+                }
                 Ok(SeqResp::InitPrompt {
                     json: serde_json::to_string(&r)?,
                 })
@@ -394,11 +473,21 @@ impl SeqCtx {
                 self.mutinst().set_id(inst_id);
                 ok()
             }
-            SeqCmd::MidProcess { data } => {
+            SeqCmd::MidProcess { mut data } => {
+                for cmd in std::mem::take(&mut data.storage) {
+                    match self.group_cmd(GroupCmd::StorageCmd { cmd }) {
+                        GroupResp::StorageResp { resp } => log::debug!("client storage: {resp:?}"),
+                        r => return Err(anyhow!("unexpected response (storage) {r:?}")),
+                    }
+                }
                 let res = self.mutinst().mid_process(data);
-                Ok(SeqResp::MidProcess {
-                    json: serde_json::to_string(&res)?,
-                })
+                let sparse = self.mutinst().take_sparse_masks();
+                let soft = self.mutinst().take_soft_masks();
+                Ok(SeqResp::MidProcess { res, sparse, soft })
+            }
+            SeqCmd::PostProcess { data } => {
+                let res = self.mutinst().post_process(data);
+                Ok(SeqResp::PostProcess { res })
             }
             SeqCmd::RunMain {} => {
                 self.mutinst().run_main()?;
@@ -407,12 +496,10 @@ impl SeqCtx {
         }
     }
 
-    fn mutinst(&mut self) -> &mut ModuleInstance {
-        self.modinst.as_mut().unwrap()
+    fn mutinst(&mut self) -> &mut dyn Controller {
+        self.modinst.as_deref_mut().unwrap()
     }
 
-    // we may want to do this in future, but for now only group cmd is storage
-    #[allow(dead_code)]
     fn group_cmd(&self, query: GroupCmd) -> GroupResp {
         if let Some(q) = &self.query {
             q.send_cmd(query).unwrap()
@@ -431,7 +518,7 @@ impl SeqCtx {
             let cmd = self
                 .server
                 .recv_req(self.wasm_ctx.limits.busy_wait_duration);
-            log::trace!("seq recv {cmd:?}");
+            log::trace!("seq {} recv {cmd:?}", self.id);
             let resp = match self.dispatch_one(cmd) {
                 Ok(v) => v,
                 Err(e) => SeqResp::Error {
@@ -451,13 +538,13 @@ struct GroupCtx {
 }
 
 struct SeqCtx {
-    #[allow(dead_code)]
+    /// request id, for logs
     id: String,
     server: TypedServer<SeqCmd, SeqResp>,
     wasm_ctx: WasmContext,
     query: Option<GroupHandle>,
     inst_id: ModuleInstId,
-    modinst: Option<ModuleInstance>,
+    modinst: Option<Box<dyn Controller>>,
     shm: Rc<ShmAllocator>,
 }
 
@@ -473,6 +560,8 @@ impl Drop for CommsPid {
 
 pub struct SeqWorkerHandle {
     pub req_id: String,
+    /// Limits of the user who started the request; forks inherit them.
+    pub limits: Arc<AiciLimits>,
     handle: SeqHandle,
     comms_pid: Option<Arc<CommsPid>>,
 }
@@ -494,14 +583,18 @@ impl SeqWorkerHandle {
             .send_cmd_expect_ok(SeqCmd::RunMain {}, Timeout::from_millis(120_000))
     }
 
-    pub fn fork(&self, target_id: ModuleInstId) -> Result<SeqWorkerHandle> {
-        match self
-            .handle
-            .send_cmd_with_timeout(SeqCmd::Fork { inst_id: target_id }, Timeout::Quick)?
-        {
+    /// Ask the worker to fork itself; the fork should be then collected with finish_fork().
+    /// This allows multiple workers to fork at the same time.
+    pub fn start_fork(&self, target_id: ModuleInstId) -> Result<()> {
+        self.handle.just_send(SeqCmd::Fork { inst_id: target_id })
+    }
+
+    pub fn finish_fork(&self) -> Result<SeqWorkerHandle> {
+        match self.handle.seq_recv_with_timeout("fork", Timeout::Quick)? {
             SeqResp::Fork { handle } => {
                 let res = SeqWorkerHandle {
                     req_id: self.req_id.clone(),
+                    limits: self.limits.clone(),
                     handle: handle.to_client(),
                     comms_pid: self.comms_pid.clone(),
                 };
@@ -519,16 +612,41 @@ impl SeqWorkerHandle {
         Ok(())
     }
 
-    pub fn check_process(&self, timeout: Duration) -> Result<SequenceResult<ProcessResultOffset>> {
+    /// Result of mid_process, allowed tokens of sparse masks by shm offset,
+    /// and shm offsets of masks written as f32 rows with soft biases.
+    pub fn check_process(
+        &self,
+        timeout: Duration,
+    ) -> Result<(
+        SequenceResult<ProcessResultOffset>,
+        Vec<(u32, Vec<TokenId>)>,
+        Vec<u32>,
+    )> {
         match self
             .handle
             .seq_recv_with_timeout("r-process", Timeout::Speculative(timeout))
         {
-            Ok(SeqResp::MidProcess { json }) => Ok(serde_json::from_str(&json)?),
+            Ok(SeqResp::MidProcess { res, sparse, soft }) => Ok((res, sparse, soft)),
             Ok(r) => Err(anyhow!("unexpected response (process) {r:?}")),
             Err(e) => Err(e.into()),
         }
     }
+
+    pub fn start_post_process(&self, data: PostProcessArg) -> Result<()> {
+        self.handle.just_send(SeqCmd::PostProcess { data })?;
+        Ok(())
+    }
+
+    pub fn check_post_process(&self, timeout: Duration) -> Result<SequenceResult> {
+        match self
+            .handle
+            .seq_recv_with_timeout("r-post-process", Timeout::Speculative(timeout))
+        {
+            Ok(SeqResp::PostProcess { res }) => Ok(res),
+            Ok(r) => Err(anyhow!("unexpected response (post_process) {r:?}")),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
 impl GroupCtx {
@@ -541,6 +659,10 @@ impl GroupCtx {
             GroupCmd::StorageCmd { cmd } => GroupResp::StorageResp {
                 resp: self.dispatch_storage_cmd(cmd),
             },
+            GroupCmd::SetLimits { limits } => {
+                self.limits = limits;
+                GroupResp::Ok {}
+            }
         }
     }
 
@@ -558,12 +680,67 @@ pub struct WorkerForker {
     fork_worker: ForkerHandle,
 }
 
+/// Runs in a freshly forked seq worker; `for_compile` workers are only used to compile modules.
+fn seq_worker(
+    server: TypedServer<SeqCmd, SeqResp>,
+    wasm_ctx: WasmContext,
+    shm: Rc<ShmAllocator>,
+    id: String,
+    for_compile: bool,
+) -> ! {
+    let _pre_timer = wasm_ctx.timers.new_timer("pre_outer");
+    let mut w_ctx = SeqCtx {
+        id,
+        server,
+        wasm_ctx,
+        shm,
+        query: None,
+        inst_id: 424242,
+        modinst: None,
+    };
+
+    if for_compile {
+        setup_bg_worker_pool();
+        w_ctx.dispatch_loop();
+    }
+
+    // and the seq worker then forks the communication process
+    // this way we don't have to send query_handle to the seq worker
+    // (inheriting it from fork doesn't work on macOS)
+    match fork_child(&w_ctx.wasm_ctx.limits).unwrap() {
+        ForkResult::Parent { handle } => {
+            set_process_name("aicirt-seq");
+            set_max_priority();
+            w_ctx.query = Some(handle.to_client());
+            w_ctx.dispatch_loop()
+        }
+        ForkResult::Child { server } => {
+            set_process_name("aicirt-comms");
+            set_max_priority();
+            if w_ctx.wasm_ctx.limits.seccomp {
+                seccomp::install(Profile::Comms).expect("failed to install seccomp filter");
+            }
+            let mut grp_ctx = GroupCtx {
+                variables: Variables::default(),
+                server,
+                limits: w_ctx.wasm_ctx.limits,
+            };
+            grp_ctx.dispatch_loop()
+        }
+    }
+}
+
 fn forker_dispatcher(
     mut server: TypedServer<ForkerCmd, ForkerResp>,
     wasm_ctx: WasmContext,
     shm: Rc<ShmAllocator>,
 ) -> ! {
     set_process_name("aicirt-forker");
+
+    // idle seq workers (each with its comms process already forked),
+    // handed out to new requests, so they don't have to wait for forks
+    let mut pool: VecDeque<WireSeqHandle> = VecDeque::new();
+
     loop {
         // wait for any children that might have exited to prevent zombies
         loop {
@@ -572,19 +749,44 @@ fn forker_dispatcher(
             if pid > 0 {
                 if libc::WIFEXITED(status) {
                     let exit_code = libc::WEXITSTATUS(status);
-                    log::debug!("Child {} exited with code {}", pid, exit_code);
+                    if exit_code == seccomp::EXIT_CODE {
+                        log::warn!("Child {} killed for a syscall not allowed by seccomp", pid);
+                    } else {
+                        log::debug!("Child {} exited with code {}", pid, exit_code);
+                    }
                 } else {
                     log::debug!("Child {} exited or sth", pid);
                 }
+                // idle workers shouldn't exit, but if they do, don't hand them out
+                let pool_len = pool.len();
+                pool.retain(|h| h.pid != pid);
+                if pool.len() != pool_len {
+                    log::warn!("pooled worker {} exited", pid);
+                }
             } else {
                 // no (more) children; stop
                 break;
             }
         }
 
+        // refill the pool, while the previously handed out worker is being instantiated
+        while pool.len() < wasm_ctx.limits.worker_pool_size {
+            match fork_child(&wasm_ctx.limits).unwrap() {
+                ForkResult::Parent { handle } => pool.push_back(handle),
+                ForkResult::Child { server } => {
+                    seq_worker(server, wasm_ctx, shm, "pool".to_string(), false)
+                }
+            }
+        }
+
         let cmd = server.recv_req(wasm_ctx.limits.busy_wait_duration);
-        let cmd_id = cmd.id;
-        let for_compile = cmd.for_compile;
+
+        if !cmd.for_compile {
+            if let Some(handle) = pool.pop_front() {
+                server.send_resp(ForkerResp(handle));
+                continue;
+            }
+        }
 
         // fork the seq worker first
         match fork_child(&wasm_ctx.limits).unwrap() {
@@ -592,50 +794,22 @@ fn forker_dispatcher(
                 server.send_resp(ForkerResp(handle));
             }
             ForkResult::Child { server } => {
-                let _pre_timer = wasm_ctx.timers.new_timer("pre_outer");
-                let mut w_ctx = SeqCtx {
-                    id: cmd_id,
-                    server,
-                    wasm_ctx,
-                    shm,
-                    query: None,
-                    inst_id: 424242,
-                    modinst: None,
-                };
-
-                if for_compile {
-                    setup_bg_worker_pool();
-                    w_ctx.dispatch_loop();
-                }
-
-                // and the seq worker then forks the communication process
-                // this way we don't have to send query_handle to the seq worker
-                // (inheriting it from fork doesn't work on macOS)
-                match fork_child(&w_ctx.wasm_ctx.limits).unwrap() {
-                    ForkResult::Parent { handle } => {
-                        set_process_name("aicirt-seq");
-                        set_max_priority();
-                        w_ctx.query = Some(handle.to_client());
-                        w_ctx.dispatch_loop()
-                    }
-                    ForkResult::Child { server } => {
-                        set_process_name("aicirt-comms");
-                        set_max_priority();
-                        let mut grp_ctx = GroupCtx {
-                            variables: Variables::default(),
-                            server,
-                            limits: w_ctx.wasm_ctx.limits,
-                        };
-                        grp_ctx.dispatch_loop()
-                    }
-                }
+                seq_worker(server, wasm_ctx, shm, cmd.id, cmd.for_compile)
             }
         }
     }
 }
 
+// exit code of the main process, set by stop_process_with()
+static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+
 extern "C" fn clean_exit(_: libc::c_int) {
-    std::process::exit(0);
+    std::process::exit(EXIT_CODE.load(Ordering::Relaxed));
+}
+
+pub fn stop_process_with(code: i32) -> ! {
+    EXIT_CODE.store(code, Ordering::Relaxed);
+    stop_process()
 }
 
 pub fn stop_process() -> ! {
@@ -695,6 +869,8 @@ impl WorkerForker {
         &self,
         req: InstantiateReq,
         module_path: PathBuf,
+        native: bool,
+        limits: AiciLimits,
     ) -> Result<(SeqWorkerHandle, SequenceResult<InitPromptResult>)> {
         let module_arg = match req.module_arg.as_str() {
             Some(a) => a.to_string(),
@@ -728,6 +904,7 @@ impl WorkerForker {
         })?;
         let mut res = SeqWorkerHandle {
             req_id: req.req_id.clone(),
+            limits: Arc::new(limits.clone()),
             handle: resp.0.to_client(),
             comms_pid: None,
         };
@@ -741,13 +918,17 @@ impl WorkerForker {
         res.comms_pid = Some(Arc::new(CommsPid { pid: comms_pid }));
         match res.handle.send_cmd_with_timeout(
             SeqCmd::Instantiate {
+                req_id: req.req_id.clone(),
                 module_path,
                 module_id: req.module_id.clone(),
                 module_arg,
+                native,
+                request_info: serde_json::to_string(&req.request_info)?,
                 prompt_str,
                 prompt_toks,
+                limits: limits.clone(),
             },
-            Timeout::from_millis(self.limits.max_init_ms),
+            Timeout::from_millis(limits.init_ms()),
         )? {
             SeqResp::InitPrompt { json } => {
                 let r: SequenceResult<InitPromptResult> = serde_json::from_str(&json)?;
@@ -767,6 +948,7 @@ impl WorkerForker {
         // res.drop() kills handle
         let res = SeqWorkerHandle {
             req_id: id.clone(),
+            limits: Arc::new(self.limits.clone()),
             handle: resp.0.to_client(),
             comms_pid: None,
         };
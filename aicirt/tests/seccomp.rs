@@ -0,0 +1,122 @@
+#![cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+
+use aicirt::{
+    seccomp::{self, Profile},
+    shm::Shm,
+};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// the filter can't be removed, so it's only ever installed in a forked child;
+// returns the exit code of the child
+fn in_child(f: impl FnOnce() -> i32) -> i32 {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let code = f();
+        unsafe { libc::_exit(code) }
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status), "status {status}");
+    libc::WEXITSTATUS(status)
+}
+
+fn word(shm: &Shm, idx: usize) -> &AtomicU32 {
+    unsafe { &*(shm.ptr_at(4 * idx) as *const AtomicU32) }
+}
+
+fn wait_for(w: &AtomicU32) -> u32 {
+    loop {
+        let v = w.load(Ordering::SeqCst);
+        if v != 0 {
+            return v;
+        }
+        std::thread::yield_now();
+    }
+}
+
+// like fork_child(): the filtered worker forks, and the channel to the child
+// is mapped by the (unfiltered) parent from /proc/<child>/fd/
+#[test]
+fn fork_under_seq_filter() {
+    const PID: usize = 0;
+    const FD: usize = 1;
+    const DONE: usize = 2;
+    let shared = Shm::anon(1 << 20).unwrap();
+
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        seccomp::install(Profile::Seq).unwrap();
+        let chan = Shm::memfd(1 << 20).unwrap();
+        let code = match unsafe { libc::fork() } {
+            0 => {
+                // waitpid() isn't allowed, so report through the shm
+                wait_for(word(&chan, 0));
+                word(&shared, DONE).store(1, Ordering::SeqCst);
+                0
+            }
+            child if child > 0 => {
+                word(&shared, FD).store(chan.fd().unwrap() as u32, Ordering::SeqCst);
+                word(&shared, PID).store(child as u32, Ordering::SeqCst);
+                drop(chan);
+                wait_for(word(&shared, DONE));
+                7
+            }
+            _ => 1,
+        };
+        unsafe { libc::_exit(code) }
+    }
+
+    let child = wait_for(word(&shared, PID));
+    let fd = word(&shared, FD).load(Ordering::SeqCst);
+    let chan = Shm::open(&format!("/proc/{child}/fd/{fd}"), 1 << 20).unwrap();
+    word(&chan, 0).store(1, Ordering::SeqCst);
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status), "status {status}");
+    assert_eq!(libc::WEXITSTATUS(status), 7);
+}
+
+#[test]
+fn disallowed_syscalls() {
+    let open = || {
+        seccomp::install(Profile::Seq).unwrap();
+        let _ = std::fs::File::open("/dev/null");
+        0
+    };
+    assert_eq!(in_child(open), seccomp::EXIT_CODE);
+
+    let kill = || {
+        seccomp::install(Profile::Seq).unwrap();
+        unsafe { libc::kill(1, 0) };
+        0
+    };
+    assert_eq!(in_child(kill), seccomp::EXIT_CODE);
+
+    let prctl = || {
+        seccomp::install(Profile::Seq).unwrap();
+        unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) };
+        0
+    };
+    assert_eq!(in_child(prctl), seccomp::EXIT_CODE);
+
+    let comms_fork = || {
+        seccomp::install(Profile::Comms).unwrap();
+        unsafe { libc::fork() };
+        0
+    };
+    assert_eq!(in_child(comms_fork), seccomp::EXIT_CODE);
+}
+
+#[test]
+fn allowed_prctl() {
+    let code = in_child(|| {
+        seccomp::install(Profile::Seq).unwrap();
+        let name = b"aicirt-test\0";
+        unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr() as usize, 0, 0, 0) };
+        3
+    });
+    assert_eq!(code, 3);
+}
@@ -0,0 +1,86 @@
+use aicirt::sockchannel::{side_addr, SockListener, SockStream};
+use std::os::unix::net::UnixStream;
+
+fn pair() -> (SockStream, SockStream) {
+    let (a, b) = UnixStream::pair().unwrap();
+    (SockStream::Unix(a), SockStream::Unix(b))
+}
+
+#[test]
+fn round_trip() {
+    let (mut a, mut b) = pair();
+    let big = (0..1_000_000).map(|i| i as u8).collect::<Vec<_>>();
+    let msgs = vec![b"{}".to_vec(), vec![], big];
+    let msgs2 = msgs.clone();
+    let writer = std::thread::spawn(move || {
+        for m in &msgs2 {
+            a.send_msg(m).unwrap();
+        }
+    });
+    for m in &msgs {
+        assert_eq!(&b.recv_msg(m.len()).unwrap(), m);
+    }
+    writer.join().unwrap();
+
+    // EOF
+    assert!(b.recv_msg(100).is_err());
+}
+
+#[test]
+fn max_size() {
+    let (mut a, mut b) = pair();
+    a.send_msg(&[1u8; 100]).unwrap();
+    let err = b.recv_msg(99).unwrap_err();
+    assert!(err.to_string().contains("too large"), "{err}");
+}
+
+#[test]
+fn unix_listener() {
+    let path = std::env::temp_dir().join(format!("aicirt-test-{}.sock", std::process::id()));
+    let addr = format!("unix:{}", path.display());
+    let listener = SockListener::bind(&addr).unwrap();
+    let mut client = SockStream::connect(&addr).unwrap();
+    let mut server = listener.accept().unwrap();
+    client.send_msg(b"ping").unwrap();
+    assert_eq!(server.recv_msg(4).unwrap(), b"ping");
+    server.send_msg(b"pong").unwrap();
+    assert_eq!(client.recv_msg(4).unwrap(), b"pong");
+    drop(listener);
+    assert!(!path.exists());
+}
+
+#[test]
+fn side_addresses() {
+    assert_eq!(side_addr("unix:/tmp/a", "-side").unwrap(), "unix:/tmp/a-side");
+    assert_eq!(side_addr("tcp:0.0.0.0:9100", "-side").unwrap(), "tcp:0.0.0.0:9101");
+    assert_eq!(side_addr("tcp:h:1", "").unwrap(), "tcp:h:1");
+    assert!(side_addr("tcp:h", "-side").is_err());
+    assert!(side_addr("h:1", "-side").is_err());
+}
+
+#[test]
+fn auth() {
+    let (mut a, mut b) = pair();
+    let server = std::thread::spawn(move || {
+        b.server_auth("secret").unwrap();
+        b.recv_msg(100).unwrap()
+    });
+    a.client_auth("secret").unwrap();
+    a.send_msg(b"{}").unwrap();
+    assert_eq!(server.join().unwrap(), b"{}");
+
+    for token in ["wrong", "secre", "secret2", ""] {
+        let (mut a, mut b) = pair();
+        let server = std::thread::spawn(move || b.server_auth("secret"));
+        let err = a.client_auth(token).unwrap_err();
+        assert!(err.to_string().contains("invalid token"), "{err}");
+        assert!(server.join().unwrap().is_err());
+    }
+}
+
+#[test]
+fn auth_oversized() {
+    let (mut a, mut b) = pair();
+    a.send_msg(&[b'x'; 10_000]).unwrap();
+    assert!(b.server_auth("secret").is_err());
+}
@@ -1,4 +1,6 @@
-use crate::{bytes::vec_from_bytes, toktrie::TokTrie, SeqId, SimpleVob, TokenId};
+use crate::{
+    bytes::vec_from_bytes, toktrie::TokTrie, LogitBias, RequestInfo, SeqId, SimpleVob, TokenId,
+};
 use serde::{Deserialize, Serialize};
 use toktrie::TokenizerEnv;
 
@@ -28,6 +30,16 @@ extern "C" {
     // Set logit bias based on bit-mask in src.
     fn aici_host_return_logit_bias(src: *const u32) -> u32;
 
+    // Like aici_host_return_logit_bias(), but also add `bias` to logits.
+    // If `tokens` is null, `bias` holds values for tokens 0..num,
+    // otherwise `bias[i]` is added to logit of `tokens[i]`.
+    fn aici_host_return_logit_bias_soft(
+        src: *const u32,
+        tokens: *const u32,
+        bias: *const f32,
+        num: u32,
+    ) -> u32;
+
     fn aici_host_self_seq_id() -> u32;
 
     fn aici_host_return_process_result(res: *const u8, res_size: u32);
@@ -40,6 +52,9 @@ extern "C" {
     // Get value of configuration parameters, like "fork".
     fn aici_host_get_config(src: *const u8, src_size: u32) -> i32;
 
+    // Return the ID of JSON-serialized RequestInfo.
+    fn aici_host_request_info() -> BlobId;
+
     // Stop the program - any error info is assumed to have been printed already.
     // Backtraces will be limited.
     fn aici_host_stop();
@@ -107,6 +122,7 @@ pub trait HostInterface {
     fn arg_bytes(&self) -> Vec<u8>;
     fn trie_bytes(&self) -> Vec<u8>;
     fn return_logit_bias(&self, vob: &SimpleVob) -> u32;
+    fn return_logit_bias_soft(&self, vob: &SimpleVob, bias: &LogitBias) -> u32;
     fn process_arg_bytes(&self) -> Vec<u8>;
     fn return_process_result(&self, res: &[u8]);
     fn storage_cmd(&self, cmd: StorageCmd) -> StorageResp;
@@ -114,6 +130,7 @@ pub trait HostInterface {
     fn self_seq_id(&self) -> SeqId;
     fn eos_token(&self) -> TokenId;
     fn get_config(&self, name: &str) -> i32;
+    fn request_info_bytes(&self) -> Vec<u8>;
     fn stop(&self) -> !;
 }
 
@@ -134,6 +151,31 @@ impl HostInterface for WasmHost {
         unsafe { aici_host_return_logit_bias(vob.as_ptr()) }
     }
 
+    fn return_logit_bias_soft(&self, vob: &SimpleVob, bias: &LogitBias) -> u32 {
+        assert!(vob.len() > 0);
+        match bias {
+            LogitBias::Sparse(pairs) => {
+                let (tokens, values): (Vec<TokenId>, Vec<f32>) = pairs.iter().cloned().unzip();
+                unsafe {
+                    aici_host_return_logit_bias_soft(
+                        vob.as_ptr(),
+                        tokens.as_ptr(),
+                        values.as_ptr(),
+                        tokens.len() as u32,
+                    )
+                }
+            }
+            LogitBias::Dense(values) => unsafe {
+                aici_host_return_logit_bias_soft(
+                    vob.as_ptr(),
+                    std::ptr::null(),
+                    values.as_ptr(),
+                    values.len() as u32,
+                )
+            },
+        }
+    }
+
     fn process_arg_bytes(&self) -> Vec<u8> {
         read_blob(unsafe { aici_host_process_arg() }, 1024)
     }
@@ -181,6 +223,136 @@ impl HostInterface for WasmHost {
         let res = unsafe { aici_host_get_config(name_bytes.as_ptr(), name_bytes.len() as u32) };
         res
     }
+
+    fn request_info_bytes(&self) -> Vec<u8> {
+        read_blob(unsafe { aici_host_request_info() }, 1024)
+    }
+}
+
+/// Host functions passed by aicirt to native (shared library) controllers in aici_native_init().
+/// They have the same semantics as the aici_host_* imports of WASM controllers.
+#[repr(C)]
+pub struct NativeHostFns {
+    pub read_blob: extern "C" fn(blob: u32, dst: *mut u8, size: u32) -> u32,
+    pub token_trie: extern "C" fn() -> u32,
+    pub module_arg: extern "C" fn() -> u32,
+    pub process_arg: extern "C" fn() -> u32,
+    pub tokenize: extern "C" fn(src: *const u8, src_size: u32) -> u32,
+    pub return_logit_bias: extern "C" fn(src: *const u32) -> u32,
+    pub return_logit_bias_soft:
+        extern "C" fn(src: *const u32, tokens: *const u32, bias: *const f32, num: u32) -> u32,
+    pub self_seq_id: extern "C" fn() -> u32,
+    pub return_process_result: extern "C" fn(res: *const u8, res_size: u32),
+    pub storage_cmd: extern "C" fn(cmd: *const u8, cmd_size: u32) -> u32,
+    pub eos_token: extern "C" fn() -> TokenId,
+    pub get_config: extern "C" fn(src: *const u8, src_size: u32) -> i32,
+    pub request_info: extern "C" fn() -> u32,
+    pub stop: extern "C" fn(),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct NativeHost {
+    fns: &'static NativeHostFns,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl NativeHost {
+    fn read_blob(&self, blob: u32, prefetch_size: usize) -> Vec<u8> {
+        let mut buffer = vec![0u8; prefetch_size];
+        let prefetch_size = prefetch_size as u32;
+        let size = (self.fns.read_blob)(blob, buffer.as_mut_ptr(), prefetch_size);
+        buffer.resize(size as usize, 0);
+        if size > prefetch_size {
+            (self.fns.read_blob)(blob, buffer.as_mut_ptr(), size);
+        }
+        buffer
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl HostInterface for NativeHost {
+    fn arg_bytes(&self) -> Vec<u8> {
+        self.read_blob((self.fns.module_arg)(), 1024)
+    }
+
+    fn trie_bytes(&self) -> Vec<u8> {
+        self.read_blob((self.fns.token_trie)(), 0)
+    }
+
+    fn return_logit_bias(&self, vob: &SimpleVob) -> u32 {
+        assert!(vob.len() > 0);
+        (self.fns.return_logit_bias)(vob.as_ptr())
+    }
+
+    fn return_logit_bias_soft(&self, vob: &SimpleVob, bias: &LogitBias) -> u32 {
+        assert!(vob.len() > 0);
+        match bias {
+            LogitBias::Sparse(pairs) => {
+                let (tokens, values): (Vec<TokenId>, Vec<f32>) = pairs.iter().cloned().unzip();
+                (self.fns.return_logit_bias_soft)(
+                    vob.as_ptr(),
+                    tokens.as_ptr(),
+                    values.as_ptr(),
+                    tokens.len() as u32,
+                )
+            }
+            LogitBias::Dense(values) => (self.fns.return_logit_bias_soft)(
+                vob.as_ptr(),
+                std::ptr::null(),
+                values.as_ptr(),
+                values.len() as u32,
+            ),
+        }
+    }
+
+    fn process_arg_bytes(&self) -> Vec<u8> {
+        self.read_blob((self.fns.process_arg)(), 1024)
+    }
+
+    fn return_process_result(&self, res: &[u8]) {
+        (self.fns.return_process_result)(res.as_ptr(), res.len() as u32)
+    }
+
+    fn storage_cmd(&self, cmd: StorageCmd) -> StorageResp {
+        let cmd_bytes = serde_json::to_vec(&cmd).unwrap();
+        let res_id = (self.fns.storage_cmd)(cmd_bytes.as_ptr(), cmd_bytes.len() as u32);
+        let resp_bytes = self.read_blob(res_id, 1024);
+        serde_json::from_slice(&resp_bytes).unwrap()
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        let id = (self.fns.tokenize)(s.as_ptr(), s.len() as u32);
+        vec_from_bytes(&self.read_blob(id, 4 * (s.len() / 3 + 10)))
+    }
+
+    fn self_seq_id(&self) -> SeqId {
+        SeqId((self.fns.self_seq_id)())
+    }
+
+    fn eos_token(&self) -> TokenId {
+        (self.fns.eos_token)()
+    }
+
+    fn get_config(&self, name: &str) -> i32 {
+        let name_bytes = name.as_bytes();
+        (self.fns.get_config)(name_bytes.as_ptr(), name_bytes.len() as u32)
+    }
+
+    fn request_info_bytes(&self) -> Vec<u8> {
+        self.read_blob((self.fns.request_info)(), 1024)
+    }
+
+    fn stop(&self) -> ! {
+        (self.fns.stop)();
+        // aicirt treats the worker dying as an error of the controller
+        std::process::abort()
+    }
+}
+
+/// Called by aici_native_init(), exported by aici_expose_all!() in native builds.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_native_host(fns: &'static NativeHostFns) {
+    set_host(Box::new(NativeHost { fns }));
 }
 
 fn get_host() -> &'static Box<dyn HostInterface> {
@@ -215,20 +387,27 @@ pub fn return_logit_bias(vob: &SimpleVob) -> u32 {
     get_host().return_logit_bias(vob)
 }
 
+pub fn return_logit_bias_soft(vob: &SimpleVob, bias: &LogitBias) -> u32 {
+    get_host().return_logit_bias_soft(vob, bias)
+}
+
 pub fn process_arg_bytes() -> Vec<u8> {
     get_host().process_arg_bytes()
 }
 
 pub fn return_process_result(res: &[u8]) {
-    unsafe {
-        aici_host_return_process_result(res.as_ptr(), res.len() as u32);
-    }
+    get_host().return_process_result(res)
 }
 
 pub fn get_config(name: &str) -> i32 {
     get_host().get_config(name)
 }
 
+/// Get information about the current request (model, limits, metadata).
+pub fn request_info() -> RequestInfo {
+    serde_json::from_slice(&get_host().request_info_bytes()).unwrap()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StorageOp {
     Set,
@@ -301,10 +480,7 @@ pub enum StorageResp {
 }
 
 pub fn storage_cmd(cmd: StorageCmd) -> StorageResp {
-    let cmd_bytes = serde_json::to_vec(&cmd).unwrap();
-    let res_id = unsafe { aici_host_storage_cmd(cmd_bytes.as_ptr(), cmd_bytes.len() as u32) };
-    let resp_bytes = read_blob(res_id, 1024);
-    serde_json::from_slice(&resp_bytes).unwrap()
+    get_host().storage_cmd(cmd)
 }
 
 // Public APIs
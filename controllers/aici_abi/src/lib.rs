@@ -20,13 +20,21 @@ pub mod substring;
 
 pub type TokenId = toktrie::TokenId;
 
+/// Version of the interface between controllers and aicirt (host functions and exports).
+/// It is embedded in modules by aici_expose_all!() as `aici_abi_version` custom section.
+/// Bump it when the module starts depending on something an older aicirt doesn't provide;
+/// aicirt refuses modules built against a newer version than it supports.
+pub const AICI_ABI_VERSION: u32 = 1;
+
 pub use host::{
-    aici_stop, arg_bytes, arg_string, get_config, host_trie, self_seq_id, tokenize, tokenize_bytes,
-    StorageCmd, StorageOp, StorageResp, VariableStorage, WasmTokenizerEnv,
+    aici_stop, arg_bytes, arg_string, get_config, host_trie, request_info, self_seq_id, tokenize,
+    tokenize_bytes, StorageCmd, StorageOp, StorageResp, VariableStorage, WasmTokenizerEnv,
 };
 
+pub use host::NativeHostFns;
+
 #[cfg(not(target_arch = "wasm32"))]
-pub use host::{set_host, HostInterface};
+pub use host::{set_host, set_native_host, HostInterface};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InitPromptArg {
@@ -48,6 +56,31 @@ impl InitPromptResult {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct SeqId(pub u32);
 
+/// Information about the current request, see request_info().
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RequestInfo {
+    /// Model id, as reported by the LLM engine.
+    #[serde(default)]
+    pub model_id: String,
+    /// Maximum length (prompt and generated tokens) of a sequence.
+    #[serde(default)]
+    pub context_length: usize,
+    /// Number of prompt tokens (after init_prompt()).
+    #[serde(default)]
+    pub prompt_tokens: usize,
+    /// max_tokens of the request.
+    pub max_tokens: Option<usize>,
+    /// max_tokens less the tokens generated so far by the current sequence.
+    pub remaining_tokens: Option<usize>,
+    /// Fuel limit of the request.
+    pub fuel: Option<usize>,
+    /// Fuel left, not counting tokens generated by other forks.
+    pub remaining_fuel: Option<usize>,
+    /// Metadata passed by the client with the request.
+    #[serde(default)]
+    pub metadata: serde_json::Value,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MidProcessArg {
     /// Sampling result for the previous iteration.
@@ -58,10 +91,23 @@ pub struct MidProcessArg {
     pub tokens: Vec<TokenId>,
     /// The token that was sampled, before splicing, if any.
     pub sampled: Option<TokenId>,
+    /// Log-probabilities for the sampled token.
+    /// Only set when requested with `logprobs` in the request.
+    #[serde(default)]
+    pub logprobs: Option<SampledLogprobs>,
     ///
     pub fork_group: Vec<SeqId>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SampledLogprobs {
+    /// Log-probability of the sampled token.
+    /// This is computed after applying the logit bias, but before temperature.
+    pub logprob: f32,
+    /// Most likely tokens and their log-probabilities, most likely first.
+    pub top: Vec<(TokenId, f32)>,
+}
+
 impl MidProcessArg {
     pub fn has_eos(&self) -> bool {
         let eos = host::eos_token();
@@ -81,13 +127,26 @@ impl MidProcessArg {
 
 pub use toktrie::{Branch, Splice};
 
+/// Additive bias for logits of a sampling branch, applied on top of the branch mask.
+#[derive(Debug, Clone)]
+pub enum LogitBias {
+    /// Biases for given tokens; other tokens are not affected.
+    Sparse(Vec<(TokenId, f32)>),
+    /// Biases indexed by token id; can be shorter than the vocabulary.
+    Dense(Vec<f32>),
+}
+
 #[derive(Debug)]
 pub struct MidProcessResult {
     /// Fork the request into multiple branches.
     /// Typically, exactly one branch is returned.
-    /// If multiple branches are returned, they are executed in parallel.
+    /// If multiple branches are returned, they are executed in parallel,
+    /// and each of them can sample under its own mask.
     /// If no branches are returned, the request is terminated.
     pub branches: Vec<Branch<SimpleVob>>,
+    /// Soft biases for sampling branches; `biases[i]` applies to `branches[i]`.
+    /// Can be shorter than `branches` (typically empty); set with `with_bias()`.
+    biases: Vec<Option<LogitBias>>,
 }
 
 impl MidProcessResult {
@@ -97,12 +156,20 @@ impl MidProcessResult {
         } else {
             MidProcessResult {
                 branches: vec![branch],
+                biases: vec![],
             }
         }
     }
 
+    pub fn from_branches(branches: Vec<Branch<SimpleVob>>) -> Self {
+        MidProcessResult {
+            branches,
+            biases: vec![],
+        }
+    }
+
     pub fn stop() -> Self {
-        MidProcessResult { branches: vec![] }
+        Self::from_branches(vec![])
     }
 
     pub fn sample(set: SimpleVob) -> Self {
@@ -113,6 +180,21 @@ impl MidProcessResult {
         Self::from_branch(Branch::sample(set, temperature))
     }
 
+    /// Sample from tokens in `set`, with `bias` added to their logits.
+    pub fn sample_with_bias(set: SimpleVob, bias: LogitBias, temperature: Option<f32>) -> Self {
+        Self::sample_with_temp(set, temperature).with_bias(0, bias)
+    }
+
+    /// Add soft bias to the sampling branch at `branch_idx`.
+    pub fn with_bias(mut self, branch_idx: usize, bias: LogitBias) -> Self {
+        assert!(branch_idx < self.branches.len());
+        if self.biases.len() <= branch_idx {
+            self.biases.resize(branch_idx + 1, None);
+        }
+        self.biases[branch_idx] = Some(bias);
+        self
+    }
+
     pub fn splice(backtrack: u32, ff_tokens: Vec<TokenId>) -> Self {
         Self::from_branch(Branch::splice(backtrack, ff_tokens))
     }
@@ -126,9 +208,32 @@ impl MidProcessResult {
     }
 }
 
+/// Why a sequence has finished.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The controller stopped the sequence (returned no branches).
+    Stopped,
+    /// EOS token was generated.
+    Eos,
+    /// max_tokens was reached.
+    MaxTokens,
+    /// The request has run out of fuel.
+    OutOfFuel,
+    /// The request was aborted by the user.
+    Aborted,
+    /// Any other reason, including errors.
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostProcessArg {
+    pub reason: FinishReason,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ProcessResultOffset {
-    /// Branches use byte offsets into the bias tensor.
+    /// Branches use byte offsets into the bias tensor, one per sampling branch.
     pub branches: Vec<Branch<usize>>,
 }
 
@@ -142,6 +247,11 @@ pub trait AiciCtrl {
     /// This is the main entry point for the module. ~20ms time limit.
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult;
 
+    /// Called once the sequence has finished. ~20ms time limit.
+    /// Logs and storage writes from here are included in the final response.
+    /// By default do nothing.
+    fn post_process(&mut self, _arg: PostProcessArg) {}
+
     // Internals
     fn aici_init_prompt(&mut self) {
         let arg: InitPromptArg = serde_json::from_slice(&host::process_arg_bytes()).unwrap();
@@ -154,18 +264,16 @@ pub trait AiciCtrl {
         let arg: MidProcessArg = serde_json::from_slice(&host::process_arg_bytes())
             .expect("aici_mid_process: failed to deserialize MidProcessArg");
         let res = self.mid_process(arg);
-        let mut used_logits = false;
+        // each sampling branch gets its own slot in the bias tensor
         let res = ProcessResultOffset {
             branches: res
                 .branches
                 .into_iter()
-                .map(|b| {
-                    b.map_mask(|vob| {
-                        if used_logits {
-                            panic!("aici_mid_process: multiple branches with sampling not yet supported");
-                        }
-                        used_logits = true;
-                        host::return_logit_bias(&vob) as usize
+                .enumerate()
+                .map(|(idx, b)| {
+                    b.map_mask(|vob| match res.biases.get(idx) {
+                        Some(Some(bias)) => host::return_logit_bias_soft(&vob, bias) as usize,
+                        _ => host::return_logit_bias(&vob) as usize,
                     })
                 })
                 .collect(),
@@ -173,6 +281,12 @@ pub trait AiciCtrl {
         let res_bytes = serde_json::to_vec(&res).expect("aici_mid_process: failed to serialize");
         host::return_process_result(&res_bytes);
     }
+
+    fn aici_post_process(&mut self) {
+        let arg: PostProcessArg = serde_json::from_slice(&host::process_arg_bytes())
+            .expect("aici_post_process: failed to deserialize PostProcessArg");
+        self.post_process(arg);
+    }
 }
 
 /// Expose method as extern "C", usage:
@@ -204,6 +318,7 @@ macro_rules! aici_expose_all {
     ($struct_name:ident, $new:expr) => {
         $crate::expose!($struct_name::aici_mid_process() -> ());
         $crate::expose!($struct_name::aici_init_prompt() -> ());
+        $crate::expose!($struct_name::aici_post_process() -> ());
 
         #[no_mangle]
         pub extern "C" fn aici_create() -> *mut $struct_name {
@@ -215,6 +330,19 @@ macro_rules! aici_expose_all {
         pub extern "C" fn aici_panic() {
             panic!("aici_panic()")
         }
+
+        #[cfg(target_arch = "wasm32")]
+        #[link_section = "aici_abi_version"]
+        #[used]
+        static AICI_ABI_VERSION_SECTION: [u8; 4] = $crate::AICI_ABI_VERSION.to_le_bytes();
+
+        // entry point when loaded by aicirt as shared library (--native-module)
+        #[cfg(not(target_arch = "wasm32"))]
+        #[no_mangle]
+        pub extern "C" fn aici_native_init(fns: &'static $crate::NativeHostFns) -> u32 {
+            $crate::set_native_host(fns);
+            $crate::AICI_ABI_VERSION
+        }
     }
 }
 
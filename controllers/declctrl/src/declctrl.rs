@@ -1238,9 +1238,9 @@ impl AiciCtrl for Runner {
 
         if let StepSpecific::Fork { branches } = &self.curr_state().specific {
             assert!(branches.len() > 1);
-            return MidProcessResult {
-                branches: branches.iter().map(|_| Branch::noop()).collect(),
-            };
+            return MidProcessResult::from_branches(
+                branches.iter().map(|_| Branch::noop()).collect(),
+            );
         }
 
         if self.maybe_wait() {
@@ -1,4 +1,4 @@
-use std::sync::Mutex;
+use std::{cell::RefCell, sync::Mutex};
 
 use aici_abi::{
     aici_stop, host_trie,
@@ -323,11 +323,16 @@ mod aici_mod {
         get_config(name)
     }
 
+    #[rquickjs::function]
+    pub fn _requestInfo() -> String {
+        serde_json::to_string(&aici_abi::request_info()).unwrap()
+    }
+
     #[rquickjs::function]
     pub fn _midProcessReturn(obj: Object<'_>) {
         let branches: Vec<Object> = obj.get2("branches");
-        let res = MidProcessResult {
-            branches: branches
+        let res = MidProcessResult::from_branches(
+            branches
                 .into_iter()
                 .map(|b| {
                     let sample_mask: Option<TokenSet> = b.get2("sampleMask");
@@ -346,7 +351,7 @@ mod aici_mod {
                     }
                 })
                 .collect(),
-        };
+        );
 
         let mut st = GLOBAL_STATE.lock().unwrap();
         st.mid_process_result = Some(res);
@@ -482,10 +487,22 @@ fn _print(msg: String) {
     println!("{msg}");
 }
 
-impl Runner {
-    pub fn new(arg: Vec<u8>) -> Self {
-        let source = String::from_utf8(arg).unwrap();
+thread_local! {
+    // set by aici_preinit(), and taken over by the first Runner
+    static PREBOOTED: RefCell<Option<Runner>> = RefCell::new(None);
+}
 
+/// aicirt snapshots module state after this call, so that the runtime is booted only once.
+#[no_mangle]
+pub extern "C" fn aici_preinit() {
+    lazy_static::initialize(&GLOBAL_STATE);
+    let runner = Runner::boot();
+    PREBOOTED.with(|p| *p.borrow_mut() = Some(runner));
+}
+
+impl Runner {
+    /// Set up the runtime and the aici module; this doesn't depend on the argument.
+    fn boot() -> Self {
         let rt = Runtime::new().unwrap();
         let s = Self {
             context: Context::full(&rt).unwrap(),
@@ -493,7 +510,7 @@ impl Runner {
 
         let aici_js = include_str!("../ts/dist/aici.js");
 
-        s.with_cb("_new", |ctx| {
+        s.with_cb("_boot", |ctx| {
             let global = ctx.globals();
             let cons = Object::new(ctx.clone()).unwrap();
             let f = Function::new(ctx.clone(), _print).unwrap();
@@ -503,6 +520,19 @@ impl Runner {
             Module::declare_def::<js_aici_mod, _>(ctx.clone(), "_aici").unwrap();
 
             let _ = ctx.unwrap_js(ctx.clone().compile("aici", aici_js));
+        });
+
+        s
+    }
+
+    pub fn new(arg: Vec<u8>) -> Self {
+        let source = String::from_utf8(arg).unwrap();
+
+        let s = PREBOOTED
+            .with(|p| p.borrow_mut().take())
+            .unwrap_or_else(Runner::boot);
+
+        s.with_cb("_new", |ctx| {
             let _ = ctx.unwrap_js(ctx.clone().compile("main", source));
         });
 
@@ -415,6 +415,30 @@ class Fork extends NextToken {
   }
 }
 
+/**
+ * Information about the current request.
+ */
+export interface RequestInfo {
+  model_id: string;
+  context_length: number;
+  prompt_tokens: number;
+  max_tokens?: number;
+  remaining_tokens?: number;
+  fuel?: number;
+  remaining_fuel?: number;
+  /**
+   * The `metadata` field from the request, passed as is.
+   */
+  metadata: any;
+}
+
+/**
+ * Get information about the current request (model, limits, and user-provided metadata).
+ */
+export function requestInfo(): RequestInfo {
+  return JSON.parse(_aici._requestInfo());
+}
+
 /**
  * Check if fork() is supported on this host.
  */
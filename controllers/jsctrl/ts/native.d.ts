@@ -207,6 +207,11 @@ declare module "_aici" {
 
   function _midProcessReturn(midProcessResult: any): void;
 
+  /**
+   * JSON-encoded information about the current request; use requestInfo() instead.
+   */
+  function _requestInfo(): string;
+
   /**
    * Represents a set of tokens.
    * The value is true at indices corresponding to tokens in the set.
@@ -12,6 +12,7 @@ use rustpython_vm::{
     VirtualMachine,
 };
 use std::{
+    cell::RefCell,
     ops::Deref,
     sync::{Arc, Mutex},
     vec,
@@ -150,6 +151,12 @@ mod _aici {
         Ok(v)
     }
 
+    #[pyfunction]
+    fn request_info(vm: &VirtualMachine) -> PyObjectRef {
+        let info = serde_json::to_value(aici_abi::request_info()).unwrap();
+        vm.new_json(&info)
+    }
+
     #[pyattr]
     #[pyclass(name)]
     #[derive(PyPayload)]
@@ -385,44 +392,63 @@ pub struct Runner {
     interpreter: rustpython_vm::Interpreter,
 }
 
+thread_local! {
+    // set by aici_preinit(), and taken over by the first Runner
+    static PREBOOTED: RefCell<Option<rustpython_vm::Interpreter>> = RefCell::new(None);
+}
+
+/// aicirt snapshots module state after this call, so that the interpreter is booted only once.
+#[no_mangle]
+pub extern "C" fn aici_preinit() {
+    lazy_static::initialize(&GLOBAL_STATE);
+    let interpreter = new_interpreter();
+    PREBOOTED.with(|p| *p.borrow_mut() = Some(interpreter));
+}
+
+fn new_interpreter() -> rustpython_vm::Interpreter {
+    rustpython_vm::Interpreter::with_init(Default::default(), |vm| {
+        vm.add_native_module(
+            "pyaici.server_native".to_owned(),
+            Box::new(_aici::make_module),
+        );
+        vm.add_frozen(rustpython_vm::py_freeze!(dir = "Lib"));
+
+        let code = rustpython_vm::py_compile!(
+            file = "../../py/pyaici/server.py",
+            module_name = "pyaici.server",
+            mode = "exec"
+        );
+        let empty = rustpython_vm::py_compile!(
+            source = "# nothing",
+            module_name = "pyaici",
+            mode = "exec"
+        );
+        let frozen_vec = vec![
+            (
+                "pyaici",
+                rustpython_vm::frozen::FrozenModule {
+                    code: empty,
+                    package: true,
+                },
+            ),
+            (
+                "pyaici.server",
+                rustpython_vm::frozen::FrozenModule {
+                    code,
+                    package: true,
+                },
+            ),
+        ];
+        vm.add_frozen(frozen_vec.into_iter());
+    })
+}
+
 impl Runner {
     pub fn new(arg: Vec<u8>) -> Self {
         let source = String::from_utf8(arg).unwrap();
-        let interpreter = rustpython_vm::Interpreter::with_init(Default::default(), |vm| {
-            vm.add_native_module(
-                "pyaici.server_native".to_owned(),
-                Box::new(_aici::make_module),
-            );
-            vm.add_frozen(rustpython_vm::py_freeze!(dir = "Lib"));
-
-            let code = rustpython_vm::py_compile!(
-                file = "../../py/pyaici/server.py",
-                module_name = "pyaici.server",
-                mode = "exec"
-            );
-            let empty = rustpython_vm::py_compile!(
-                source = "# nothing",
-                module_name = "pyaici",
-                mode = "exec"
-            );
-            let frozen_vec = vec![
-                (
-                    "pyaici",
-                    rustpython_vm::frozen::FrozenModule {
-                        code: empty,
-                        package: true,
-                    },
-                ),
-                (
-                    "pyaici.server",
-                    rustpython_vm::frozen::FrozenModule {
-                        code,
-                        package: true,
-                    },
-                ),
-            ];
-            vm.add_frozen(frozen_vec.into_iter());
-        });
+        let interpreter = PREBOOTED
+            .with(|p| p.borrow_mut().take())
+            .unwrap_or_else(new_interpreter);
         interpreter.enter(|vm| {
             let scope = vm.new_scope_with_builtins();
 
@@ -597,6 +623,30 @@ trait VmExt {
             .collect();
         vm.ctx.new_list(elts)
     }
+
+    fn new_json(&self, v: &serde_json::Value) -> PyObjectRef {
+        let vm = self.get_vm();
+        match v {
+            serde_json::Value::Null => vm.ctx.none(),
+            serde_json::Value::Bool(b) => vm.ctx.new_bool(*b).into(),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => vm.ctx.new_int(i).into(),
+                None => vm.ctx.new_float(n.as_f64().unwrap()).into(),
+            },
+            serde_json::Value::String(s) => vm.ctx.new_str(s.as_str()).into(),
+            serde_json::Value::Array(arr) => {
+                let elts = arr.iter().map(|e| self.new_json(e)).collect();
+                vm.ctx.new_list(elts).into()
+            }
+            serde_json::Value::Object(obj) => {
+                let dict = vm.ctx.new_dict();
+                for (k, e) in obj {
+                    dict.set_item(k.as_str(), self.new_json(e), vm).unwrap();
+                }
+                dict.into()
+            }
+        }
+    }
 }
 
 impl VmExt for VirtualMachine {
@@ -656,7 +706,7 @@ impl AiciCtrl for Runner {
                 }
             });
 
-            MidProcessResult { branches }
+            MidProcessResult::from_branches(branches)
         })
     }
 }
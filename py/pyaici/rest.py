@@ -177,6 +177,8 @@ def run_controller(
     prompt="",
     temperature: Optional[float] = None,
     max_tokens: Optional[int] = 200,
+    overflow: Optional[str] = None,
+    keep_prefix: Optional[int] = None,
     base_url: Optional[str] = None,
 ):
     data = {
@@ -184,6 +186,8 @@ def run_controller(
         "controller_arg": controller_arg,
         "prompt": prompt,
         "max_tokens": max_tokens,
+        "overflow": overflow,
+        "keep_prefix": keep_prefix,
         "temperature": temperature,
     }
     t0 = time.time()
@@ -17,6 +17,7 @@ from pyaici.server_native import (
     DynamicLexer,
     Constraint,
     get_config,
+    request_info,
     get_var,
     set_var,
     append_var,
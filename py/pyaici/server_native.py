@@ -1,7 +1,7 @@
 # Type stubs
 
 from __future__ import annotations
-from typing import Any, Sequence, List
+from typing import Any, Dict, Sequence, List
 import pyaici.server as aici
 
 
@@ -84,6 +84,14 @@ def get_config(name: str) -> int:
     ...
 
 
+def request_info() -> Dict[str, Any]:
+    """
+    Get information about the current request: "model_id", "context_length", "prompt_tokens",
+    "max_tokens", "remaining_tokens", "fuel", "remaining_fuel", and user-provided "metadata".
+    """
+    ...
+
+
 class TokenSet(Sequence[bool]):
     """
     Represents a set of tokens.
@@ -0,0 +1,155 @@
+import functools
+import re
+from typing import Optional
+
+import pytest
+
+import pyaici.rest
+
+# the "none" controller takes the prompt from controller_arg and just samples
+NONE = "none"
+
+
+def run(prompt: str, max_tokens: Optional[int], **kwargs):
+    res = pyaici.rest.run_controller(
+        controller=NONE,
+        controller_arg=prompt,
+        temperature=0.0,
+        max_tokens=max_tokens,
+        **kwargs,
+    )
+    if res["error"]:
+        pytest.fail(res["error"])
+    initial = res["response"][0]
+    assert initial["object"] == "initial-run"
+    return initial.get("truncation", None)
+
+
+def expect_error(msg: str, prompt: str, max_tokens: Optional[int], **kwargs):
+    with pytest.raises(RuntimeError, match=msg):
+        run(prompt, max_tokens, **kwargs)
+
+
+def rejected(prompt: str, max_tokens: int) -> str:
+    with pytest.raises(RuntimeError) as e:
+        run(prompt, max_tokens)
+    return str(e.value)
+
+
+@functools.cache
+def context_length() -> int:
+    m = re.search(r"maximum context length is (\d+) tokens",
+                  rejected("Hello", 10_000_000))
+    assert m
+    return int(m.group(1))
+
+
+def num_tokens(prompt: str) -> int:
+    # the rejection lists the tokens in the prompt, without running anything
+    m = re.search(r"\((\d+) in the messages",
+                  rejected(prompt, context_length()))
+    assert m
+    return int(m.group(1))
+
+
+@functools.cache
+def prompt_with_tokens(n: int) -> str:
+    words = n
+    for _ in range(10):
+        prompt = "hello " * words
+        k = num_tokens(prompt)
+        if k == n:
+            return prompt
+        words += n - k
+    pytest.fail(f"can't build a prompt with {n} tokens")
+
+
+def long_prompt() -> str:
+    return "hello " * (context_length() + 50)
+
+
+def test_fits():
+    assert run("Hello", 2) is None
+
+
+def test_reject():
+    expect_error("maximum context length", long_prompt(), 2)
+    expect_error("maximum context length", "Hello", context_length())
+
+
+def test_truncate_left():
+    ctx = context_length()
+    t = run(long_prompt(), 2, overflow="truncate_left")
+    assert t["policy"] == "truncate_left"
+    assert t["dropped_offset"] == 0
+    assert t["prompt_tokens"] > ctx
+    assert t["prompt_tokens"] - t["dropped_tokens"] + 2 == ctx
+    assert t["max_tokens"] == 2
+
+
+def test_truncate_middle():
+    ctx = context_length()
+    t = run(long_prompt(), 2, overflow="truncate_middle")
+    assert t["policy"] == "truncate_middle"
+    # the BOS token is kept by default
+    assert t["dropped_offset"] == 1
+    assert t["prompt_tokens"] - t["dropped_tokens"] + 2 == ctx
+
+    t = run(long_prompt(), 2, overflow="truncate_middle", keep_prefix=10)
+    assert t["dropped_offset"] == 10
+    assert t["prompt_tokens"] - t["dropped_tokens"] + 2 == ctx
+
+
+def test_truncate_middle_keep_prefix_too_large():
+    ctx = context_length()
+    expect_error("keep_prefix",
+                 long_prompt(),
+                 2,
+                 overflow="truncate_middle",
+                 keep_prefix=ctx + 100)
+    # it's enough to drop the tail of the prompt
+    prompt = long_prompt()
+    n = num_tokens(prompt)
+    t = run(prompt, 2, overflow="truncate_middle", keep_prefix=ctx - 2)
+    assert t["dropped_offset"] == ctx - 2
+    assert t["dropped_tokens"] == n - (ctx - 2)
+    expect_error("keep_prefix",
+                 prompt,
+                 2,
+                 overflow="truncate_middle",
+                 keep_prefix=ctx - 1)
+
+
+def test_truncate_no_room_for_output():
+    ctx = context_length()
+    for overflow in ["truncate_left", "truncate_middle"]:
+        expect_error("must be smaller", "Hello", ctx, overflow=overflow)
+        expect_error("must be smaller",
+                     long_prompt(),
+                     ctx + 1,
+                     overflow=overflow)
+
+
+def test_shrink_max_tokens():
+    ctx = context_length()
+    t = run(prompt_with_tokens(ctx - 2), 100, overflow="shrink_max_tokens")
+    assert t["policy"] == "shrink_max_tokens"
+    assert t["prompt_tokens"] == ctx - 2
+    assert t["dropped_tokens"] == 0
+    assert t["max_tokens"] == 2
+
+    t = run(prompt_with_tokens(ctx - 1), 100, overflow="shrink_max_tokens")
+    assert t["max_tokens"] == 1
+
+
+def test_shrink_max_tokens_to_zero():
+    ctx = context_length()
+    # max_tokens would be 0
+    expect_error("doesn't fit",
+                 prompt_with_tokens(ctx),
+                 2,
+                 overflow="shrink_max_tokens")
+    expect_error("doesn't fit",
+                 long_prompt(),
+                 2,
+                 overflow="shrink_max_tokens")
@@ -0,0 +1,206 @@
+//! Loading rllm options from a TOML file (`--config file.toml`).
+//!
+//! Top-level keys are names of command line options (`port = 8080`, `json_size = 64`);
+//! this includes backend-specific model loader options (`gguf`, `gpu_layers`, `dtype`, ...).
+//! The `[settings]` table sets engine settings (like `-s`) and the `[aicirt]` table
+//! is passed down to aicirt (like `-A`).
+//! Options given on the command line take precedence over the file;
+//! list options (`-s`, `-A`, `--test`) from the file and the command line are combined.
+
+use anyhow::{anyhow, bail, Result};
+use clap::{ArgAction, ArgMatches, Command};
+use std::ffi::OsString;
+use toml::{Table, Value};
+
+use crate::util::{all_setting_values, apply_settings};
+
+const CONFIG_ARG: &str = "--config";
+
+fn find_config_path(args: &[OsString]) -> Option<String> {
+    let mut iter = args.iter().skip(1);
+    while let Some(a) = iter.next() {
+        let a = a.to_string_lossy();
+        if a == CONFIG_ARG {
+            return iter.next().map(|s| s.to_string_lossy().to_string());
+        }
+        if let Some(v) = a.strip_prefix(&format!("{CONFIG_ARG}=")) {
+            return Some(v.to_string());
+        }
+    }
+    None
+}
+
+fn scalar_to_string(key: &str, v: &Value) -> Result<String> {
+    match v {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        _ => bail!("config: expecting a scalar value for '{key}'"),
+    }
+}
+
+fn values_of(key: &str, v: &Value) -> Result<Vec<String>> {
+    match v {
+        Value::Array(arr) => arr.iter().map(|e| scalar_to_string(key, e)).collect(),
+        _ => Ok(vec![scalar_to_string(key, v)?]),
+    }
+}
+
+/// Turn `[aicirt]` table into aicirt command line options.
+fn aicirt_args(tbl: &Table) -> Result<Vec<String>> {
+    let mut res = Vec::new();
+    for (k, v) in tbl {
+        let name = format!("--{}", k.replace('_', "-"));
+        match v {
+            Value::Boolean(true) => res.push(name),
+            Value::Boolean(false) => {}
+            _ => {
+                for v in values_of(k, v)? {
+                    res.push(format!("{name}={v}"));
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// If `--config` is present in `args`, read the file and apply it to `cli`.
+/// Scalar options become defaults (so that command line overrides them),
+/// while list options are prepended to the returned arguments.
+pub fn apply_config_file(
+    mut cli: Command,
+    args: Vec<OsString>,
+) -> Result<(Command, Vec<OsString>)> {
+    let path = match find_config_path(&args) {
+        Some(p) => p,
+        None => return Ok((cli, args)),
+    };
+    let content =
+        std::fs::read_to_string(&path).map_err(|e| anyhow!("can't read config {path}: {e}"))?;
+    let tbl: Table = content
+        .parse()
+        .map_err(|e| anyhow!("can't parse config {path}: {e}"))?;
+
+    let mut extra: Vec<OsString> = Vec::new();
+
+    for (key, v) in &tbl {
+        match key.as_str() {
+            "settings" => {
+                let settings = v
+                    .as_table()
+                    .ok_or_else(|| anyhow!("config: [settings] should be a table"))?;
+                for (k, v) in settings {
+                    extra.push("-s".into());
+                    extra.push(format!("{k}={}", scalar_to_string(k, v)?).into());
+                }
+                continue;
+            }
+            "aicirt" => {
+                let aicirt = v
+                    .as_table()
+                    .ok_or_else(|| anyhow!("config: [aicirt] should be a table"))?;
+                for a in aicirt_args(aicirt)? {
+                    extra.push(format!("--aicirt-arg={a}").into());
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let id = key.replace('-', "_");
+        let arg = cli
+            .get_arguments()
+            .find(|a| a.get_id().as_str() == id && a.get_long().is_some())
+            .ok_or_else(|| anyhow!("config: unknown option '{key}'"))?;
+        let long = arg.get_long().unwrap().to_string();
+        let action = arg.get_action().clone();
+
+        match action {
+            ArgAction::Append => {
+                for v in values_of(key, v)? {
+                    extra.push(format!("--{long}={v}").into());
+                }
+            }
+            ArgAction::SetTrue => {
+                let b = v
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("config: expecting boolean for '{key}'"))?;
+                cli = cli.mut_arg(&id, |a| a.default_value(b.to_string()));
+            }
+            ArgAction::Set => {
+                let v = scalar_to_string(key, v)?;
+                cli = cli.mut_arg(&id, |a| a.default_value(v).required(false));
+            }
+            _ => bail!("config: option '{key}' can't be set from config file"),
+        }
+    }
+
+    let mut res = args;
+    let rest = res.split_off(1);
+    res.extend(extra);
+    res.extend(rest);
+    Ok((cli, res))
+}
+
+fn raw_to_value(s: &str) -> Value {
+    if let Ok(i) = s.parse::<i64>() {
+        Value::Integer(i)
+    } else if let Ok(f) = s.parse::<f64>() {
+        Value::Float(f)
+    } else {
+        Value::String(s.to_string())
+    }
+}
+
+/// Render the effective configuration in the format accepted by `--config`.
+pub fn effective_config(cli: &Command, matches: &ArgMatches) -> Result<String> {
+    let setting_id = cli
+        .get_arguments()
+        .find(|a| a.get_short() == Some('s'))
+        .map(|a| a.get_id().as_str())
+        .unwrap_or("setting");
+
+    let mut tbl = Table::new();
+    for arg in cli.get_arguments() {
+        let id = arg.get_id().as_str();
+        if arg.get_long().is_none() || ["config", "print_config", setting_id].contains(&id) {
+            continue;
+        }
+        let value = match arg.get_action() {
+            ArgAction::SetTrue => Value::Boolean(matches.get_flag(id)),
+            ArgAction::Set | ArgAction::Append => {
+                let vals = match matches.get_raw(id) {
+                    Some(vals) => vals
+                        .map(|v| raw_to_value(&v.to_string_lossy()))
+                        .collect::<Vec<_>>(),
+                    None => continue,
+                };
+                if let ArgAction::Append = arg.get_action() {
+                    Value::Array(vals)
+                } else {
+                    vals.into_iter().next().unwrap()
+                }
+            }
+            _ => continue,
+        };
+        tbl.insert(id.to_string(), value);
+    }
+
+    let settings = matches
+        .get_many::<String>(setting_id)
+        .map(|v| v.cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    apply_settings(&settings)?;
+    tbl.insert(
+        "settings".to_string(),
+        Value::Table(
+            all_setting_values()
+                .into_iter()
+                .map(|(k, v)| (k, Value::Float(v)))
+                .collect(),
+        ),
+    );
+
+    Ok(toml::to_string(&tbl)?)
+}
@@ -282,3 +282,14 @@ impl Default for AiciConfig {
         Self { max_fuel: 0 }
     }
 }
+
+impl AiciConfig {
+    /// Maximal fuel per request; defaults to 10x the model context length.
+    pub fn max_fuel_for(&self, model_len: usize) -> usize {
+        if self.max_fuel == 0 {
+            model_len * 10
+        } else {
+            self.max_fuel
+        }
+    }
+}
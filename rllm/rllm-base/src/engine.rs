@@ -2,16 +2,16 @@ use crate::{
     config::{ParallelConfig, RllmConfig, SamplingParams, SchedulerConfig},
     iface::AiciRtIface,
     seq::{
-        FinishReason, RequestOutput, SchedulingPhase, SeqOutput, Sequence, SequenceGroup, Token,
-        TokenUsage,
+        FinishReason, GroupTiming, RequestOutput, SchedulingPhase, SeqOutput, Sequence,
+        SequenceGroup, Token, TokenUsage,
     },
     util::get_setting,
-    AiciBias as _, HashMap, LoaderArgs, LogitsProcessor, ModelExec, Scheduler, SchedulerOutputs,
-    SequenceManager, TBlockSpaceManager as _,
+    AiciBias as _, AiciBiasData, HashMap, LoaderArgs, LogitsProcessor, ModelExec, Scheduler,
+    SchedulerOutputs, SequenceManager, TBlockSpaceManager as _,
 };
-use aici_abi::{toktrie::TokTrie, Splice};
+use aici_abi::{toktrie::TokTrie, SampledLogprobs, Splice, StorageCmd};
 use aicirt::{
-    api::{AiciMidOp, AiciMidProcessReq, ModuleInstId, SequenceResult},
+    api::{AiciMidOp, AiciMidProcessReq, AiciMidProcessResp, ModuleInstId, SequenceResult},
     with_timer, TimerRef, TimerSet,
 };
 use anyhow::{bail, Error as E, Result};
@@ -20,9 +20,11 @@ use hf_hub::{
     RepoType,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, ops::Deref, path::PathBuf, sync::Arc, time::Instant};
+use std::{fmt::Display, ops::Deref, path::PathBuf, sync::Arc, time::{Duration, Instant}};
 use tokenizers::Tokenizer;
 
+const AICIRT_RESTARTED: &str = "aicirt was restarted; the request was aborted";
+
 #[derive(Clone)]
 pub struct ExpectedToken {
     pub sampled: Token,
@@ -43,6 +45,17 @@ pub struct AddRequest {
     pub sampling_params: SamplingParams,
     pub expected: Option<ExpectedGeneration>,
     pub init_result: Option<SequenceResult>,
+    /// Epoch of aicirt before the controller was instantiated; see [`AiciRtIface::epoch`].
+    pub aicirt_epoch: usize,
+}
+
+/// Input from the client for a running request.
+pub enum RequestInput {
+    Abort,
+    /// Storage commands to execute in the request's group before the next controller step.
+    Storage(Vec<StorageCmd>),
+    /// Tokens to splice into all sequences of the request.
+    Tokens(Vec<Token>),
 }
 
 pub enum Repo {
@@ -118,6 +131,27 @@ impl Stats {
     }
 }
 
+/// Log-probability of `token` and of `top_k` most likely tokens.
+fn sampled_logprobs(logits: &[f32], token: Token, top_k: usize) -> SampledLogprobs {
+    let max_logit = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let log_sum = max_logit + logits.iter().map(|l| (l - max_logit).exp()).sum::<f32>().ln();
+    let cmp = |a: &usize, b: &usize| logits[*b].total_cmp(&logits[*a]);
+    let mut top = (0..logits.len()).collect::<Vec<_>>();
+    if top_k < top.len() {
+        top.select_nth_unstable_by(top_k, cmp);
+        top.truncate(top_k);
+    }
+    top.sort_by(cmp);
+    SampledLogprobs {
+        logprob: logits[token as usize] - log_sum,
+        top: top
+            .into_iter()
+            .filter(|i| logits[*i] > f32::NEG_INFINITY)
+            .map(|i| (i as Token, logits[i] - log_sum))
+            .collect(),
+    }
+}
+
 pub struct RllmEngine<ME: ModelExec> {
     pub config: Arc<RllmConfig<ME>>,
     pub tokenizer: Arc<Tokenizer>,
@@ -138,6 +172,7 @@ pub struct RllmEngine<ME: ModelExec> {
 
     tim_schedule: TimerRef,
     tim_aici_mid: TimerRef,
+    tim_aici_fork: TimerRef,
     tim_run_model: TimerRef,
 
     pub(crate) tim_model_fwd: TimerRef,
@@ -147,6 +182,7 @@ pub struct RllmEngine<ME: ModelExec> {
     tim_logit_sample: TimerRef,
 
     aicirt: Option<AiciRtIface>,
+    aicirt_epoch: usize,
 
     scheduler: Scheduler<ME>,
     seq_mgr: Arc<ME::SequenceManager>,
@@ -161,9 +197,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
         let model_len = model_meta.max_sequence_length;
 
         let mut aici = args.aici.clone();
-        if aici.max_fuel == 0 {
-            aici.max_fuel = model_len * 10;
-        }
+        aici.max_fuel = aici.max_fuel_for(model_len);
 
         let rllm_config = RllmConfig {
             model: model_config,
@@ -226,9 +260,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
             alt: args.alt,
             scheduler,
             aicirt: None,
+            aicirt_epoch: 0,
             tim_step: timers.new_timer("step"),
             tim_schedule: timers.new_timer("step.schedule"),
             tim_aici_mid: timers.new_timer("step.aici_mid"),
+            tim_aici_fork: timers.new_timer("step.aici_fork"),
             tim_run_model: timers.new_timer("step.run_model"),
             tim_model_fwd: timers.new_timer("step.run_model.model_fwd"),
             tim_sample: timers.new_timer("step.run_model.sample"),
@@ -252,9 +288,29 @@ impl<ME: ModelExec> RllmEngine<ME> {
     }
 
     pub fn set_aicirt(&mut self, aicirt: AiciRtIface) {
+        self.aicirt_epoch = aicirt.epoch();
         self.aicirt = Some(aicirt);
     }
 
+    /// Fail requests with controllers instantiated in aicirt before `epoch`; used when aicirt
+    /// dies and the controller state is lost. Requests without controllers are not affected.
+    fn fail_aici_requests(&self, sched_out: &mut SchedulerOutputs, msg: &str, epoch: usize) {
+        log::warn!("failing controller requests before epoch {epoch}: {msg}");
+        let fail = |sg: &mut SequenceGroup| {
+            if sg.sampling_params.controller.is_none() || sg.aicirt_epoch >= epoch {
+                return;
+            }
+            for seq in sg.seqs.iter_mut() {
+                if !seq.is_finished() {
+                    seq.aici_logs.push(SequenceResult::from_error(format!("\n{msg}\n")));
+                    self.scheduler.finish_seq(seq, FinishReason::Failed);
+                }
+            }
+        };
+        sched_out.next_seq_groups.iter_mut().for_each(&fail);
+        self.scheduler.for_each_sg(&fail);
+    }
+
     pub fn gen_req_id(&mut self) -> String {
         self.req_id_cnt += 1;
         format!("_{}", self.req_id_cnt)
@@ -264,6 +320,33 @@ impl<ME: ModelExec> RllmEngine<ME> {
         self.scheduler.abort_seq_group(request_id);
     }
 
+    pub fn add_request_input(&mut self, request_id: &str, input: RequestInput) -> Result<()> {
+        if let RequestInput::Abort = input {
+            self.abort_request(request_id);
+            return Ok(());
+        }
+        let mut input = Some(input);
+        let mut res = Err(anyhow::anyhow!("request {request_id} is not running"));
+        self.scheduler.for_each_sg(|sg| {
+            if sg.request_id != request_id || sg.is_finished() {
+                return;
+            }
+            res = Ok(());
+            match input.take() {
+                Some(RequestInput::Storage(cmds)) => {
+                    if sg.sampling_params.controller.is_none() {
+                        res = Err(anyhow::anyhow!("request {request_id} has no controller"));
+                    } else {
+                        sg.pending_storage.extend(cmds);
+                    }
+                }
+                Some(RequestInput::Tokens(tokens)) => sg.pending_tokens.extend(tokens),
+                _ => {}
+            }
+        });
+        res
+    }
+
     pub fn num_pending_requests(&self) -> usize {
         self.scheduler.get_num_unfinished_seq_groups()
     }
@@ -277,9 +360,22 @@ impl<ME: ModelExec> RllmEngine<ME> {
     }
 
     pub fn queue_request(&mut self, req: AddRequest) -> Result<()> {
+        if req.sampling_params.controller.is_some() {
+            if let Some(aicirt) = self.aicirt.as_ref() {
+                // aicirt was restarted while the request was on its way here
+                if req.aicirt_epoch != aicirt.epoch() {
+                    bail!("{AICIRT_RESTARTED}");
+                }
+            }
+        }
+
         let mut seq = Sequence::new(self.seq_mgr.new_sequence(), &req.prompt);
+        let mut timing = GroupTiming::new();
         match req.init_result {
-            Some(r) => seq.aici_logs.push(r.clone()),
+            Some(r) => {
+                timing.aici_init_micros = r.micros;
+                seq.aici_logs.push(r.clone())
+            }
             None => {}
         }
         seq.expected = req.expected;
@@ -299,6 +395,10 @@ impl<ME: ModelExec> RllmEngine<ME> {
             logits_processor,
             max_index: 0,
             usage: TokenUsage::default(),
+            timing,
+            pending_storage: Vec::new(),
+            pending_tokens: Vec::new(),
+            aicirt_epoch: req.aicirt_epoch,
         };
 
         self.scheduler.add_seq_group(sg);
@@ -321,6 +421,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             },
             expected: Some(exp_gen),
             init_result: None,
+            aicirt_epoch: self.aicirt.as_ref().map_or(0, |a| a.epoch()),
         })
     }
 
@@ -337,6 +438,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             sampling_params,
             expected: None,
             init_result: None,
+            aicirt_epoch: self.aicirt.as_ref().map_or(0, |a| a.epoch()),
         })
     }
 
@@ -350,7 +452,20 @@ impl<ME: ModelExec> RllmEngine<ME> {
             return Ok((self.tmodel.empty_bias(vocab_size), seq_id_mapping));
         }
 
-        let mid_res = self.aicirt.as_mut().unwrap().finish_mid_process()?;
+        let mid_res = match self.aicirt.as_mut().unwrap().finish_mid_process() {
+            Ok(r) => r,
+            Err(e) => {
+                // everything in this step went to the old process
+                self.fail_aici_requests(sched_out, &e.to_string(), usize::MAX);
+                return Ok((self.tmodel.empty_bias(vocab_size), seq_id_mapping));
+            }
+        };
+
+        self.save_post_process_logs(sched_out, &mid_res);
+
+        if mid_res.fork_micros > 0 {
+            self.tim_aici_fork.add_elapsed(Duration::from_micros(mid_res.fork_micros));
+        }
 
         for sg in sched_out.next_seq_groups.iter_mut() {
             if sg.sampling_params.controller.is_none() {
@@ -362,13 +477,17 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     continue;
                 }
                 assert!(seq.has_aici);
-                match self.save_aici_log(seq, &mid_res.seqs) {
+                match self.save_aici_log(seq, &mut sg.timing, &mid_res.seqs) {
                     Some(resp) => {
                         if resp.branches.is_empty() {
                             self.scheduler.finish_seq(seq, FinishReason::AiciStop);
                             continue;
                         }
                         for (idx, b) in resp.branches.iter().enumerate() {
+                            // every sampling branch has its own row in the bias tensor
+                            if let Some(mask_idx) = b.sample_mask {
+                                assert!(mask_idx < mid_res.num_masks);
+                            }
                             if idx == 0 {
                                 seq.aici_sampling = Some(b.clone());
                                 seq.mid_op = Some(seq.defl_mid_op());
@@ -398,13 +517,20 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
 
         let shm = &self.aicirt.as_mut().unwrap().bin_shm;
-        let slice = shm.slice_at_byte_offset::<f32>(
-            mid_res.first_mask_byte_offset,
-            mid_res.mask_num_elts * mid_res.num_masks,
-        );
+        let off = mid_res.first_mask_byte_offset;
+        let num_elts = mid_res.mask_num_elts * mid_res.num_masks;
+        let data = match mid_res.dtype.as_str() {
+            "f32" => AiciBiasData::F32(shm.slice_at_byte_offset(off, num_elts)),
+            "bool" => AiciBiasData::Bool(shm.slice_at_byte_offset(off, num_elts / 32)),
+            dtype => bail!("unsupported bias dtype from aicirt: {dtype}"),
+        };
         Ok((
-            self.tmodel
-                .new_bias(slice, mid_res.num_masks, mid_res.mask_num_elts),
+            self.tmodel.new_bias(
+                data,
+                mid_res.num_masks,
+                mid_res.mask_num_elts,
+                mid_res.sparse_masks,
+            ),
             seq_id_mapping,
         ))
     }
@@ -490,6 +616,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             with_timer!(self.tim_aici_bias, self.aici_bias(sched_out)?);
 
         for sg in sched_out.next_seq_groups.iter_mut() {
+            let mut used_pending_tokens = false;
             for seq in sg.seqs.iter_mut() {
                 if seq.sched_phase != SchedulingPhase::Running {
                     continue;
@@ -501,8 +628,9 @@ impl<ME: ModelExec> RllmEngine<ME> {
 
                 let mut info = "";
                 let mut sampled = None;
+                let mut logprobs = None;
 
-                let splice = match &seq.aici_sampling {
+                let mut splice = match &seq.aici_sampling {
                     Some(b) if b.sample_mask.is_none() => {
                         assert!(b.splices.len() == 1);
                         let s = &b.splices[0];
@@ -534,6 +662,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
 
                         sampled = Some(next_token);
 
+                        if let (true, Some(k)) = (seq.has_aici, sg.sampling_params.logprobs) {
+                            let logits = ME::tensor_to_vec1(&logits);
+                            logprobs = Some(sampled_logprobs(&logits, next_token, k as usize));
+                        }
+
                         let splices = seq
                             .aici_sampling
                             .as_ref()
@@ -571,6 +704,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     }
                 };
 
+                if !sg.pending_tokens.is_empty() {
+                    splice.ff_tokens.extend_from_slice(&sg.pending_tokens);
+                    used_pending_tokens = true;
+                }
+
                 log::trace!(
                     "sample *{}:{} {} {}",
                     seq.seq_id,
@@ -588,6 +726,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     splice.backtrack as usize,
                     &splice.ff_tokens,
                 );
+                sg.timing.first_token.get_or_insert_with(Instant::now);
 
                 let has_eos = splice.ff_tokens.contains(&self.eos_token_id);
 
@@ -595,6 +734,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     seq.mid_op.as_mut().unwrap().tokens = splice.ff_tokens;
                     seq.mid_op.as_mut().unwrap().backtrack = splice.backtrack;
                     seq.mid_op.as_mut().unwrap().sampled = sampled;
+                    seq.mid_op.as_mut().unwrap().logprobs = logprobs;
                 }
 
                 if !sg.sampling_params.ignore_eos && has_eos {
@@ -604,6 +744,9 @@ impl<ME: ModelExec> RllmEngine<ME> {
                         .finish_seq(seq, FinishReason::MaxTokensReached);
                 }
             }
+            if used_pending_tokens {
+                sg.pending_tokens.clear();
+            }
         }
 
         let mut outputs = self.dropped_outputs(sched_out);
@@ -627,6 +770,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
                 .collect(),
             usage: sg.usage.clone(),
             is_final,
+            timing: if is_final {
+                Some(sg.timing_info())
+            } else {
+                None
+            },
         }
     }
 
@@ -661,9 +809,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
     fn save_aici_log<'a, T>(
         &self,
         seq: &mut Sequence,
+        timing: &mut GroupTiming,
         seqs: &'a HashMap<ModuleInstId, SequenceResult<T>>,
     ) -> Option<&'a T> {
         if let Some(r) = seqs.get(&seq.seq_id.to_num()) {
+            timing.add_aici_result(r);
             seq.aici_logs.push(r.clone_with(None));
             if r.error.len() > 0 {
                 self.scheduler.finish_seq(seq, FinishReason::Failed);
@@ -678,11 +828,42 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
     }
 
+    /// Attach results of controllers' post_process() to sequences that finished in
+    /// the previous step; typically their groups are now being dropped,
+    /// so this ends up in the final output.
+    fn save_post_process_logs(
+        &self,
+        sched_out: &mut SchedulerOutputs,
+        mid_res: &AiciMidProcessResp,
+    ) {
+        if mid_res.finished.is_empty() {
+            return;
+        }
+        for sg in sched_out
+            .dropped_seq_groups
+            .iter_mut()
+            .chain(sched_out.next_seq_groups.iter_mut())
+        {
+            for seq in sg.seqs.iter_mut() {
+                if let Some(r) = mid_res.finished.get(&seq.seq_id.to_num()) {
+                    sg.timing.add_aici_result(r);
+                    seq.aici_logs.push(r.clone());
+                }
+            }
+        }
+    }
+
     fn aici_mid(&mut self, sched_out: &mut SchedulerOutputs) -> Result<()> {
         if self.aicirt.is_none() {
             return Ok(());
         }
 
+        let epoch = self.aicirt.as_ref().unwrap().epoch();
+        if epoch != self.aicirt_epoch {
+            self.aicirt_epoch = epoch;
+            self.fail_aici_requests(sched_out, AICIRT_RESTARTED, epoch);
+        }
+
         let mut mid_ops = Vec::new();
 
         for sg in sched_out.next_seq_groups.iter_mut() {
@@ -690,30 +871,45 @@ impl<ME: ModelExec> RllmEngine<ME> {
                 continue;
             }
 
+            // storage is shared by the group, so only the first sequence gets the commands
+            let mut storage = std::mem::take(&mut sg.pending_storage);
+
             for seq in sg.seqs.iter_mut() {
                 if seq.sched_phase != SchedulingPhase::Running {
                     continue;
                 }
 
-                if seq.has_aici {
-                    mid_ops.push(seq.mid_op.take().unwrap());
+                let mut op = if seq.has_aici {
+                    seq.mid_op.take().unwrap()
                 } else {
                     seq.has_aici = true;
-                    mid_ops.push(AiciMidOp {
+                    AiciMidOp {
                         req_id: Some(sg.request_id.clone()),
                         ..seq.defl_mid_op()
-                    });
-                }
+                    }
+                };
+                op.storage = std::mem::take(&mut storage);
+                mid_ops.push(op);
             }
+
+            // if nothing was running, keep them for later
+            sg.pending_storage = storage;
         }
 
-        self.aicirt
+        let freed = self.scheduler.get_freed_seq_ids();
+        let r = self
+            .aicirt
             .as_mut()
             .unwrap()
             .start_mid_process(AiciMidProcessReq {
                 ops: mid_ops,
-                freed: self.scheduler.get_freed_seq_ids(),
-            })?;
+                freed: freed.iter().map(|(id, _)| *id).collect(),
+                finish_reasons: freed.iter().map(|(id, r)| (*id, r.to_aici())).collect(),
+            });
+        if let Err(e) = r {
+            // finish_mid_process() will also fail, and fail the requests then
+            log::warn!("start_mid_process: {e}");
+        }
 
         Ok(())
     }
@@ -755,7 +951,13 @@ impl<ME: ModelExec> RllmEngine<ME> {
             sched_out.next_seq_groups.len(),
             sched_out.dropped_seq_groups.len()
         );
+        let step_start = Instant::now();
         let outputs = with_timer!(self.tim_run_model, self.run_model(&mut sched_out));
+        let step_time = step_start.elapsed();
+        for sg in sched_out.next_seq_groups.iter_mut() {
+            sg.timing.num_steps += 1;
+            sg.timing.step_time += step_time;
+        }
         // we run step_finished() regardless if model failed
         self.scheduler.step_finished(sched_out);
 
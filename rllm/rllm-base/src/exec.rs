@@ -1,12 +1,12 @@
 use std::{fmt::Display, sync::Arc};
 
-use aicirt::TimerRef;
+use aicirt::{api::BiasType, TimerRef};
 use anyhow::Result;
 
 use crate::{
     config::{ModelMeta, RllmConfig},
     scheduler::SchedulerOutputs,
-    seq::{Sequence, SequenceGroup},
+    seq::{Sequence, SequenceGroup, Token},
     HashMap, LoaderArgs, LogitsProcessor, RllmEngine,
 };
 
@@ -16,6 +16,15 @@ pub enum BlockLocation {
     CPU,
 }
 
+/// Masks from aicirt, as consecutive rows, one per mask index.
+#[derive(Debug, Clone, Copy)]
+pub enum AiciBiasData {
+    /// One additive bias per token.
+    F32(&'static [f32]),
+    /// Bitsets of allowed tokens; token `i` is allowed if bit `i % 32` of word `i / 32` is set.
+    Bool(&'static [u32]),
+}
+
 pub trait AiciBias<T> {
     fn apply(&self, logits: &mut T, seq_id: usize);
 }
@@ -75,8 +84,17 @@ pub trait ModelExec: Sized {
     fn finalize_run(&mut self) -> Result<()>;
 
     fn empty_bias(&self, vocab_size: usize) -> Self::AiciBias;
-    fn new_bias(&self, slice: &'static [f32], num_seqs: usize, vocab_size: usize)
-        -> Self::AiciBias;
+    /// Format of masks requested from aicirt.
+    fn bias_type() -> BiasType;
+    /// `data` holds `num_seqs` rows of `vocab_size` elements; rows of masks in `sparse`
+    /// were not written, and only the listed tokens are allowed for them.
+    fn new_bias(
+        &self,
+        data: AiciBiasData,
+        num_seqs: usize,
+        vocab_size: usize,
+        sparse: HashMap<usize, Vec<Token>>,
+    ) -> Self::AiciBias;
 
     fn sample(&self, processor: &mut LogitsProcessor, logits: &Self::Tensor) -> Result<u32>;
 }
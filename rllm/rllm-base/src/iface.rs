@@ -6,34 +6,117 @@ use aici_abi::{
 };
 use aicirt::{
     api::{
-        AiciMidProcessReq, AiciMidProcessResp, AuthInfo, GetTagsResp, InstantiateReq, MkModuleReq,
-        MkModuleResp, SequenceResult, SetTagsReq, TokensResp,
+        decode_bin, encode_bin, AiciMidProcessReq, AiciMidProcessResp, AuthInfo, GetTagsResp,
+        InstantiateReq, MkModuleReq, MkModuleResp, SequenceResult, SetTagsReq, TokensResp,
+        BIN_MSG_MAGIC,
     },
     futexshm::ClientChannel,
     msgchannel::MessageChannel,
     shm::{Shm, Unlink},
+    sockchannel::{self, SockStream},
     user_error,
 };
-use anyhow::Result;
+use anyhow::{ensure, Result};
+use base64::Engine as _;
 use futures::future::select_all;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
     process::{Child, Command},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
     time::Duration,
 };
 use tokio::{signal::unix::SignalKind, sync::oneshot};
 
+/// Sending half of a channel to aicirt: shared memory, or a socket when aicirt runs
+/// elsewhere (aicirt --listen).
+enum ReqSender {
+    Shm(ClientChannel),
+    Socket(SockStream),
+}
+
+impl ReqSender {
+    fn send_req(&mut self, msg: &[u8]) -> Result<()> {
+        match self {
+            ReqSender::Shm(ch) => ch.send_req(msg),
+            ReqSender::Socket(s) => s.send_msg(msg),
+        }
+    }
+}
+
+/// Receiving half of a channel to aicirt.
+enum RespReceiver {
+    Shm(ClientChannel),
+    Socket(SockStream, usize),
+}
+
+impl RespReceiver {
+    /// None on timeout; sockets block until a message arrives, and fail when closed.
+    fn recv_resp(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        match self {
+            RespReceiver::Shm(ch) => Ok(ch.recv_resp(timeout)),
+            RespReceiver::Socket(s, max_size) => Ok(Some(s.recv_msg(*max_size)?)),
+        }
+    }
+
+    fn recv_resp2(&mut self, busy: Duration, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        match self {
+            RespReceiver::Shm(ch) => Ok(ch.recv_resp2(busy, timeout)),
+            RespReceiver::Socket(s, max_size) => Ok(Some(s.recv_msg(*max_size)?)),
+        }
+    }
+}
+
 pub struct CmdChannel {
     cmd_pending: bool,
-    cmd_ch: ClientChannel,
-    resp_ch: ClientChannel,
+    /// Send mid_process in binary encoding; set when aicirt supports it.
+    bin_encoding: bool,
+    cmd_ch: ReqSender,
+    resp_ch: RespReceiver,
     #[allow(dead_code)]
     busy_wait_duration: Duration,
+    state: Arc<AiciRtState>,
 }
 
+/// Shared between the engine, the server, and the thread supervising the aicirt process.
+pub struct AiciRtState {
+    pid: AtomicI32,
+    /// Incremented every time aicirt is restarted.
+    epoch: AtomicUsize,
+    /// Set when aicirt exited and was not yet restarted.
+    dead: AtomicBool,
+    /// Set when we're killing aicirt on purpose; no restarts then.
+    shutdown: AtomicBool,
+}
+
+impl AiciRtState {
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    fn set_dead(&self) {
+        self.dead.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::SeqCst)
+    }
+
+    fn kill_child(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        let pid = self.pid.load(Ordering::SeqCst);
+        unsafe {
+            libc::kill(-pid, libc::SIGTERM);
+        }
+    }
+}
+
+const CRASH_MSG: &str = "aicirt process died; the request was aborted";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Empty {}
 
@@ -44,18 +127,51 @@ fn build_ch(name: &str, json_size: usize) -> Result<ClientChannel> {
     Ok(ClientChannel::new(shm))
 }
 
+fn connect_ch(addr: &str, token: &str, json_size: usize) -> Result<(ReqSender, RespReceiver)> {
+    let mut conn = SockStream::connect(addr)
+        .map_err(|e| anyhow::anyhow!("can't connect to aicirt at {addr}: {e}"))?;
+    conn.client_auth(token).map_err(|e| anyhow::anyhow!("aicirt at {addr}: {e}"))?;
+    Ok((
+        ReqSender::Socket(conn.try_clone()?),
+        RespReceiver::Socket(conn, json_size * M),
+    ))
+}
+
 impl CmdChannel {
     pub fn new(
         json_size: usize,
         pref: &str,
         suff: &str,
         busy_wait_duration: Duration,
+        state: Arc<AiciRtState>,
     ) -> Result<Self> {
         Ok(Self {
             cmd_pending: false,
-            cmd_ch: build_ch(&format!("{}cmd{}", pref, suff), json_size)?,
-            resp_ch: build_ch(&format!("{}resp{}", pref, suff), json_size)?,
+            bin_encoding: false,
+            cmd_ch: ReqSender::Shm(build_ch(&format!("{}cmd{}", pref, suff), json_size)?),
+            resp_ch: RespReceiver::Shm(build_ch(&format!("{}resp{}", pref, suff), json_size)?),
             busy_wait_duration,
+            state,
+        })
+    }
+
+    /// Connect to aicirt started with `--listen`; `suff` selects the side channel.
+    pub fn connect(
+        addr: &str,
+        token: &str,
+        suff: &str,
+        json_size: usize,
+        state: Arc<AiciRtState>,
+    ) -> Result<Self> {
+        let (cmd_ch, resp_ch) =
+            connect_ch(&sockchannel::side_addr(addr, suff)?, token, json_size)?;
+        Ok(Self {
+            cmd_pending: false,
+            bin_encoding: false,
+            cmd_ch,
+            resp_ch,
+            busy_wait_duration: Duration::ZERO,
+            state,
         })
     }
 
@@ -81,18 +197,59 @@ impl CmdChannel {
         self.send_bytes(&bytes)
     }
 
+    fn recv_bytes(&mut self, ctx: &str) -> Result<Vec<u8>> {
+        assert!(self.cmd_pending);
+        let bytes = loop {
+            // spin, but check every now and then if aicirt is still there
+            match self.resp_ch.recv_resp(Duration::from_millis(20)) {
+                Ok(Some(bytes)) => break bytes,
+                Ok(None) => {}
+                Err(e) => {
+                    // only with sockets; remote aicirt is not restarted
+                    log::error!("aicirt connection lost: {e}");
+                    self.state.set_dead();
+                }
+            }
+            if self.state.is_dead() {
+                self.cmd_pending = false;
+                anyhow::bail!("{CRASH_MSG} ({ctx})");
+            }
+        };
+        self.cmd_pending = false;
+        Ok(bytes)
+    }
+
     pub fn expect<R>(&mut self, ctx: &str) -> Result<R>
     where
         R: for<'d> Deserialize<'d>,
     {
-        assert!(self.cmd_pending);
-        let bytes = self.resp_ch.recv_resp(Duration::MAX).unwrap();
-        self.cmd_pending = false;
-        let mut resp: Value = serde_json::from_slice(&bytes)?;
+        let bytes = self.recv_bytes(ctx)?;
+        Self::parse_resp(ctx, &bytes)
+    }
+
+    /// Like expect(), but the response can be in binary encoding.
+    pub fn expect_bin<R>(&mut self, ctx: &str) -> Result<R>
+    where
+        R: for<'d> Deserialize<'d>,
+    {
+        let bytes = self.recv_bytes(ctx)?;
+        if bytes.first() == Some(&BIN_MSG_MAGIC) {
+            decode_bin(&bytes).map_err(|e| anyhow::anyhow!("Bad response ({ctx}): {e}"))
+        } else {
+            // errors are always JSON
+            Self::parse_resp(ctx, &bytes)
+        }
+    }
+
+    fn parse_resp<R>(ctx: &str, bytes: &[u8]) -> Result<R>
+    where
+        R: for<'d> Deserialize<'d>,
+    {
+        let mut resp: Value = serde_json::from_slice(bytes)?;
         if resp["type"] != "ok" {
             return Err(anyhow::anyhow!(
                 "Bad response ({ctx}): {}",
-                limit_bytes(&bytes, 500)
+                limit_bytes(bytes, 500)
             ));
         }
         let data = resp
@@ -102,30 +259,41 @@ impl CmdChannel {
             .ok_or_else(|| {
                 anyhow::anyhow!(
                     "Bad response ({ctx}) - no 'data': {}",
-                    limit_bytes(&bytes, 500)
+                    limit_bytes(bytes, 500)
                 )
             })?;
         let resp = serde_json::from_value(data).map_err(|e| {
-            anyhow::anyhow!("Bad response ({ctx}): {e} {}", limit_bytes(&bytes, 500))
+            anyhow::anyhow!("Bad response ({ctx}): {e} {}", limit_bytes(bytes, 500))
         })?;
         Ok(resp)
     }
 }
 
 pub struct AiciRtIface {
-    cmd: CmdChannel,
+    cmd: Arc<Mutex<CmdChannel>>,
     pub pending_mid_size: usize,
+    pending_epoch: usize,
     pub bin_shm: Shm,
     pub side_cmd: AsyncCmdChannel,
-    #[allow(dead_code)]
-    child: Child,
+    state: Arc<AiciRtState>,
+    /// aicirt is remote, and sends masks in the mid_process response.
+    inline_masks: bool,
 }
 
+#[derive(Clone)]
 pub struct Args {
     pub aicirt: String,
+    /// Connect to aicirt listening at this address (unix:PATH or tcp:HOST:PORT),
+    /// instead of spawning it.
+    pub aicirt_addr: Option<String>,
+    /// Shared secret sent when connecting to aicirt_addr.
+    pub aicirt_token: Option<String>,
     pub tokenizer: String,
     pub json_size: usize,
     pub bin_size: usize,
+    pub sparse_mask_max_tokens: usize,
+    /// Format of masks in the binary shm, as preferred by the model backend.
+    pub bias_dtype: String,
     pub shm_prefix: String,
     pub busy_wait_time: u64,
     pub add_args: Vec<String>,
@@ -137,45 +305,185 @@ pub fn kill_self() {
     }
 }
 
+fn spawn_aicirt(args: &Args) -> Result<Child> {
+    let mut cmd_bld = Command::new(&args.aicirt);
+    cmd_bld
+        .arg("--tokenizer")
+        .arg(&args.tokenizer)
+        .arg("--json-size")
+        .arg(&args.json_size.to_string())
+        .arg("--bin-size")
+        .arg(&args.bin_size.to_string())
+        .arg("--sparse-mask-max-tokens")
+        .arg(&args.sparse_mask_max_tokens.to_string())
+        .arg("--bias-dtype")
+        .arg(&args.bias_dtype)
+        .arg("--name")
+        .arg(&args.shm_prefix)
+        .arg("--futex")
+        .arg("--cap-fork")
+        .arg("--cap-ff-tokens")
+        .arg("--cap-backtrack");
+    for a in &args.add_args {
+        cmd_bld.arg(a);
+    }
+    let child = cmd_bld.arg("--server").spawn()?;
+    Ok(child)
+}
+
+fn check_aicirt(cmd: &mut CmdChannel, vocab_size: u32) -> Result<()> {
+    let _: Value = cmd.exec("ping", json!({}))?;
+
+    let tokens: TokensResp = cmd
+        .exec("tokens", json!({}))
+        .map_err(|e| anyhow::anyhow!("check for pending aicirt processes! {e}"))?;
+
+    // well, this is somewhat unlikely as we're passing the same tokenizer name down...
+    if tokens.vocab_size != vocab_size {
+        return Err(anyhow::anyhow!(
+            "Vocab size mismatch: {:?} != {:?}",
+            tokens,
+            vocab_size
+        ));
+    }
+
+    // older aicirt doesn't know the op, and we stay with JSON
+    let enc: Result<Value> = cmd.exec("set_encoding", json!({ "encoding": "bincode" }));
+    cmd.bin_encoding = enc.is_ok();
+    log::debug!("aicirt mid_process encoding: bincode={}", cmd.bin_encoding);
+
+    Ok(())
+}
+
+fn watch_aicirt(mut child: Child, state: Arc<AiciRtState>, exits: mpsc::Sender<libc::c_int>) {
+    let pid = child.id() as libc::c_int;
+    thread::spawn(move || {
+        let status = child.wait();
+        if state.pid.load(Ordering::SeqCst) == pid {
+            state.dead.store(true, Ordering::SeqCst);
+            if !state.shutdown.load(Ordering::SeqCst) {
+                log::error!("aicirt (pid={pid}) exited unexpectedly: {status:?}");
+            }
+        }
+        let _ = exits.send(pid);
+    });
+}
+
+fn restart_aicirt(
+    args: &Args,
+    vocab_size: u32,
+    cmd: &Mutex<CmdChannel>,
+    side_cmd: &AsyncCmdChannel,
+    state: &Arc<AiciRtState>,
+    exits: &mpsc::Sender<libc::c_int>,
+) -> Result<()> {
+    let busy_wait_time = Duration::from_millis(args.busy_wait_time);
+    let mut cmd = cmd.lock().unwrap();
+    // aicirt unlinks the channels on start, so we need to create them again
+    *cmd = CmdChannel::new(
+        args.json_size,
+        &args.shm_prefix,
+        "",
+        busy_wait_time,
+        state.clone(),
+    )?;
+    side_cmd.reconnect(args.json_size, &args.shm_prefix, "-side")?;
+    let child = spawn_aicirt(args)?;
+    let pid = child.id() as libc::c_int;
+    state.pid.store(pid, Ordering::SeqCst);
+    state.dead.store(false, Ordering::SeqCst);
+    watch_aicirt(child, state.clone(), exits.clone());
+    if let Err(e) = check_aicirt(&mut cmd, vocab_size) {
+        state.set_dead();
+        unsafe {
+            libc::kill(-pid, libc::SIGTERM);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Restarts aicirt (with the same arguments and shm names) whenever it exits,
+/// unless we're shutting down.
+fn supervise_aicirt(
+    args: Args,
+    vocab_size: u32,
+    cmd: Arc<Mutex<CmdChannel>>,
+    side_cmd: AsyncCmdChannel,
+    state: Arc<AiciRtState>,
+    exits_tx: mpsc::Sender<libc::c_int>,
+    exits: mpsc::Receiver<libc::c_int>,
+) {
+    while let Ok(pid) = exits.recv() {
+        if state.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        if pid != state.pid.load(Ordering::SeqCst) {
+            // child from a failed restart attempt
+            continue;
+        }
+        side_cmd.fail_pending(CRASH_MSG);
+
+        let mut delay = Duration::from_millis(100);
+        while let Err(e) = restart_aicirt(&args, vocab_size, &cmd, &side_cmd, &state, &exits_tx) {
+            if state.shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            log::error!("failed to restart aicirt: {e}; retrying in {delay:?}");
+            thread::sleep(delay);
+            delay = std::cmp::min(delay * 2, Duration::from_secs(10));
+        }
+
+        // anything sent to the old process is lost
+        side_cmd.fail_pending(CRASH_MSG);
+        state.epoch.fetch_add(1, Ordering::SeqCst);
+        log::info!("aicirt restarted, pid={}", state.pid.load(Ordering::SeqCst));
+    }
+}
+
+fn new_state() -> Arc<AiciRtState> {
+    Arc::new(AiciRtState {
+        pid: AtomicI32::new(0),
+        epoch: AtomicUsize::new(0),
+        dead: AtomicBool::new(false),
+        shutdown: AtomicBool::new(false),
+    })
+}
+
 impl AiciRtIface {
     pub fn start_aicirt(args: &Args, tok_trie: &TokTrie) -> Result<Self> {
+        if let Some(addr) = &args.aicirt_addr {
+            return Self::connect_aicirt(addr, args, tok_trie);
+        }
+
         let busy_wait_time = Duration::from_millis(args.busy_wait_time);
         let shm_name = MessageChannel::shm_name(&(args.shm_prefix.clone() + "bin"));
-        let cmd = CmdChannel::new(args.json_size, &args.shm_prefix, "", busy_wait_time)?;
-        let side_cmd = AsyncCmdChannel::new(args.json_size, &args.shm_prefix, "-side")?;
+        let state = new_state();
+        let cmd = CmdChannel::new(
+            args.json_size,
+            &args.shm_prefix,
+            "",
+            busy_wait_time,
+            state.clone(),
+        )?;
+        let side_cmd =
+            AsyncCmdChannel::new(args.json_size, &args.shm_prefix, "-side", state.clone())?;
         let bin_shm = Shm::new(&shm_name, args.bin_size * M, Unlink::Pre)?;
 
-        let mut cmd_bld = Command::new(&args.aicirt);
-        cmd_bld
-            .arg("--tokenizer")
-            .arg(&args.tokenizer)
-            .arg("--json-size")
-            .arg(&args.json_size.to_string())
-            .arg("--bin-size")
-            .arg(&args.bin_size.to_string())
-            .arg("--name")
-            .arg(&args.shm_prefix)
-            .arg("--futex")
-            .arg("--cap-fork")
-            .arg("--cap-ff-tokens")
-            .arg("--cap-backtrack");
-        for a in &args.add_args {
-            cmd_bld.arg(a);
-        }
-        let child = cmd_bld.arg("--server").spawn()?;
+        let child = spawn_aicirt(args)?;
+        state.pid.store(child.id() as libc::c_int, Ordering::SeqCst);
 
-        let pid = child.id() as libc::c_int;
         let default_panic_hook = std::panic::take_hook();
 
+        let state2 = state.clone();
         std::panic::set_hook(Box::new(move |panic_info| {
-            eprintln!("killing {pid}");
-            unsafe {
-                libc::kill(-pid, libc::SIGTERM);
-            }
+            eprintln!("killing {}", state2.pid.load(Ordering::SeqCst));
+            state2.kill_child();
             default_panic_hook(panic_info);
             std::process::exit(100);
         }));
 
+        let state2 = state.clone();
         let _killer = tokio::spawn(async move {
             let sigs = vec![
                 SignalKind::interrupt(),
@@ -193,50 +501,125 @@ impl AiciRtIface {
                 let pinned_futures: Vec<_> = futures.into_iter().map(|f| Box::pin(f)).collect();
                 select_all(pinned_futures).await;
                 log::info!("Killing child process");
-                unsafe {
-                    libc::kill(-pid, libc::SIGTERM);
-                }
+                state2.kill_child();
             }
         });
 
-        let mut r = Self {
+        let (exits_tx, exits) = mpsc::channel();
+        watch_aicirt(child, state.clone(), exits_tx.clone());
+
+        let mut cmd = cmd;
+        let vocab_size = tok_trie.info().vocab_size;
+        check_aicirt(&mut cmd, vocab_size)?;
+
+        let cmd = Arc::new(Mutex::new(cmd));
+        {
+            let args = args.clone();
+            let cmd = cmd.clone();
+            let side_cmd = side_cmd.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                supervise_aicirt(args, vocab_size, cmd, side_cmd, state, exits_tx, exits)
+            });
+        }
+
+        Ok(Self {
             cmd,
             side_cmd,
             bin_shm,
-            child,
+            state,
             pending_mid_size: usize::MAX,
-        };
+            pending_epoch: 0,
+            inline_masks: false,
+        })
+    }
 
-        let _: Value = r.cmd.exec("ping", json!({}))?;
+    /// Use aicirt started elsewhere with `--listen`; it's not supervised or restarted,
+    /// and if the connection is lost, requests with controllers fail.
+    fn connect_aicirt(addr: &str, args: &Args, tok_trie: &TokTrie) -> Result<Self> {
+        let state = new_state();
+        let token = args.aicirt_token.as_deref().unwrap_or_default();
+        let mut cmd = CmdChannel::connect(addr, token, "", args.json_size, state.clone())?;
+        check_aicirt(&mut cmd, tok_trie.info().vocab_size)?;
+        let side_cmd = AsyncCmdChannel::connect(addr, token, args.json_size, state.clone())?;
+        // masks from the responses are unpacked here
+        let bin_shm = Shm::anon(args.bin_size * M)?;
+        log::info!("connected to aicirt at {addr}");
 
-        let tokens: TokensResp = r
-            .cmd
-            .exec("tokens", json!({}))
-            .map_err(|e| anyhow::anyhow!("check for pending aicirt processes! {e}"))?;
+        Ok(Self {
+            cmd: Arc::new(Mutex::new(cmd)),
+            side_cmd,
+            bin_shm,
+            state,
+            pending_mid_size: usize::MAX,
+            pending_epoch: 0,
+            inline_masks: true,
+        })
+    }
 
-        // well, this is somewhat unlikely as we're passing the same tokenizer name down...
-        if tokens.vocab_size != tok_trie.info().vocab_size {
-            return Err(anyhow::anyhow!(
-                "Vocab size mismatch: {:?} != {:?}",
-                tokens,
-                tok_trie.info()
-            ));
+    /// Decode the bitsets sent by remote aicirt into `bin_shm`, as bool masks, so that
+    /// the response can be used as if aicirt had written them there.
+    fn unpack_masks(&self, r: &mut AiciMidProcessResp) -> Result<()> {
+        let masks = std::mem::take(&mut r.masks)
+            .iter()
+            .map(|m| base64::engine::general_purpose::STANDARD.decode(m))
+            .collect::<Result<Vec<_>, _>>()?;
+        let row_words = masks.first().map_or(0, |m| (m.len() + 3) / 4);
+        ensure!(
+            row_words * 4 * masks.len() <= self.bin_shm.len(),
+            "masks from aicirt don't fit in --bin-size"
+        );
+        let words: &mut [u32] = self.bin_shm.slice_at_byte_offset(0, row_words * masks.len());
+        words.fill(0);
+        for (row, mask) in masks.iter().enumerate() {
+            ensure!(mask.len() <= row_words * 4, "mask size mismatch");
+            let dst = &mut words[row * row_words..(row + 1) * row_words];
+            for (i, b) in mask.iter().enumerate() {
+                dst[i / 4] |= (*b as u32) << (8 * (i % 4));
+            }
         }
+        r.dtype = "bool".to_string();
+        r.first_mask_byte_offset = 0;
+        r.num_masks = masks.len();
+        r.mask_num_bytes = row_words * 4;
+        r.mask_num_elts = row_words * 32;
+        Ok(())
+    }
 
-        Ok(r)
+    /// Changes every time aicirt is restarted; all controller state is lost then.
+    pub fn epoch(&self) -> usize {
+        self.state.epoch()
     }
 
     pub fn start_mid_process(&mut self, req: AiciMidProcessReq) -> Result<()> {
         assert!(self.pending_mid_size == usize::MAX);
+        let mut cmd = self.cmd.lock().unwrap();
+        if self.state.is_dead() {
+            anyhow::bail!("{CRASH_MSG}");
+        }
+        self.pending_epoch = self.state.epoch();
         self.pending_mid_size = req.ops.len();
-        self.cmd.send("mid_process", req)
+        if cmd.bin_encoding {
+            cmd.send_bytes(&encode_bin(&req))
+        } else {
+            cmd.send("mid_process", req)
+        }
     }
 
     pub fn finish_mid_process(&mut self) -> Result<AiciMidProcessResp> {
-        assert!(self.pending_mid_size < usize::MAX);
-        let r: AiciMidProcessResp = self.cmd.expect("async:mid_process")?;
-        // assert!(r.num_seqs == self.pending_mid_size);
+        if self.pending_mid_size == usize::MAX {
+            anyhow::bail!("{CRASH_MSG}");
+        }
         self.pending_mid_size = usize::MAX;
+        let mut cmd = self.cmd.lock().unwrap();
+        if self.pending_epoch != self.state.epoch() {
+            anyhow::bail!("{CRASH_MSG}");
+        }
+        let mut r: AiciMidProcessResp = cmd.expect_bin("async:mid_process")?;
+        if self.inline_masks {
+            self.unpack_masks(&mut r)?;
+        }
+        // assert!(r.num_seqs == self.pending_mid_size);
         Ok(r)
     }
 }
@@ -244,31 +627,93 @@ impl AiciRtIface {
 #[derive(Clone)]
 pub struct AsyncCmdChannel {
     pending_reqs: Arc<Mutex<HashMap<String, oneshot::Sender<Value>>>>,
-    cmd_ch: Arc<Mutex<ClientChannel>>,
+    cmd_ch: Arc<Mutex<ReqSender>>,
+    resp_ch: Arc<Mutex<RespReceiver>>,
+    state: Arc<AiciRtState>,
 }
 
 impl AsyncCmdChannel {
-    pub fn new(json_size: usize, pref: &str, suff: &str) -> Result<Self> {
-        let cmd = CmdChannel::new(json_size, pref, suff, Duration::ZERO)?;
-        let pending_reqs = Arc::new(Mutex::new(
-            HashMap::<String, oneshot::Sender<Value>>::default(),
-        ));
-        {
-            let mut resp_ch = cmd.resp_ch;
-            let pending_reqs = pending_reqs.clone();
-            thread::spawn(move || loop {
-                let resp = resp_ch.recv_resp2(Duration::ZERO, Duration::MAX).unwrap();
-                let resp: Value = serde_json::from_slice(&resp).unwrap();
-                let rid = resp["$rid"].as_str().unwrap().to_string();
-                let tx = pending_reqs.lock().unwrap().remove(&rid).unwrap();
-                tx.send(resp).unwrap();
-            });
-        }
+    pub fn new(
+        json_size: usize,
+        pref: &str,
+        suff: &str,
+        state: Arc<AiciRtState>,
+    ) -> Result<Self> {
+        let cmd = CmdChannel::new(json_size, pref, suff, Duration::ZERO, state.clone())?;
+        Ok(Self::from_cmd(cmd, state))
+    }
 
-        Ok(Self {
-            pending_reqs,
+    /// Side channel of aicirt started with `--listen`.
+    pub fn connect(
+        addr: &str,
+        token: &str,
+        json_size: usize,
+        state: Arc<AiciRtState>,
+    ) -> Result<Self> {
+        let cmd = CmdChannel::connect(addr, token, "-side", json_size, state.clone())?;
+        Ok(Self::from_cmd(cmd, state))
+    }
+
+    fn from_cmd(cmd: CmdChannel, state: Arc<AiciRtState>) -> Self {
+        let ch = Self {
+            pending_reqs: Arc::new(Mutex::new(HashMap::default())),
             cmd_ch: Arc::new(Mutex::new(cmd.cmd_ch)),
-        })
+            resp_ch: Arc::new(Mutex::new(cmd.resp_ch)),
+            state,
+        };
+        let ch2 = ch.clone();
+        thread::spawn(move || ch2.recv_loop());
+        ch
+    }
+
+    fn recv_loop(&self) {
+        loop {
+            // the timeout is so that reconnect() can replace the channel
+            let resp = self
+                .resp_ch
+                .lock()
+                .unwrap()
+                .recv_resp2(Duration::ZERO, Duration::from_millis(200));
+            let resp = match resp {
+                Ok(Some(r)) => r,
+                Ok(None) => continue,
+                Err(e) => {
+                    // only with sockets; remote aicirt is not restarted
+                    log::error!("aicirt side connection lost: {e}");
+                    self.state.set_dead();
+                    self.fail_pending(CRASH_MSG);
+                    return;
+                }
+            };
+            let resp: Value = serde_json::from_slice(&resp).unwrap();
+            let rid = resp["$rid"].as_str().unwrap().to_string();
+            match self.pending_reqs.lock().unwrap().remove(&rid) {
+                Some(tx) => {
+                    let _ = tx.send(resp);
+                }
+                None => log::warn!("response for unknown request {rid}"),
+            }
+        }
+    }
+
+    fn reconnect(&self, json_size: usize, pref: &str, suff: &str) -> Result<()> {
+        let mut cmd_ch = self.cmd_ch.lock().unwrap();
+        let mut resp_ch = self.resp_ch.lock().unwrap();
+        *cmd_ch = ReqSender::Shm(build_ch(&format!("{}cmd{}", pref, suff), json_size)?);
+        *resp_ch = RespReceiver::Shm(build_ch(&format!("{}resp{}", pref, suff), json_size)?);
+        Ok(())
+    }
+
+    /// Epoch of the aicirt process the next command goes to; see [`AiciRtIface::epoch`].
+    pub fn epoch(&self) -> usize {
+        self.state.epoch()
+    }
+
+    fn fail_pending(&self, msg: &str) {
+        let pending = std::mem::take(&mut *self.pending_reqs.lock().unwrap());
+        for (_, tx) in pending {
+            let _ = tx.send(json!({ "type": "error", "error": msg }));
+        }
     }
 
     pub async fn set_tags(&self, req: SetTagsReq, authinfo: AuthInfo) -> Result<GetTagsResp> {
@@ -302,12 +747,15 @@ impl AsyncCmdChannel {
         data["$auth"] = serde_json::to_value(authinfo)?;
 
         let (tx, rx) = oneshot::channel();
-        self.pending_reqs.lock().unwrap().insert(rid.clone(), tx);
 
-        self.cmd_ch
-            .lock()
-            .unwrap()
-            .send_req(&serde_json::to_vec(&data)?)?;
+        {
+            let mut cmd_ch = self.cmd_ch.lock().unwrap();
+            if self.state.is_dead() {
+                anyhow::bail!("aicirt process died; it is being restarted");
+            }
+            self.pending_reqs.lock().unwrap().insert(rid.clone(), tx);
+            cmd_ch.send_req(&serde_json::to_vec(&data)?)?;
+        }
 
         let mut resp = rx.await?;
 
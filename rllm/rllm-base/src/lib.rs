@@ -1,6 +1,7 @@
 pub mod seq;
 
 // vllm modules
+pub mod cfgfile;
 pub mod config;
 mod engine;
 mod exec;
@@ -97,7 +97,7 @@ pub struct Scheduler<ME: ModelExec> {
     pub(crate) config: Arc<RllmConfig<ME>>,
     prompt_limit: usize,
     pub(crate) block_manager: ME::BlockSpaceManager,
-    freed_seq_ids: RefCell<Vec<usize>>,
+    freed_seq_ids: RefCell<Vec<(usize, FinishReason)>>,
     seq_mgr: Arc<ME::SequenceManager>,
 
     queues: Mutex<Vec<Vec<SequenceGroup>>>,
@@ -168,7 +168,7 @@ impl<ME: ModelExec> Scheduler<ME> {
         }
     }
 
-    pub(crate) fn get_freed_seq_ids(&self) -> Vec<usize> {
+    pub(crate) fn get_freed_seq_ids(&self) -> Vec<(usize, FinishReason)> {
         self.freed_seq_ids.borrow_mut().drain(..).collect()
     }
 
@@ -339,6 +339,10 @@ impl<ME: ModelExec> Scheduler<ME> {
     fn _allocate(&mut self, seq_group: &mut SequenceGroup) {
         self.block_manager.allocate(seq_group);
         self.set_phase(seq_group, SchedulingPhase::Running);
+        seq_group
+            .timing
+            .scheduled
+            .get_or_insert_with(std::time::Instant::now);
     }
 
     fn _append_slots(&mut self, seq_group: &mut SequenceGroup, outputs: &mut SchedulerOutputs) {
@@ -453,7 +457,9 @@ impl<ME: ModelExec> Scheduler<ME> {
             )))
         }
         seq.sched_phase = SchedulingPhase::Finished(reason);
-        self.freed_seq_ids.borrow_mut().push(seq.seq_id.to_num());
+        self.freed_seq_ids
+            .borrow_mut()
+            .push((seq.seq_id.to_num(), reason));
         self.seq_mgr.delete(seq.seq_id);
     }
 
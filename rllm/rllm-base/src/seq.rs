@@ -1,10 +1,13 @@
 use crate::{
     config::SamplingParams, engine::ExpectedGeneration, LogitsProcessor, SeqId, SequenceManager,
 };
-use aici_abi::{toktrie::TokTrie, Branch, TokenId};
+use aici_abi::{toktrie::TokTrie, Branch, StorageCmd, TokenId};
 use aicirt::api::{AiciMidOp, SequenceResult};
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant, SystemTime},
+};
 
 pub type Token = u32;
 
@@ -39,6 +42,18 @@ impl FinishReason {
         };
         r.to_string()
     }
+
+    /// Reason passed to the controller's post_process().
+    pub fn to_aici(&self) -> aici_abi::FinishReason {
+        match self {
+            FinishReason::FoundEos => aici_abi::FinishReason::Eos,
+            FinishReason::MaxTokensReached => aici_abi::FinishReason::MaxTokens,
+            FinishReason::Aborted => aici_abi::FinishReason::Aborted,
+            FinishReason::AiciStop => aici_abi::FinishReason::Stopped,
+            FinishReason::AiciOutOfFuel => aici_abi::FinishReason::OutOfFuel,
+            FinishReason::Failed | FinishReason::Deadlock => aici_abi::FinishReason::Failed,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -135,8 +150,10 @@ impl Sequence {
             clone_idx: None,
             req_id: None,
             sampled: None,
+            logprobs: None,
             backtrack: 0,
             tokens: vec![],
+            storage: vec![],
         }
     }
 
@@ -258,6 +275,13 @@ pub struct SequenceGroup {
     pub logits_processor: LogitsProcessor,
     pub max_index: usize,
     pub usage: TokenUsage,
+    pub timing: GroupTiming,
+    /// Storage commands from the client, to be executed before the next controller step.
+    pub pending_storage: Vec<StorageCmd>,
+    /// Tokens from the client, to be appended to all sequences in the next step.
+    pub pending_tokens: Vec<Token>,
+    /// Epoch of the aicirt process the controller was instantiated in.
+    pub aicirt_epoch: usize,
 }
 
 impl Debug for SequenceGroup {
@@ -326,6 +350,94 @@ impl SequenceGroup {
             .iter()
             .all(|seq| seq.sched_phase == SchedulingPhase::Suspended || seq.is_finished())
     }
+
+    pub fn timing_info(&self) -> RequestTiming {
+        let t = &self.timing;
+        let now = Instant::now();
+        let at_ms = |i: Instant| t.arrival_unix_ms + (i - self.arrival_time).as_millis() as u64;
+        let micros = |a: Instant, b: Instant| b.saturating_duration_since(a).as_micros() as u64;
+        let scheduled = t.scheduled.unwrap_or(now);
+        let first_token = t.first_token.unwrap_or(now);
+        RequestTiming {
+            enqueued_at_ms: t.arrival_unix_ms,
+            scheduled_at_ms: t.scheduled.map(at_ms),
+            first_token_at_ms: t.first_token.map(at_ms),
+            finished_at_ms: at_ms(now),
+            queue_micros: micros(self.arrival_time, scheduled),
+            prefill_micros: micros(scheduled, first_token),
+            decode_micros: micros(first_token, now),
+            num_steps: t.num_steps,
+            avg_step_micros: if t.num_steps == 0 {
+                0
+            } else {
+                t.step_time.as_micros() as u64 / t.num_steps as u64
+            },
+            aici_init_micros: t.aici_init_micros,
+            aici_mid_micros: t.aici_mid_micros,
+            aici_timeouts: t.aici_timeouts,
+        }
+    }
+}
+
+/// Engine-side timestamps and counters for a sequence group.
+#[derive(Debug, Clone)]
+pub struct GroupTiming {
+    pub arrival_unix_ms: u64,
+    pub scheduled: Option<Instant>,
+    pub first_token: Option<Instant>,
+    pub num_steps: usize,
+    pub step_time: Duration,
+    pub aici_init_micros: u64,
+    pub aici_mid_micros: u64,
+    pub aici_timeouts: usize,
+}
+
+impl GroupTiming {
+    pub fn new() -> Self {
+        let arrival_unix_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        GroupTiming {
+            arrival_unix_ms,
+            scheduled: None,
+            first_token: None,
+            num_steps: 0,
+            step_time: Duration::ZERO,
+            aici_init_micros: 0,
+            aici_mid_micros: 0,
+            aici_timeouts: 0,
+        }
+    }
+
+    pub fn add_aici_result<T>(&mut self, r: &SequenceResult<T>) {
+        self.aici_mid_micros += r.micros;
+        if r.timeout {
+            self.aici_timeouts += 1;
+        }
+    }
+}
+
+/// Latency breakdown of a finished request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestTiming {
+    pub enqueued_at_ms: u64,
+    pub scheduled_at_ms: Option<u64>,
+    pub first_token_at_ms: Option<u64>,
+    pub finished_at_ms: u64,
+    /// Time from arrival until first scheduled.
+    pub queue_micros: u64,
+    /// Time from first scheduled until first token sampled.
+    pub prefill_micros: u64,
+    /// Time from first token until finished.
+    pub decode_micros: u64,
+    /// Number of engine steps the request took part in.
+    pub num_steps: usize,
+    pub avg_step_micros: u64,
+    /// Time spent in the controller, as reported by aicirt.
+    pub aici_init_micros: u64,
+    pub aici_mid_micros: u64,
+    pub aici_timeouts: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -362,4 +474,6 @@ pub struct RequestOutput {
     pub usage: TokenUsage,
     pub seq_outputs: Vec<SeqOutput>,
     pub is_final: bool,
+    /// Only set in the final output.
+    pub timing: Option<RequestTiming>,
 }
@@ -1,3 +1,4 @@
+use crate::seq::RequestTiming;
 use aici_abi::StorageCmd;
 use serde::{Deserialize, Serialize};
 
@@ -11,6 +12,42 @@ pub struct RunRequest {
     pub top_p: Option<f32>,        // defl 1.0
     pub top_k: Option<isize>,      // defl -1
     pub max_tokens: Option<usize>, // defl context size
+    #[serde(default)]
+    pub overflow: OverflowPolicy, // defl reject
+    pub keep_prefix: Option<usize>, // defl 1; only for overflow=truncate_middle
+    /// Pass log-probability of the sampled token and this many top alternatives to the controller.
+    pub logprobs: Option<i32>,
+    /// Arbitrary JSON passed as-is to the controller (see `aici_host_request_info`).
+    #[serde(default)]
+    pub metadata: serde_json::Value,
+}
+
+/// What to do when prompt and max_tokens do not fit in the model's context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Fail the request.
+    #[default]
+    Reject,
+    /// Drop tokens from the beginning of the prompt.
+    TruncateLeft,
+    /// Keep the first `keep_prefix` tokens of the prompt and drop tokens after them.
+    TruncateMiddle,
+    /// Reduce max_tokens so that the full prompt fits.
+    ShrinkMaxTokens,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TruncationResponse {
+    pub policy: OverflowPolicy,
+    /// Number of prompt tokens before truncation.
+    pub prompt_tokens: usize,
+    /// Number of prompt tokens removed.
+    pub dropped_tokens: usize,
+    /// Offset in the original prompt of the first removed token.
+    pub dropped_offset: usize,
+    /// max_tokens after adjustment.
+    pub max_tokens: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +63,8 @@ pub struct InitialRunResponse {
     pub object: &'static str, // "initial-run"
     pub created: u64,
     pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<TruncationResponse>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +72,9 @@ pub struct RunResponse {
     pub object: &'static str, // "run"
     pub forks: Vec<RunForkResponse>,
     pub usage: RunUsageResponse,
+    /// Only present in the final run object.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timing: Option<RequestTiming>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,3 +88,21 @@ pub struct RunForkResponse {
     pub storage: Vec<StorageCmd>,
     pub micros: u64,
 }
+
+/// Messages sent by the client over the `/v1/run/ws` WebSocket, after the initial `RunRequest`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RunSessionMessage {
+    /// Write a variable in the storage of the request's group.
+    WriteVar {
+        name: String,
+        value: String,
+        #[serde(default)]
+        append: bool,
+        when_version_is: Option<u64>,
+    },
+    /// Stop the request.
+    Abort,
+    /// Tokenize the text and splice it into all forks in the next step.
+    AppendText { text: String },
+}
@@ -2,51 +2,117 @@ use crate::seq::{FinishReason, RequestOutput, SeqOutput};
 use crate::server::{auth_info, APIError, AiciServerData, InferenceResult};
 use crate::{config::SamplingParams, seq::Token, AddRequest};
 use actix_web::{post, web, web::Bytes, HttpResponse};
-use aicirt::{api::InstantiateReq, get_unix_time};
+use aici_abi::RequestInfo;
+use aicirt::{
+    api::{AuthInfo, InstantiateReq},
+    get_unix_time,
+};
 use serde_json::{json, Value};
 use tokio::sync::mpsc::Receiver;
 use uuid::Uuid;
 
-use super::api::{InitialRunResponse, RunForkResponse, RunRequest, RunResponse, RunUsageResponse};
+use super::api::{
+    InitialRunResponse, OverflowPolicy, RunForkResponse, RunRequest, RunResponse,
+    RunUsageResponse, TruncationResponse,
+};
 
 const NONE_CONTROLLER: &str = "none";
 
 fn check_length(
-    request: &web::Json<RunRequest>,
+    request: &RunRequest,
     data: &AiciServerData,
-) -> Result<(usize, Vec<Token>), APIError> {
+) -> Result<(usize, Vec<Token>, Option<TruncationResponse>), APIError> {
     let prompt = if request.controller == NONE_CONTROLLER {
         request.controller_arg.as_str().unwrap_or(&request.prompt)
     } else {
         request.prompt.as_str()
     };
-    let token_ids = data
+    let mut token_ids = data
         .tokenizer
         .encode(prompt, true)
         .map_err(APIError::from)?
         .get_ids()
         .to_vec();
 
-    let max_tokens = if let Some(max_toks) = request.max_tokens {
-        max_toks
-    } else {
-        data.model_meta.max_sequence_length - token_ids.len()
-    };
+    let max_len = data.model_meta.max_sequence_length;
+    let prompt_tokens = token_ids.len();
+    // we need space for at least one generated token
+    let wanted_tokens = request.max_tokens.unwrap_or(1);
 
-    if token_ids.len() + max_tokens > data.model_meta.max_sequence_length {
-        Err(APIError::new(format!(
-            "This model's maximum context length is {} tokens. \
-            However, you requested {} tokens ({} in the messages, \
-            {} in the completion). Please reduce the length of the \
-            messages or completion.",
-            data.model_meta.max_sequence_length,
-            max_tokens + token_ids.len(),
-            token_ids.len(),
-            max_tokens
-        )))
-    } else {
-        Ok((max_tokens, token_ids))
+    if prompt_tokens + wanted_tokens <= max_len {
+        let max_tokens = request.max_tokens.unwrap_or(max_len - prompt_tokens);
+        return Ok((max_tokens, token_ids, None));
+    }
+
+    let policy = request.overflow;
+    let mut dropped_offset = 0;
+
+    match policy {
+        OverflowPolicy::Reject => {
+            return Err(APIError::new(format!(
+                "This model's maximum context length is {} tokens. \
+                However, you requested {} tokens ({} in the messages, \
+                {} in the completion). Please reduce the length of the \
+                messages or completion, or set 'overflow'.",
+                max_len,
+                wanted_tokens + prompt_tokens,
+                prompt_tokens,
+                wanted_tokens
+            )));
+        }
+        OverflowPolicy::ShrinkMaxTokens => {
+            if prompt_tokens >= max_len {
+                return Err(APIError::new(format!(
+                    "The prompt has {} tokens, which doesn't fit in this model's \
+                    maximum context length of {} tokens.",
+                    prompt_tokens, max_len
+                )));
+            }
+        }
+        OverflowPolicy::TruncateLeft | OverflowPolicy::TruncateMiddle => {
+            if wanted_tokens >= max_len {
+                return Err(APIError::new(format!(
+                    "max_tokens ({}) must be smaller than this model's \
+                    maximum context length of {} tokens.",
+                    wanted_tokens, max_len
+                )));
+            }
+            if policy == OverflowPolicy::TruncateMiddle {
+                dropped_offset = request.keep_prefix.unwrap_or(1);
+            }
+            let num_drop = prompt_tokens + wanted_tokens - max_len;
+            if dropped_offset + num_drop > prompt_tokens {
+                return Err(APIError::new(format!(
+                    "keep_prefix ({}) too large; need to drop {} of {} prompt tokens.",
+                    dropped_offset, num_drop, prompt_tokens
+                )));
+            }
+            token_ids.drain(dropped_offset..dropped_offset + num_drop);
+        }
     }
+
+    let max_tokens = match (policy, request.max_tokens) {
+        (OverflowPolicy::ShrinkMaxTokens, _) | (_, None) => max_len - token_ids.len(),
+        (_, Some(max_toks)) => max_toks,
+    };
+
+    log::debug!(
+        "overflow {:?}: prompt {} -> {} tokens; max_tokens {}",
+        policy,
+        prompt_tokens,
+        token_ids.len(),
+        max_tokens
+    );
+
+    let truncation = TruncationResponse {
+        policy,
+        prompt_tokens,
+        dropped_tokens: prompt_tokens - token_ids.len(),
+        dropped_offset,
+        max_tokens,
+    };
+
+    Ok((max_tokens, token_ids, Some(truncation)))
 }
 
 macro_rules! set_fields_if_some {
@@ -73,10 +139,26 @@ async fn run_controller(
     data: web::Data<AiciServerData>,
     request: web::Json<RunRequest>,
 ) -> Result<HttpResponse, APIError> {
-    let token_ids = check_length(&request, &data);
+    let (initial, rx) = start_run(auth_info(&req), &data, &request).await?;
+
+    return Ok(HttpResponse::Ok()
+        .append_header(("content-type", "text/event-stream"))
+        .streaming(Client {
+            rx,
+            initial: Some(initial),
+        }));
+}
+
+/// Instantiate the controller (if any) and queue the request in the engine.
+pub(crate) async fn start_run(
+    auth: AuthInfo,
+    data: &AiciServerData,
+    request: &RunRequest,
+) -> Result<(InitialRunResponse, Receiver<InferenceResult>), APIError> {
+    let token_ids = check_length(request, data);
     bail_if_error!(token_ids);
 
-    let (max_tokens, token_ids) = token_ids.unwrap();
+    let (max_tokens, token_ids, truncation) = token_ids.unwrap();
 
     let request_id = format!("run-{}", Uuid::new_v4());
 
@@ -85,6 +167,7 @@ async fn run_controller(
     sampling_params.ignore_eos = true;
 
     set_fields_if_some!(request, sampling_params, temperature, top_p, top_k);
+    sampling_params.logprobs = request.logprobs;
 
     if request.controller != NONE_CONTROLLER {
         sampling_params.controller = Some(request.controller.clone());
@@ -96,6 +179,8 @@ async fn run_controller(
 
     bail_if_error!(sampling_params.verify_args());
 
+    // read before instantiating, so that a restart in between fails the request
+    let aicirt_epoch = data.side_cmd_ch.epoch();
     let (init_result, token_ids) = if let Some(mod_id) = sampling_params.controller.as_ref() {
         let inst = data
             .side_cmd_ch
@@ -105,8 +190,20 @@ async fn run_controller(
                     prompt: json!(token_ids),
                     module_id: mod_id.clone(),
                     module_arg: json!(sampling_params.controller_arg),
+                    request_info: RequestInfo {
+                        model_id: data.model_meta.id.clone(),
+                        context_length: data.model_meta.max_sequence_length,
+                        prompt_tokens: token_ids.len(),
+                        max_tokens: Some(sampling_params.max_tokens),
+                        fuel: Some(std::cmp::min(
+                            sampling_params.aici_fuel.unwrap_or(usize::MAX),
+                            data.max_fuel,
+                        )),
+                        metadata: request.metadata.clone(),
+                        ..Default::default()
+                    },
                 },
-                auth_info(&req),
+                auth,
             )
             .await;
         bail_if_error!(inst);
@@ -137,6 +234,7 @@ async fn run_controller(
                     aici_logs: vec![r],
                 }],
                 is_final: true,
+                timing: None,
             };
             let (tx, rx) = tokio::sync::mpsc::channel(1);
             tx.send(Ok(outp)).await.unwrap();
@@ -149,6 +247,7 @@ async fn run_controller(
                 sampling_params,
                 expected: None,
                 init_result,
+                aicirt_epoch,
             });
 
             bail_if_error!(rx);
@@ -156,17 +255,55 @@ async fn run_controller(
         }
     };
 
-    return Ok(HttpResponse::Ok()
-        .append_header(("content-type", "text/event-stream"))
-        .streaming(Client {
-            rx,
-            initial: Some(InitialRunResponse {
-                id: request_id,
-                object: "initial-run",
-                created: get_unix_time(),
-                model: data.model_meta.id.clone(),
-            }),
-        }));
+    let initial = InitialRunResponse {
+        id: request_id,
+        object: "initial-run",
+        created: get_unix_time(),
+        model: data.model_meta.id.clone(),
+        truncation,
+    };
+
+    Ok((initial, rx))
+}
+
+pub(crate) fn run_response(so: &RequestOutput) -> RunResponse {
+    let u = &so.usage;
+    RunResponse {
+        object: "run",
+        usage: RunUsageResponse {
+            sampled_tokens: u.gen_tokens,
+            ff_tokens: u.prompt_tokens,
+            cost: u.fuel_tokens(),
+        },
+        forks: so
+            .seq_outputs
+            .iter()
+            .map(|choice| RunForkResponse {
+                text: choice.new_text.clone(),
+                index: choice.index,
+                finish_reason: choice.finish_reason.map(|r| r.short_name()),
+                micros: choice.aici_logs.iter().map(|e| e.micros).sum(),
+                logs: choice
+                    .aici_logs
+                    .iter()
+                    .map(|e| e.logs.clone())
+                    .collect::<Vec<_>>()
+                    .join(""),
+                error: choice
+                    .aici_logs
+                    .iter()
+                    .map(|e| e.error.clone())
+                    .collect::<Vec<_>>()
+                    .join(""),
+                storage: choice
+                    .aici_logs
+                    .iter()
+                    .flat_map(|e| e.storage.clone())
+                    .collect::<Vec<_>>(),
+            })
+            .collect(),
+        timing: so.timing.clone(),
+    }
 }
 
 struct Client {
@@ -194,42 +331,7 @@ impl futures::Stream for Client {
 
         self.rx.poll_recv(cx).map(|x| match x {
             Some(Ok(so)) => {
-                let u = &so.usage;
-                let r = RunResponse {
-                    object: "run",
-                    usage: RunUsageResponse {
-                        sampled_tokens: u.gen_tokens,
-                        ff_tokens: u.prompt_tokens,
-                        cost: u.fuel_tokens(),
-                    },
-                    forks: so
-                        .seq_outputs
-                        .iter()
-                        .map(|choice| RunForkResponse {
-                            text: choice.new_text.clone(),
-                            index: choice.index,
-                            finish_reason: choice.finish_reason.map(|r| r.short_name()),
-                            micros: choice.aici_logs.iter().map(|e| e.micros).sum(),
-                            logs: choice
-                                .aici_logs
-                                .iter()
-                                .map(|e| e.logs.clone())
-                                .collect::<Vec<_>>()
-                                .join(""),
-                            error: choice
-                                .aici_logs
-                                .iter()
-                                .map(|e| e.error.clone())
-                                .collect::<Vec<_>>()
-                                .join(""),
-                            storage: choice
-                                .aici_logs
-                                .iter()
-                                .flat_map(|e| e.storage.clone())
-                                .collect::<Vec<_>>(),
-                        })
-                        .collect(),
-                };
+                let r = run_response(&so);
                 let res = serde_json::to_string(&r).unwrap();
                 let mut res = format!("data: {}\n\n", res);
                 if so.is_final {
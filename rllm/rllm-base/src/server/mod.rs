@@ -3,7 +3,7 @@ use crate::{
     iface::{kill_self, AiciRtIface, AsyncCmdChannel},
     seq::RequestOutput,
     util::apply_settings,
-    AddRequest, HashMap, LoaderArgs, ModelExec, RllmEngine,
+    AddRequest, HashMap, LoaderArgs, ModelExec, RequestInput, RllmEngine,
 };
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use aici_abi::toktrie::TokTrie;
@@ -25,6 +25,7 @@ use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
 mod api;
 mod completion;
 mod openai;
+mod ws;
 
 #[derive(Debug)]
 pub struct APIError {
@@ -121,6 +122,7 @@ pub struct AiciServerData {
     pub tok_trie: Arc<TokTrie>,
     pub side_cmd_ch: AsyncCmdChannel,
     pub stats: Arc<Mutex<ServerStats>>,
+    pub max_fuel: usize,
 }
 
 #[derive(Args, Debug)]
@@ -133,6 +135,14 @@ pub struct RllmCliArgs {
     #[arg(long)]
     pub log: Option<String>,
 
+    /// Load options from TOML file; command line options take precedence
+    #[arg(long)]
+    pub config: Option<String>,
+
+    /// Print effective configuration (in --config format) and exit
+    #[arg(long, default_value_t = false)]
+    pub print_config: bool,
+
     /// HuggingFace model name, URL or path starting with "./"
     #[arg(short, long, help_heading = "Model")]
     pub model: String,
@@ -170,6 +180,19 @@ pub struct RllmCliArgs {
     #[arg(long, help_heading = "AICI settings")]
     pub aicirt: Option<String>,
 
+    /// Connect to aicirt started elsewhere with --listen (unix:PATH or tcp:HOST:PORT),
+    /// instead of spawning it; it has to use the same tokenizer
+    #[arg(long, value_name = "ADDR", help_heading = "AICI settings")]
+    pub aicirt_addr: Option<String>,
+
+    /// Shared secret of aicirt at --aicirt-addr (its --listen-token)
+    #[arg(long, value_name = "TOKEN", help_heading = "AICI settings")]
+    pub aicirt_token: Option<String>,
+
+    /// File with the shared secret of aicirt at --aicirt-addr (see --aicirt-token)
+    #[arg(long, value_name = "PATH", help_heading = "AICI settings")]
+    pub aicirt_token_file: Option<String>,
+
     /// Size of JSON comm buffer in megabytes
     #[arg(long, default_value = "128", help_heading = "AICI settings")]
     pub json_size: usize,
@@ -178,6 +201,15 @@ pub struct RllmCliArgs {
     #[arg(long, default_value = "32", help_heading = "AICI settings")]
     pub bin_size: usize,
 
+    /// Masks allowing at most this many tokens are passed as token lists; 0 to disable
+    #[arg(long, default_value = "256", help_heading = "AICI settings")]
+    pub sparse_mask_max_tokens: usize,
+
+    /// Format of masks passed from aicirt (f32 or bool); defaults to what the backend prefers.
+    /// With bool, aicirt passes f32 masks in steps where controllers return soft logit biases.
+    #[arg(long, help_heading = "AICI settings")]
+    pub bias_dtype: Option<String>,
+
     /// How many milliseconds to spin-wait for a message over IPC and SHM.
     #[arg(long, default_value = "200", help_heading = "AICI settings")]
     pub busy_wait_time: u64,
@@ -309,6 +341,10 @@ More info at: {url}"#
 
 pub enum InferenceReq {
     AddRequest(AddRequest),
+    Input {
+        request_id: String,
+        input: RequestInput,
+    },
 }
 
 type InferenceResult = Result<RequestOutput>;
@@ -334,6 +370,12 @@ impl InferenceWorker {
         self.running.insert(rid, tx);
         Ok(rx)
     }
+
+    pub fn add_input(&mut self, request_id: String, input: RequestInput) -> Result<()> {
+        self.req_sender
+            .try_send(InferenceReq::Input { request_id, input })?;
+        Ok(())
+    }
 }
 
 fn inference_loop<ME: ModelExec>(
@@ -366,6 +408,11 @@ fn inference_loop<ME: ModelExec>(
                         }
                     }
                 }
+                Ok(InferenceReq::Input { request_id, input }) => {
+                    if let Err(e) = engine.add_request_input(&request_id, input) {
+                        log::warn!("input for {request_id}: {e}");
+                    }
+                }
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => panic!(),
             }
@@ -622,6 +669,8 @@ pub async fn server_main<ME: ModelExec>(
 
     let aicirt = match &args.aicirt {
         Some(v) => v.clone(),
+        // not spawned
+        None if args.aicirt_addr.is_some() => String::new(),
         None => match guess_aicirt() {
             Ok(v) => v,
             Err(e) => {
@@ -631,6 +680,26 @@ pub async fn server_main<ME: ModelExec>(
         },
     };
 
+    let aicirt_token = match (&args.aicirt_token, &args.aicirt_token_file) {
+        (Some(t), None) => Some(t.clone()),
+        (None, Some(path)) => match std::fs::read_to_string(path) {
+            Ok(t) => Some(t.trim().to_string()),
+            Err(e) => {
+                eprintln!("can't read {path}: {e}");
+                std::process::exit(10);
+            }
+        },
+        (None, None) if args.aicirt_addr.is_some() => {
+            eprintln!("--aicirt-addr requires --aicirt-token or --aicirt-token-file");
+            std::process::exit(10);
+        }
+        (None, None) => None,
+        (Some(_), Some(_)) => {
+            eprintln!("only one of --aicirt-token and --aicirt-token-file allowed");
+            std::process::exit(10);
+        }
+    };
+
     let shm_prefix = match &args.shm_prefix {
         Some(v) => {
             if v.starts_with("/") {
@@ -644,9 +713,16 @@ pub async fn server_main<ME: ModelExec>(
 
     let rt_args = crate::iface::Args {
         aicirt,
+        aicirt_addr: args.aicirt_addr.clone(),
+        aicirt_token,
         tokenizer: loader_args.tokenizer.clone(),
         json_size: args.json_size,
         bin_size: args.bin_size,
+        sparse_mask_max_tokens: args.sparse_mask_max_tokens,
+        bias_dtype: args
+            .bias_dtype
+            .clone()
+            .unwrap_or_else(|| ME::bias_type().to_string()),
         shm_prefix,
         busy_wait_time: args.busy_wait_time,
         add_args: args.aicirt_arg.clone(),
@@ -658,6 +734,9 @@ pub async fn server_main<ME: ModelExec>(
     }));
     let iface = AiciRtIface::start_aicirt(&rt_args, &tok_trie).expect("failed to start aicirt");
     let side_cmd_ch = iface.side_cmd.clone();
+    let max_fuel = loader_args
+        .aici
+        .max_fuel_for(model_meta.max_sequence_length);
     let handle = spawn_inference_loop::<ME>(&args, loader_args, model_args, iface, stats.clone());
 
     let app_data = AiciServerData {
@@ -667,6 +746,7 @@ pub async fn server_main<ME: ModelExec>(
         tok_trie: Arc::new(tok_trie),
         side_cmd_ch,
         stats,
+        max_fuel,
     };
     let app_data = web::Data::new(app_data);
 
@@ -677,6 +757,7 @@ pub async fn server_main<ME: ModelExec>(
             .service(models)
             .service(tunnel_info)
             .service(completion::run_controller)
+            .service(ws::run_controller_ws)
             .service(get_controllers_tags)
             .service(tag_controller)
             .configure(|cfg| {
@@ -0,0 +1,168 @@
+use super::{
+    api::{RunRequest, RunSessionMessage},
+    auth_info,
+    completion::{run_response, start_run},
+    AiciServerData,
+};
+use crate::RequestInput;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use aici_abi::{StorageCmd, StorageOp};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::json;
+
+/// Same as /v1/run, but over a WebSocket, which also lets the client
+/// send data to the running request.
+/// The first message from the client is the `RunRequest`; later ones are `RunSessionMessage`.
+#[actix_web::get("/v1/run/ws")]
+async fn run_controller_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AiciServerData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
+    let auth = auth_info(&req);
+    actix_web::rt::spawn(async move {
+        run_session(data, auth, session, msg_stream).await;
+    });
+    Ok(response)
+}
+
+async fn send_json<T: Serialize>(session: &mut Session, v: &T) -> bool {
+    session
+        .text(serde_json::to_string(v).unwrap())
+        .await
+        .is_ok()
+}
+
+async fn send_error(session: &mut Session, msg: &str) -> bool {
+    send_json(session, &json!({ "object": "error", "error": msg })).await
+}
+
+fn session_input(data: &AiciServerData, msg: &str) -> Result<RequestInput> {
+    let msg: RunSessionMessage = serde_json::from_str(msg)?;
+    let input = match msg {
+        RunSessionMessage::WriteVar {
+            name,
+            value,
+            append,
+            when_version_is,
+        } => RequestInput::Storage(vec![StorageCmd::WriteVar {
+            name,
+            value: value.into_bytes(),
+            op: if append {
+                StorageOp::Append
+            } else {
+                StorageOp::Set
+            },
+            when_version_is,
+        }]),
+        RunSessionMessage::Abort => RequestInput::Abort,
+        RunSessionMessage::AppendText { text } => {
+            let tokens = data
+                .tokenizer
+                .encode(text, false)
+                .map_err(|e| anyhow!("{e}"))?
+                .get_ids()
+                .to_vec();
+            RequestInput::Tokens(tokens)
+        }
+    };
+    Ok(input)
+}
+
+async fn run_session(
+    data: web::Data<AiciServerData>,
+    auth: aicirt::api::AuthInfo,
+    mut session: Session,
+    mut msg_stream: MessageStream,
+) {
+    let request: RunRequest = loop {
+        match msg_stream.recv().await {
+            Some(Ok(Message::Text(t))) => match serde_json::from_str(&t) {
+                Ok(r) => break r,
+                Err(e) => {
+                    send_error(&mut session, &format!("invalid run request: {e}")).await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+            },
+            Some(Ok(Message::Ping(b))) => {
+                let _ = session.pong(&b).await;
+            }
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+            Some(Ok(_)) => {}
+        }
+    };
+
+    let (initial, mut rx) = match start_run(auth, &data, &request).await {
+        Ok(r) => r,
+        Err(e) => {
+            send_error(&mut session, &e.msg).await;
+            let _ = session.close(None).await;
+            return;
+        }
+    };
+    let request_id = initial.id.clone();
+
+    let abort = |data: &AiciServerData| {
+        let _ = data
+            .worker
+            .lock()
+            .unwrap()
+            .add_input(request_id.clone(), RequestInput::Abort);
+    };
+
+    if !send_json(&mut session, &initial).await {
+        abort(&data);
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            outp = rx.recv() => match outp {
+                Some(Ok(so)) => {
+                    if !send_json(&mut session, &run_response(&so)).await {
+                        abort(&data);
+                        return;
+                    }
+                    if so.is_final {
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    send_error(&mut session, &format!("{e}")).await;
+                    break;
+                }
+                None => break,
+            },
+            msg = msg_stream.recv() => match msg {
+                Some(Ok(Message::Text(t))) => {
+                    let r = session_input(&data, &t).and_then(|input| {
+                        data.worker
+                            .lock()
+                            .unwrap()
+                            .add_input(request_id.clone(), input)
+                    });
+                    if let Err(e) = r {
+                        if !send_error(&mut session, &format!("{e}")).await {
+                            abort(&data);
+                            return;
+                        }
+                    }
+                }
+                Some(Ok(Message::Ping(b))) => {
+                    let _ = session.pong(&b).await;
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    abort(&data);
+                    return;
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    let _ = session.close(None).await;
+}
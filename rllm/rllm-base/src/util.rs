@@ -1,4 +1,7 @@
-use crate::HashMap;
+use crate::{
+    cfgfile::{apply_config_file, effective_config},
+    HashMap,
+};
 use aicirt::bintokens::list_tokenizers;
 use anyhow::{bail, Result};
 use clap::{Args, Command, Parser};
@@ -37,6 +40,14 @@ pub fn set_setting(name: &str, val: f64) -> Result<()> {
     }
 }
 
+/// Current values of all settings.
+pub fn all_setting_values() -> Vec<(String, f64)> {
+    SETTINGS
+        .iter()
+        .map(|(k, _, _)| (k.to_string(), get_setting(k)))
+        .collect()
+}
+
 pub fn get_setting(name: &str) -> f64 {
     let settings = CHECK_SETTINGS.lock().unwrap();
     if let Some(val) = settings.get(name) {
@@ -92,7 +103,26 @@ where
         logging_info()
     ));
     let cli = T::augment_args(cli);
-    let matches = cli.get_matches();
+    let (mut cli, args) = match apply_config_file(cli, std::env::args_os().collect()) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(101);
+        }
+    };
+    let matches = cli
+        .try_get_matches_from_mut(args)
+        .unwrap_or_else(|err| err.exit());
+    if let Ok(Some(true)) = matches.try_get_one::<bool>("print_config") {
+        match effective_config(&cli, &matches) {
+            Ok(s) => print!("{s}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(101);
+            }
+        }
+        std::process::exit(0);
+    }
     T::from_arg_matches(&matches)
         .map_err(|err| err.exit())
         .unwrap()
@@ -5,12 +5,15 @@ use super::{
     util::{synchronize, to_vec1},
     DType,
 };
-use aicirt::{with_timer, TimerRef};
+use aicirt::{api::BiasType, with_timer, TimerRef};
 use anyhow::Result;
 use rand::distributions::Distribution as _;
-use rllm::{config::RllmConfig, AiciBias, LogitsProcessor, ModelExec, SchedulerOutputs};
+use rllm::{
+    config::RllmConfig, AiciBias, AiciBiasData, HashMap, LogitsProcessor, ModelExec,
+    SchedulerOutputs,
+};
 use std::{sync::Arc, time::Instant};
-use tch::{Device, IndexOp, Tensor};
+use tch::{kind::Element, Device, IndexOp, Kind, Tensor};
 
 pub trait TModelInner {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor;
@@ -145,27 +148,69 @@ impl ModelExec for TModel {
         Ok(())
     }
 
+    fn bias_type() -> BiasType {
+        BiasType::Bool
+    }
+
     fn empty_bias(&self, vocab_size: usize) -> Self::AiciBias {
         TchAiciBias {
             vocab_size,
             bias: None,
+            is_mask: false,
+            rows: vec![],
+            sparse_mask: None,
+            sparse_rows: HashMap::default(),
         }
     }
 
     fn new_bias(
         &self,
-        slice: &'static [f32],
+        data: AiciBiasData,
         num_seqs: usize,
         vocab_size: usize,
+        sparse: HashMap<usize, Vec<u32>>,
     ) -> Self::AiciBias {
         let _no_grad = tch::no_grad_guard();
 
-        let tensor = Tensor::from_slice(slice)
-            .to(self.config.model.device)
-            .reshape(&[num_seqs as i64, vocab_size as i64]);
+        // only copy rows of dense masks to the device
+        let dense: Vec<usize> = (0..num_seqs).filter(|i| !sparse.contains_key(i)).collect();
+        let mut rows = vec![-1; num_seqs];
+        for (row, idx) in dense.iter().enumerate() {
+            rows[*idx] = row as i64;
+        }
+        let device = self.config.model.device;
+        let num_rows = dense.len() as i64;
+        let (bias, is_mask) = match data {
+            AiciBiasData::F32(slice) => {
+                let bias = dense_rows(slice, num_seqs, &dense)
+                    .map(|t| t.to(device).reshape(&[num_rows, vocab_size as i64]));
+                (bias, false)
+            }
+            AiciBiasData::Bool(words) => {
+                // the bits are expanded on the device, into a tensor of disallowed tokens
+                let words: &[i32] = unsafe {
+                    std::slice::from_raw_parts(words.as_ptr() as *const i32, words.len())
+                };
+                let bias = dense_rows(words, num_seqs, &dense).map(|t| {
+                    let shifts = Tensor::arange(32, (Kind::Int, device));
+                    t.to(device)
+                        .reshape(&[num_rows, (vocab_size / 32) as i64, 1])
+                        .bitwise_right_shift(&shifts)
+                        .bitwise_and(1)
+                        .eq(0)
+                        .reshape(&[num_rows, vocab_size as i64])
+                });
+                (bias, true)
+            }
+        };
+        let (sparse_mask, sparse_rows) = sparse_rows(&sparse, vocab_size, device);
         TchAiciBias {
             vocab_size,
-            bias: Some(tensor),
+            bias,
+            is_mask,
+            rows,
+            sparse_mask,
+            sparse_rows,
         }
     }
 
@@ -269,15 +314,84 @@ impl TModel {
     }
 }
 
+/// Rows `dense` out of `num_rows` rows in `slice`, as a flat tensor on the CPU.
+fn dense_rows<T: Element + Copy>(
+    slice: &[T],
+    num_rows: usize,
+    dense: &[usize],
+) -> Option<Tensor> {
+    if dense.is_empty() {
+        None
+    } else if dense.len() == num_rows {
+        Some(Tensor::from_slice(slice))
+    } else {
+        let row_len = slice.len() / num_rows;
+        let mut data = Vec::with_capacity(dense.len() * row_len);
+        for idx in dense {
+            data.extend_from_slice(&slice[idx * row_len..(idx + 1) * row_len]);
+        }
+        Some(Tensor::from_slice(&data))
+    }
+}
+
+/// Expand the allow-lists of sparse masks on the device, into one tensor with a row
+/// of disallowed tokens per mask; only the indices of allowed tokens are copied there.
+fn sparse_rows(
+    sparse: &HashMap<usize, Vec<u32>>,
+    vocab_size: usize,
+    device: Device,
+) -> (Option<Tensor>, HashMap<usize, i64>) {
+    if sparse.is_empty() {
+        return (None, HashMap::default());
+    }
+    let mut rows = HashMap::default();
+    let mut allowed = Vec::new();
+    for (row, (idx, tokens)) in sparse.iter().enumerate() {
+        rows.insert(*idx, row as i64);
+        let off = row * vocab_size;
+        allowed.extend(
+            tokens
+                .iter()
+                .map(|t| *t as usize)
+                .filter(|t| *t < vocab_size)
+                .map(|t| (off + t) as i64),
+        );
+    }
+    let allowed = Tensor::from_slice(&allowed).to(device);
+    let mut mask = Tensor::ones(&[(rows.len() * vocab_size) as i64], (Kind::Bool, device));
+    let _ = mask.index_fill_(0, &allowed, 0);
+    let mask = mask.reshape(&[rows.len() as i64, vocab_size as i64]);
+    (Some(mask), rows)
+}
+
 pub struct TchAiciBias {
     pub vocab_size: usize,
+    /// Either additive biases, or (if `is_mask`) booleans set for disallowed tokens.
     pub bias: Option<Tensor>,
+    pub is_mask: bool,
+    /// Row in `bias` for every mask index; -1 for sparse masks.
+    pub rows: Vec<i64>,
+    /// Booleans set for tokens disallowed by sparse masks.
+    pub sparse_mask: Option<Tensor>,
+    /// Row in `sparse_mask` for mask indices of sparse masks.
+    pub sparse_rows: HashMap<usize, i64>,
 }
 
 impl AiciBias<Tensor> for TchAiciBias {
     fn apply(&self, logits: &mut Tensor, seq_id: usize) {
+        let num_logits = logits.size()[0];
+        if let Some(row) = self.sparse_rows.get(&seq_id) {
+            let mask = self.sparse_mask.as_ref().unwrap();
+            let mask = mask.i((*row, 0..num_logits));
+            *logits = logits.masked_fill(&mask, f64::NEG_INFINITY);
+            return;
+        }
         let bias = self.bias.as_ref().unwrap();
-        let bias = bias.i((seq_id as i64, 0..logits.size()[0]));
-        *logits = &*logits + bias;
+        let bias = bias.i((self.rows[seq_id], 0..num_logits));
+        if self.is_mask {
+            *logits = logits.masked_fill(&bias, f64::NEG_INFINITY);
+        } else {
+            *logits = &*logits + bias;
+        }
     }
 }
@@ -1,11 +1,11 @@
-use aicirt::{with_timer, TimerRef};
+use aicirt::{api::BiasType, with_timer, TimerRef};
 use anyhow::Result;
 use llama_cpp_low as cpp;
 use rand::distributions::Distribution as _;
 use rllm::{
     config::{ModelMeta, RllmConfig},
     seq::SchedulingPhase,
-    AiciBias, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs,
+    AiciBias, AiciBiasData, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs,
 };
 use std::{sync::Arc, time::Instant};
 
@@ -122,26 +122,33 @@ impl ModelExec for TModel {
         Ok(())
     }
 
+    fn bias_type() -> BiasType {
+        BiasType::Bool
+    }
+
     fn empty_bias(&self, vocab_size: usize) -> Self::AiciBias {
         CppAiciBias {
             vocab_size,
             bias: None,
+            sparse: HashMap::default(),
         }
     }
 
     fn new_bias(
         &self,
-        slice: &'static [f32],
+        data: AiciBiasData,
         num_seqs: usize,
         vocab_size: usize,
+        sparse: HashMap<usize, Vec<u32>>,
     ) -> Self::AiciBias {
-        let tensor = {
-            assert!(slice.len() == num_seqs * vocab_size);
-            Tensor::from_slice(slice)
-        };
+        match data {
+            AiciBiasData::F32(slice) => assert!(slice.len() == num_seqs * vocab_size),
+            AiciBiasData::Bool(words) => assert!(words.len() * 32 == num_seqs * vocab_size),
+        }
         CppAiciBias {
             vocab_size,
-            bias: Some(tensor),
+            bias: Some(data),
+            sparse,
         }
     }
 
@@ -262,17 +269,42 @@ impl TModel {
 
 pub struct CppAiciBias {
     pub vocab_size: usize,
-    pub bias: Option<Tensor>,
+    pub bias: Option<AiciBiasData>,
+    pub sparse: HashMap<usize, Vec<u32>>,
 }
 
 impl AiciBias<Tensor> for CppAiciBias {
     fn apply(&self, logits: &mut Tensor, seq_id: usize) {
-        let bias = self.bias.as_ref().unwrap();
-        let sp = seq_id * self.vocab_size;
+        if let Some(allowed) = self.sparse.get(&seq_id) {
+            let logits = logits.as_mut_slice();
+            let kept: Vec<(usize, f32)> = allowed
+                .iter()
+                .map(|t| *t as usize)
+                .filter(|t| *t < logits.len())
+                .map(|t| (t, logits[t]))
+                .collect();
+            logits.fill(f32::NEG_INFINITY);
+            for (t, v) in kept {
+                logits[t] = v;
+            }
+            return;
+        }
         let logits = logits.as_mut_slice();
-        let bias = bias.as_slice();
-        for i in 0..logits.len() {
-            logits[i] += bias[sp + i];
+        match self.bias.unwrap() {
+            AiciBiasData::F32(bias) => {
+                let sp = seq_id * self.vocab_size;
+                for i in 0..logits.len() {
+                    logits[i] += bias[sp + i];
+                }
+            }
+            AiciBiasData::Bool(bits) => {
+                let sp = seq_id * self.vocab_size / 32;
+                for i in 0..logits.len() {
+                    if bits[sp + i / 32] & (1 << (i % 32)) == 0 {
+                        logits[i] = f32::NEG_INFINITY;
+                    }
+                }
+            }
         }
     }
 }
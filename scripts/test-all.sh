@@ -4,4 +4,7 @@ set -x
 set -e
 ./scripts/test-pyctrl.sh
 ./scripts/test-jsctrl.sh
+./scripts/test-preinit.sh
+./scripts/test-fuel.sh
+./scripts/test-trace.sh
 pytest
@@ -0,0 +1,24 @@
+#!/bin/sh
+
+# Run pyctrl under a step fuel budget it can't fit in, twice, and check
+# that it fails with the same fuel error both times.
+
+set -x
+set -e
+cd `dirname $0`
+HERE=`pwd`
+cd $HERE/../controllers/pyctrl
+cargo build --release
+(cd $HERE/../aicirt; cargo build --release)
+BIN=$(cd $HERE/../target; pwd)
+OUT=$(mktemp -d)
+
+for run in 1 2 ; do
+  $BIN/release/aicirt --tokenizer gpt4 --module $BIN/wasm32-wasi/release/aici_pyctrl.wasm \
+    --run-arg ./samples/yesno.py --simulate --sim-prompt "Is the sky blue?" --sim-max-tokens 5 \
+    --sim-sampling first --wasm-max-step-fuel 10000 > $OUT/run$run.txt
+done
+grep "fuel budget of 10000 exceeded" $OUT/run1.txt
+grep "finished (Failed" $OUT/run1.txt
+diff $OUT/run1.txt $OUT/run2.txt
+rm -rf $OUT
@@ -0,0 +1,22 @@
+#!/bin/sh
+
+# Instantiate pyctrl (which has aici_preinit()) twice: first without a snapshot,
+# which runs aici_preinit() and saves it, then restoring from that snapshot.
+
+set -x
+set -e
+cd `dirname $0`
+HERE=`pwd`
+cd $HERE/../controllers/pyctrl
+cargo build --release
+(cd $HERE/../aicirt; cargo build --release)
+BIN=$(cd $HERE/../target; pwd)
+
+rm -f cache/*.snap
+for run in fresh snapshot ; do
+  $BIN/release/aicirt --tokenizer gpt4 --module $BIN/wasm32-wasi/release/aici_pyctrl.wasm \
+    --run-arg ./samples/yesno.py --simulate --sim-prompt "Is the sky blue?" --sim-max-tokens 5
+  if [ $run = fresh ] ; then
+    ls cache/*.snap
+  fi
+done
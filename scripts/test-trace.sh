@@ -0,0 +1,40 @@
+#!/bin/sh
+
+# Record simulated pyctrl and jsctrl runs, and check that replaying them
+# gives the same results, logs and masks; then check that simulating
+# with --sim-sampling=first gives the same text every time.
+
+set -x
+set -e
+cd `dirname $0`
+HERE=`pwd`
+(cd $HERE/../controllers/pyctrl; cargo build --release)
+(cd $HERE/../controllers/jsctrl; cargo build --release)
+(cd $HERE/../aicirt; cargo build --release)
+BIN=$(cd $HERE/../target; pwd)
+OUT=$(mktemp -d)
+
+# replay needs the modules in ./cache, where the simulation put them
+cd $HERE/../controllers/pyctrl
+for ctrl in py js ; do
+  case $ctrl in
+    py) ARG=./samples/yesno.py ;;
+    js) ARG=../jsctrl/samples/hello.js ;;
+  esac
+  $BIN/release/aicirt --tokenizer gpt4 --module $BIN/wasm32-wasi/release/aici_${ctrl}ctrl.wasm \
+    --run-arg $ARG --simulate --sim-prompt "Is the sky blue?" --sim-max-tokens 5 \
+    --record $OUT/$ctrl
+  $BIN/release/aicirt --tokenizer gpt4 --replay $OUT/$ctrl/simulate.jsonl > $OUT/$ctrl-replay.txt || true
+  cat $OUT/$ctrl-replay.txt
+  grep "replay OK" $OUT/$ctrl-replay.txt
+
+  for run in 1 2 ; do
+    $BIN/release/aicirt --tokenizer gpt4 --module $BIN/wasm32-wasi/release/aici_${ctrl}ctrl.wasm \
+      --run-arg $ARG --simulate --sim-prompt "Is the sky blue?" --sim-max-tokens 5 \
+      --sim-sampling first > $OUT/$ctrl-first$run.txt
+  done
+  cat $OUT/$ctrl-first1.txt
+  grep "finished (" $OUT/$ctrl-first1.txt
+  diff $OUT/$ctrl-first1.txt $OUT/$ctrl-first2.txt
+done
+rm -rf $OUT